@@ -3,8 +3,13 @@ use std::io::{self, Write};
 use mnist::*;
 use torchic::{
     nn::{Adam, MLP},
-    runtime::{RuntimeStats, WGPUContext, init_runtime, no_grad, stats},
+    readback::HostCopy,
+    runtime::{
+        WGPUContext, init_runtime, mark_step, memory_report, no_grad, record_allocator_events,
+        stats, synchronize,
+    },
     tensor::Tensor,
+    trace::trace,
 };
 
 struct DataLoader {
@@ -109,6 +114,7 @@ fn one_hot_batch_to_indices(data: &[f32]) -> Vec<usize> {
 
 fn main() {
     init_runtime(prompt_adapters(), 42);
+    record_allocator_events(true);
 
     let mut stat_vec = vec![];
     stat_vec.push(stats());
@@ -120,6 +126,7 @@ fn main() {
     let test_batches = loader.test_batches(batch_size);
 
     stat_vec.push(stats());
+    mark_step();
 
     let epochs = 10;
     let lr = 1e-3;
@@ -128,6 +135,7 @@ fn main() {
     let mut optimizer = Adam::new(&model, lr, 0.9, 0.999, 1e-8);
 
     stat_vec.push(stats());
+    mark_step();
 
     let mut epoch_times = vec![];
     let mut losses = vec![];
@@ -135,6 +143,8 @@ fn main() {
         let start = std::time::Instant::now();
 
         let mut total_loss = 0.0;
+        // Loss of the previous step, read while the next one runs
+        let mut last_loss: Option<HostCopy> = None;
 
         for (img, lbl) in &train_batches {
             let output = model.forward(img).unwrap();
@@ -144,9 +154,15 @@ fn main() {
             loss.backward();
             optimizer.step();
 
-            total_loss += loss.to_vec()[0];
+            if let Some(copy) = last_loss.replace(loss.copy_to_host()) {
+                total_loss += copy.wait()[0];
+            }
+        }
+        if let Some(copy) = last_loss {
+            total_loss += copy.wait()[0];
         }
 
+        synchronize();
         epoch_times.push(start.elapsed().as_secs_f32());
         losses.push(total_loss / train_batches.len() as f32);
         println!(
@@ -156,20 +172,30 @@ fn main() {
             epoch_times[epoch_times.len() - 1],
         );
         stat_vec.push(stats());
+        mark_step();
     }
     std::fs::write("./epoch_times.txt", format!("{:?}", epoch_times)).unwrap();
     std::fs::write("./loss.txt", format!("{:?}", losses)).unwrap();
     serde_json::to_writer_pretty(std::fs::File::create("stats.json").unwrap(), &stat_vec).unwrap();
+    memory_report().save("memory_report.html").unwrap();
 
     {
         // Evaluation
-        let _ng = no_grad().unwrap();
+        let _ng = no_grad();
 
         let mut correct = 0;
         let mut total = 0;
 
+        // Every batch but maybe the last has the traced shape
+        let first = &test_batches[0].0;
+        let compiled = trace(std::slice::from_ref(first), |x| model.forward(&x[0])).unwrap();
+
         for (img, lbl) in &test_batches {
-            let output = model.forward(img).unwrap();
+            let output = if img.shape() == first.shape() {
+                compiled.run(std::slice::from_ref(img)).unwrap()
+            } else {
+                model.forward(img).unwrap()
+            };
 
             let pred = one_hot_batch_to_indices(&output.to_vec());
             let exp = one_hot_batch_to_indices(&lbl.to_vec());
@@ -0,0 +1,261 @@
+use std::fmt;
+
+use crate::runtime::RuntimeConfigError;
+
+/// Overrides the adapter name filter. The special value `fallback` forces the software adapter.
+pub const ADAPTER_ENV: &str = "TORCHIC_ADAPTER";
+/// Overrides the backends, as a comma separated list like `vulkan,metal`.
+pub const BACKEND_ENV: &str = "TORCHIC_BACKEND";
+
+/// Criteria for picking an adapter without user interaction. The `TORCHIC_ADAPTER` and
+/// `TORCHIC_BACKEND` environment variables take precedence over the values set in code.
+#[derive(Debug, Clone)]
+pub struct AdapterPreference {
+    power: wgpu::PowerPreference,
+    backends: wgpu::Backends,
+    name: Option<String>,
+    force_fallback: bool,
+}
+
+impl Default for AdapterPreference {
+    fn default() -> Self {
+        Self {
+            power: wgpu::PowerPreference::None,
+            backends: wgpu::Backends::all(),
+            name: None,
+            force_fallback: false,
+        }
+    }
+}
+
+impl AdapterPreference {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ranks discrete GPUs first for `HighPerformance` and integrated ones for `LowPower`.
+    /// Hardware adapters always come before software ones.
+    pub fn power(mut self, power: wgpu::PowerPreference) -> Self {
+        self.power = power;
+        self
+    }
+
+    pub fn backends(mut self, backends: wgpu::Backends) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    /// Only accepts adapters whose name contains `name`, ignoring case.
+    pub fn name_contains(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Only accepts the software fallback adapter, e.g. to run on machines without a GPU.
+    pub fn force_fallback(mut self, force: bool) -> Self {
+        self.force_fallback = force;
+        self
+    }
+
+    /// Picks the best adapter matching the preference.
+    pub fn select(&self) -> Result<wgpu::Adapter, AdapterSelectionError> {
+        let pref = self.clone().with_env(
+            std::env::var(ADAPTER_ENV).ok().as_deref(),
+            std::env::var(BACKEND_ENV).ok().as_deref(),
+        )?;
+
+        let instance = wgpu::Instance::default();
+        let adapters = pollster::block_on(instance.enumerate_adapters(pref.backends));
+        let infos: Vec<wgpu::AdapterInfo> = adapters.iter().map(|a| a.get_info()).collect();
+
+        match pref.pick(&infos) {
+            Some(i) => Ok(adapters.into_iter().nth(i).unwrap()),
+            None => Err(AdapterSelectionError::NoMatchingAdapter {
+                available: infos
+                    .iter()
+                    .map(|i| format!("{} ({}, {:?})", i.name, i.backend, i.device_type))
+                    .collect(),
+            }),
+        }
+    }
+
+    fn with_env(
+        mut self,
+        adapter: Option<&str>,
+        backend: Option<&str>,
+    ) -> Result<Self, AdapterSelectionError> {
+        match adapter {
+            Some(a) if a.eq_ignore_ascii_case("fallback") => self.force_fallback = true,
+            Some(a) if !a.is_empty() => self.name = Some(a.to_string()),
+            _ => {}
+        }
+
+        if let Some(backend) = backend {
+            let backends = wgpu::Backends::from_comma_list(backend);
+            if backends.is_empty() {
+                return Err(AdapterSelectionError::InvalidBackend(backend.to_string()));
+            }
+            self.backends = backends;
+        }
+
+        Ok(self)
+    }
+
+    // Index of the best matching adapter
+    fn pick(&self, infos: &[wgpu::AdapterInfo]) -> Option<usize> {
+        let name = self.name.as_ref().map(|n| n.to_lowercase());
+
+        infos
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| self.backends.contains(info.backend.into()))
+            .filter(|(_, info)| !self.force_fallback || info.device_type == wgpu::DeviceType::Cpu)
+            .filter(|(_, info)| {
+                name.as_ref()
+                    .is_none_or(|n| info.name.to_lowercase().contains(n))
+            })
+            .min_by_key(|(i, info)| (self.rank(info.device_type), *i))
+            .map(|(i, _)| i)
+    }
+
+    fn rank(&self, device_type: wgpu::DeviceType) -> u8 {
+        use wgpu::{DeviceType, PowerPreference};
+
+        match (self.power, device_type) {
+            (PowerPreference::HighPerformance, DeviceType::DiscreteGpu) => 0,
+            (PowerPreference::HighPerformance, DeviceType::IntegratedGpu) => 1,
+            (PowerPreference::LowPower, DeviceType::IntegratedGpu) => 0,
+            (PowerPreference::LowPower, DeviceType::DiscreteGpu) => 1,
+            (_, DeviceType::DiscreteGpu | DeviceType::IntegratedGpu) => 0,
+            (_, DeviceType::VirtualGpu) => 2,
+            (_, DeviceType::Other) => 3,
+            (_, DeviceType::Cpu) => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AdapterSelectionError {
+    /// `TORCHIC_BACKEND` doesn't name any known backend.
+    InvalidBackend(String),
+    NoMatchingAdapter {
+        available: Vec<String>,
+    },
+    Config(RuntimeConfigError),
+}
+
+impl fmt::Display for AdapterSelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdapterSelectionError::InvalidBackend(b) => write!(f, "unknown backend '{}'", b),
+            AdapterSelectionError::NoMatchingAdapter { available } => write!(
+                f,
+                "no adapter matches the preference, available adapters: [{}]",
+                available.join(", ")
+            ),
+            AdapterSelectionError::Config(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AdapterSelectionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(
+        name: &str,
+        device_type: wgpu::DeviceType,
+        backend: wgpu::Backend,
+    ) -> wgpu::AdapterInfo {
+        wgpu::AdapterInfo {
+            name: name.to_string(),
+            vendor: 0,
+            device: 0,
+            device_type,
+            device_pci_bus_id: String::new(),
+            driver: String::new(),
+            driver_info: String::new(),
+            backend,
+            subgroup_min_size: 4,
+            subgroup_max_size: 128,
+            transient_saves_memory: false,
+        }
+    }
+
+    fn infos() -> Vec<wgpu::AdapterInfo> {
+        use wgpu::{Backend, DeviceType};
+
+        vec![
+            info("llvmpipe", DeviceType::Cpu, Backend::Vulkan),
+            info("Intel UHD", DeviceType::IntegratedGpu, Backend::Vulkan),
+            info("NVIDIA RTX", DeviceType::DiscreteGpu, Backend::Vulkan),
+            info("NVIDIA RTX", DeviceType::DiscreteGpu, Backend::Gl),
+        ]
+    }
+
+    #[test]
+    fn pick_ranks_by_power_preference() {
+        let infos = infos();
+
+        let pref = AdapterPreference::new();
+        assert_eq!(pref.pick(&infos), Some(1));
+
+        let pref = AdapterPreference::new().power(wgpu::PowerPreference::HighPerformance);
+        assert_eq!(pref.pick(&infos), Some(2));
+
+        let pref = AdapterPreference::new().power(wgpu::PowerPreference::LowPower);
+        assert_eq!(pref.pick(&infos), Some(1));
+    }
+
+    #[test]
+    fn pick_filters_by_backend_name_and_fallback() {
+        let infos = infos();
+
+        let pref = AdapterPreference::new().backends(wgpu::Backends::GL);
+        assert_eq!(pref.pick(&infos), Some(3));
+
+        let pref = AdapterPreference::new().name_contains("intel");
+        assert_eq!(pref.pick(&infos), Some(1));
+
+        let pref = AdapterPreference::new().force_fallback(true);
+        assert_eq!(pref.pick(&infos), Some(0));
+
+        let pref = AdapterPreference::new().name_contains("radeon");
+        assert_eq!(pref.pick(&infos), None);
+    }
+
+    #[test]
+    fn env_overrides_preference() {
+        let pref = AdapterPreference::new()
+            .name_contains("intel")
+            .with_env(Some("rtx"), Some("gl"))
+            .unwrap();
+        assert_eq!(pref.pick(&infos()), Some(3));
+
+        let pref = AdapterPreference::new()
+            .with_env(Some("fallback"), None)
+            .unwrap();
+        assert_eq!(pref.pick(&infos()), Some(0));
+
+        assert!(matches!(
+            AdapterPreference::new().with_env(None, Some("glide")),
+            Err(AdapterSelectionError::InvalidBackend(_))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a fallback adapter"]
+    fn fallback_adapter_runs_ops() {
+        let adapter = AdapterPreference::new()
+            .force_fallback(true)
+            .select()
+            .expect("No fallback adapter");
+        assert_eq!(adapter.get_info().device_type, wgpu::DeviceType::Cpu);
+
+        let device = crate::runtime::Device::new(adapter, 0);
+        let x = crate::tensor::Tensor::new_on(&device, &[2], &[1.0, 2.0], false);
+        assert_eq!(x.mul_s(2.0).unwrap().to_vec(), vec![2.0, 4.0]);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{cell::Cell, marker::PhantomData};
+
+use crate::{backend::BackendKind, nn::Adam, ops::TensorOpError, runtime::Device, tensor::Tensor};
+
+/// Precision an op computes in. Tensors always store f32, f16 ops round their inputs and result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Precision {
+    F32,
+    F16,
+}
+
+thread_local! {
+    static AUTOCAST: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restores the autocast mode of the current thread that was active before the guard was
+/// created.
+pub struct AutocastGuard {
+    enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for AutocastGuard {
+    fn drop(&mut self) {
+        AUTOCAST.set(self.enabled);
+    }
+}
+
+/// Runs the matmuls of this thread in f16 until the guard is dropped, like `torch.autocast()`.
+/// Every other op stays in f32.
+///
+/// GPU devices need the `SHADER_F16` feature, see `RuntimeConfig::shader_f16`; without it
+/// matmuls keep running in f32. The CPU backend emulates f16 by rounding.
+pub fn autocast() -> AutocastGuard {
+    let guard = AutocastGuard {
+        enabled: AUTOCAST.get(),
+        _not_send: PhantomData,
+    };
+    AUTOCAST.set(true);
+
+    guard
+}
+
+pub fn is_autocast_enabled() -> bool {
+    AUTOCAST.get()
+}
+
+// Precision of the matmuls dispatched now on `device`
+pub(crate) fn matmul_precision(device: &Device) -> Precision {
+    let f16 = match device.backend() {
+        BackendKind::Cpu => true,
+        BackendKind::Wgpu => device.features().contains(wgpu::Features::SHADER_F16),
+    };
+
+    if AUTOCAST.get() && f16 {
+        Precision::F16
+    } else {
+        Precision::F32
+    }
+}
+
+/// Rounds to the nearest f16, ties to even. Values out of its range become infinite.
+pub(crate) fn round_f16(x: f32) -> f32 {
+    if !x.is_finite() {
+        return x;
+    }
+
+    // Spacing of the f16 values around `x`, constant below the smallest normal f16
+    let exponent = ((x.to_bits() >> 23) & 0xff) as i32 - 127;
+    let quantum = 2f32.powi(exponent.max(-14) - 10);
+    let rounded = (x / quantum).round_ties_even() * quantum;
+
+    if rounded.abs() > 65504.0 {
+        f32::INFINITY.copysign(x)
+    } else {
+        rounded
+    }
+}
+
+/// Dynamic loss scaling for `Adam` under `autocast`. Small gradients underflow in f16, so the
+/// loss is scaled up before backward and the gradients scaled back down in the step. Steps with
+/// infinite or NaN gradients are skipped and the scale backs off; after `growth_interval` steps
+/// without overflow it grows again.
+///
+/// ```ignore
+/// let loss = { let _ac = autocast(); model.forward(&x)?.cross_entropy_loss(&y)? };
+/// optimizer.zero_grad();
+/// scaler.scale(&loss)?.backward();
+/// scaler.step(&mut optimizer);
+/// ```
+#[derive(Debug, Clone)]
+pub struct GradScaler {
+    scale: f32,
+    growth_factor: f32,
+    backoff_factor: f32,
+    growth_interval: usize,
+    // Steps since the last overflow or growth
+    good_steps: usize,
+}
+
+impl Default for GradScaler {
+    fn default() -> Self {
+        Self {
+            scale: 65536.0,
+            growth_factor: 2.0,
+            backoff_factor: 0.5,
+            growth_interval: 2000,
+            good_steps: 0,
+        }
+    }
+}
+
+impl GradScaler {
+    /// Starts at a scale of 2^16, halved on overflow and doubled every 2000 good steps.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn init_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn growth_factor(mut self, factor: f32) -> Self {
+        self.growth_factor = factor;
+        self
+    }
+
+    pub fn backoff_factor(mut self, factor: f32) -> Self {
+        self.backoff_factor = factor;
+        self
+    }
+
+    pub fn growth_interval(mut self, steps: usize) -> Self {
+        self.growth_interval = steps;
+        self
+    }
+
+    /// Current loss scale.
+    pub fn get_scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn scale(&self, loss: &Tensor) -> Result<Tensor, TensorOpError> {
+        loss.mul_s(self.scale)
+    }
+
+    /// Unscales the gradients and steps `optimizer`, unless one of them overflowed. Updates the
+    /// scale and returns whether the step was taken.
+    pub fn step(&mut self, optimizer: &mut Adam) -> bool {
+        let finite = optimizer.grads_finite();
+
+        if finite {
+            optimizer.step_with_grad_scale(1.0 / self.scale);
+            self.good_steps += 1;
+            if self.good_steps >= self.growth_interval {
+                self.scale *= self.growth_factor;
+                self.good_steps = 0;
+            }
+        } else {
+            self.scale *= self.backoff_factor;
+            self.good_steps = 0;
+        }
+
+        finite
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        nn::{MLP, Model},
+        runtime::{RuntimeConfig, test_adapter},
+    };
+
+    #[test]
+    fn rounds_like_f16() {
+        assert_eq!(round_f16(1.0), 1.0);
+        // 1 + 2^-11 is halfway between two f16 values and rounds to the even one
+        assert_eq!(round_f16(1.0 + 2f32.powi(-11)), 1.0);
+        assert_eq!(round_f16(1.0 + 3.0 * 2f32.powi(-11)), 1.0 + 2f32.powi(-9));
+        assert_eq!(round_f16(0.1), 0.099975586);
+        assert_eq!(round_f16(65504.0), 65504.0);
+        assert_eq!(round_f16(65519.0), 65504.0);
+        assert_eq!(round_f16(-65520.0), f32::NEG_INFINITY);
+        // Subnormals are multiples of 2^-24
+        assert_eq!(round_f16(2f32.powi(-25)), 0.0);
+        assert_eq!(round_f16(3.0 * 2f32.powi(-25)), 2f32.powi(-23));
+        assert!(round_f16(f32::NAN).is_nan());
+    }
+
+    #[test]
+    fn autocast_rounds_matmuls_only() {
+        let device = Device::cpu(0);
+        let a = Tensor::new_on(&device, &[1, 2], &[0.1, 300.0], false);
+        let b = Tensor::new_on(&device, &[2, 1], &[1.0, 300.0], false);
+
+        {
+            let _ac = autocast();
+            assert!(is_autocast_enabled());
+            // 0.1 rounds to 0.099975586 and 90000.1 overflows
+            assert_eq!(a.matmul(&b).unwrap().to_vec(), vec![f32::INFINITY]);
+            assert_eq!(a.sum().unwrap().to_vec(), vec![300.1]);
+            assert_eq!(a.mul_s(1.0).unwrap().to_vec(), vec![0.1, 300.0]);
+            assert_eq!(a.add(&a).unwrap().to_vec(), vec![0.2, 600.0]);
+        }
+
+        assert!(!is_autocast_enabled());
+        assert_eq!(a.matmul(&b).unwrap().to_vec(), vec![90000.1]);
+    }
+
+    #[test]
+    #[ignore = "needs SHADER_F16"]
+    fn f16_matmul_matches_f32_on_the_gpu() {
+        let config = RuntimeConfig::new().shader_f16(true);
+        let device = Device::with_config(test_adapter(), &config)
+            .expect("No SHADER_F16 support on the test adapter");
+
+        // Sizes that leave tiles partly empty
+        let (m, k, n) = (37, 70, 45);
+        let data = |len: usize, seed: usize| -> Vec<f32> {
+            (0..len)
+                .map(|i| ((i * 31 + seed) % 97) as f32 / 97.0 - 0.5)
+                .collect()
+        };
+        let a = Tensor::new_on(&device, &[m, k], &data(m * k, 1), false);
+        let b = Tensor::new_on(&device, &[k, n], &data(k * n, 2), false);
+
+        let expected = a.matmul(&b).unwrap().to_vec();
+        let f16 = {
+            let _ac = autocast();
+            a.matmul(&b).unwrap().to_vec()
+        };
+        assert_ne!(f16, expected);
+        for (x, e) in f16.iter().zip(&expected) {
+            assert!((x - e).abs() <= 1e-2 * e.abs().max(1.0), "{x} != {e}");
+        }
+    }
+
+    #[test]
+    fn scaler_skips_overflowing_steps_and_unscales_gradients() {
+        let device = Device::cpu(0);
+        let model = MLP::new_on(&device, &[2, 2], false);
+        let mut opt = Adam::new(&model, 0.1, 0.9, 0.999, 1e-8);
+        let reference = MLP::new_on(&Device::cpu(0), &[2, 2], false);
+        let mut reference_opt = Adam::new(&reference, 0.1, 0.9, 0.999, 1e-8);
+        let mut scaler = GradScaler::new().init_scale(f32::MAX).growth_interval(1);
+
+        let step = |model: &MLP, scale: f32| {
+            let x = Tensor::new_on(model.params()[0].device(), &[1, 2], &[1.0, 2.0], false);
+            let out = model.forward(&x).unwrap();
+            out.mul(&out).unwrap().mul_s(scale).unwrap().backward();
+        };
+
+        // The scaled gradients overflow f32
+        opt.zero_grad();
+        step(&model, scaler.get_scale());
+        let before: Vec<_> = model.params().iter().map(|p| p.to_vec()).collect();
+        assert!(!scaler.step(&mut opt));
+        assert_eq!(scaler.get_scale(), f32::MAX / 2.0);
+        let after: Vec<_> = model.params().iter().map(|p| p.to_vec()).collect();
+        assert_eq!(before, after);
+
+        let mut scaler = scaler.init_scale(1024.0);
+        opt.zero_grad();
+        step(&model, scaler.get_scale());
+        assert!(scaler.step(&mut opt));
+        assert_eq!(scaler.get_scale(), 2048.0);
+
+        reference_opt.zero_grad();
+        step(&reference, 1.0);
+        reference_opt.step();
+        for (p, r) in model.params().iter().zip(reference.params()) {
+            for (a, b) in p.to_vec().iter().zip(r.to_vec()) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn grads_are_checked_with_one_readback() {
+        let device = Device::new(test_adapter(), 0);
+        let model = MLP::new_on(&device, &[2, 4, 2], false);
+        let opt = Adam::new(&model, 0.1, 0.9, 0.999, 1e-8);
+        let readbacks = || {
+            let counters = device.stats().readback_buffer_stats.counters;
+            counters.pool_hits + counters.new_allocations
+        };
+
+        for (scale, finite) in [(1.0, true), (f32::MAX, false)] {
+            opt.zero_grad();
+            let x = Tensor::new_on(&device, &[1, 2], &[1.0, 2.0], false);
+            let out = model.forward(&x).unwrap();
+            out.mul(&out).unwrap().mul_s(scale).unwrap().backward();
+
+            let before = readbacks();
+            assert_eq!(opt.grads_finite(), finite);
+            assert_eq!(readbacks(), before + 1);
+        }
+    }
+}
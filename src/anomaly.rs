@@ -0,0 +1,188 @@
+use std::{backtrace::Backtrace, cell::RefCell, fmt, sync::Arc};
+
+use crate::{
+    backend::TensorStorage,
+    ops::{OpType, TensorOpError},
+    runtime::{Runtime, is_anomaly_enabled},
+    tensor::Tensor,
+};
+
+// Bits of `Backend::non_finite_flags`
+pub(crate) const NAN_FLAG: u32 = 1;
+pub(crate) const INF_FLAG: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    NaN,
+    Inf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyPhase {
+    Forward,
+    Backward,
+}
+
+/// Description of the first op that produced a non-finite value while anomaly detection was on.
+#[derive(Debug)]
+pub struct AnomalyReport {
+    pub op: String,
+    pub kind: AnomalyKind,
+    pub phase: AnomalyPhase,
+    pub input_shapes: Vec<Vec<usize>>,
+    /// Forward op whose gradient was being computed, for anomalies found during backward.
+    pub forward_op: Option<String>,
+    /// Where the forward node was created. Only available for nodes created in anomaly mode.
+    pub creation_site: Option<Arc<Backtrace>>,
+}
+
+impl fmt::Display for AnomalyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} produced by {} ({:?}) with input shapes {:?}",
+            self.kind, self.op, self.phase, self.input_shapes
+        )?;
+
+        if let Some(forward_op) = &self.forward_op {
+            write!(f, " while computing the gradient of {}", forward_op)?;
+        }
+
+        match &self.creation_site {
+            Some(site) => write!(f, "\nForward node created at:\n{}", site),
+            None => write!(
+                f,
+                "\nCreation site unknown, enable detect_anomaly() for the forward pass too"
+            ),
+        }
+    }
+}
+
+// Forward node whose backward is currently being computed
+#[derive(Debug, Clone)]
+pub(crate) struct BackwardContext {
+    pub(crate) op: OpType,
+    pub(crate) creation_site: Option<Arc<Backtrace>>,
+}
+
+thread_local! {
+    static BACKWARD_CONTEXT: RefCell<Option<BackwardContext>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn capture_creation_site() -> Option<Arc<Backtrace>> {
+    if is_anomaly_enabled() {
+        Some(Arc::new(Backtrace::force_capture()))
+    } else {
+        None
+    }
+}
+
+// Sets the node whose backward runs on this thread and returns the previous one
+pub(crate) fn set_backward_context(ctx: Option<BackwardContext>) -> Option<BackwardContext> {
+    BACKWARD_CONTEXT.replace(ctx)
+}
+
+/// Checks the output of `op` for NaN/Inf on its device if anomaly detection is enabled.
+pub(crate) fn check_output(
+    rt: &Runtime,
+    op: &OpType,
+    inputs: &[&Tensor],
+    out: &TensorStorage,
+) -> Result<(), TensorOpError> {
+    if !is_anomaly_enabled() {
+        return Ok(());
+    }
+
+    let flags = rt.backend.non_finite_flags(&[out]);
+    let kind = if flags & NAN_FLAG != 0 {
+        AnomalyKind::NaN
+    } else if flags & INF_FLAG != 0 {
+        AnomalyKind::Inf
+    } else {
+        return Ok(());
+    };
+
+    let backward_context = BACKWARD_CONTEXT.with_borrow(|ctx| ctx.clone());
+    let report = match backward_context {
+        Some(ctx) => AnomalyReport {
+            op: format!("{:?}", op),
+            kind,
+            phase: AnomalyPhase::Backward,
+            input_shapes: inputs.iter().map(|t| t.shape().to_vec()).collect(),
+            forward_op: Some(format!("{:?}", ctx.op)),
+            creation_site: ctx.creation_site,
+        },
+        None => AnomalyReport {
+            op: format!("{:?}", op),
+            kind,
+            phase: AnomalyPhase::Forward,
+            input_shapes: inputs.iter().map(|t| t.shape().to_vec()).collect(),
+            forward_op: None,
+            creation_site: capture_creation_site(),
+        },
+    };
+
+    Err(TensorOpError::Anomaly(Box::new(report)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{detect_anomaly, init_test_runtime};
+
+    #[test]
+    fn finite_outputs_pass_the_check() {
+        let _rt = init_test_runtime();
+        let _anomaly = detect_anomaly();
+
+        let x = Tensor::new(&[3], &[1.0, 4.0, 9.0], false);
+        assert_eq!(x.sqrt().unwrap().to_vec(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn forward_anomaly_names_op_and_input_shapes() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, -1.0], false);
+        assert!(x.sqrt().is_ok());
+
+        let _anomaly = detect_anomaly();
+        let Err(TensorOpError::Anomaly(report)) = x.sqrt() else {
+            panic!("sqrt of a negative number should be reported");
+        };
+
+        assert_eq!(report.kind, AnomalyKind::NaN);
+        assert_eq!(report.phase, AnomalyPhase::Forward);
+        assert_eq!(report.op, "UnopEwizeType(Sqrt)");
+        assert_eq!(report.input_shapes, vec![vec![2]]);
+        assert!(report.creation_site.is_some());
+
+        let big = Tensor::new(&[1], &[f32::MAX], false);
+        let Err(TensorOpError::Anomaly(report)) = big.mul_s(2.0) else {
+            panic!("overflow should be reported");
+        };
+        assert_eq!(report.kind, AnomalyKind::Inf);
+    }
+
+    #[test]
+    fn backward_anomaly_names_forward_node() {
+        let _rt = init_test_runtime();
+        let _anomaly = detect_anomaly();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let w = Tensor::new(&[2], &[3.0, 4.0], false);
+        let y = x.mul(&w).unwrap();
+        y.register_hook(|_| Some(Tensor::new(&[2], &[f32::NAN, 0.0], false)))
+            .unwrap();
+
+        let Err(TensorOpError::Anomaly(report)) = y.sum().unwrap().try_backward() else {
+            panic!("NaN gradient should be reported");
+        };
+
+        assert_eq!(report.phase, AnomalyPhase::Backward);
+        assert_eq!(report.op, "BinopEwizeType(Mul)");
+        assert_eq!(report.forward_op.as_deref(), Some("BinopEwizeType(Mul)"));
+        assert_eq!(report.input_shapes, vec![vec![2], vec![2]]);
+        assert!(report.creation_site.is_some());
+    }
+}
@@ -1,34 +1,196 @@
 use std::{
+    backtrace::Backtrace,
     collections::{HashMap, HashSet},
-    sync::Mutex,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 use crate::{
-    ops::{self, OpType, ReduceOpType, ScalarEwizeType},
-    runtime::{no_grad, rt},
-    tensor::Tensor,
+    anomaly::{self, BackwardContext},
+    ops::{self, OpType, ReduceOpType, ScalarEwizeType, TensorOpError},
+    runtime::{
+        Device, default_device, is_anomaly_enabled, is_grad_enabled, no_grad, set_grad_enabled,
+    },
+    tensor::{Tensor, TensorInner, get_tensor_id, is_inference_tensor},
 };
 
 #[derive(Debug)]
 pub(crate) struct GradNode {
     pub(crate) op: OpType,
-    pub(crate) parents: Vec<Tensor>,
+    // Saved inputs of the op. Taken out once backward consumed them, unless the graph is retained
+    parents: Mutex<Option<Vec<Tensor>>>,
     pub(crate) meta: Option<GradNodeMeta>,
+    pub(crate) creation_site: Option<Arc<Backtrace>>,
+}
+
+impl GradNode {
+    pub(crate) fn new(op: OpType, parents: Vec<Tensor>, meta: Option<GradNodeMeta>) -> Self {
+        Self {
+            op,
+            parents: Mutex::new(Some(parents)),
+            meta,
+            creation_site: anomaly::capture_creation_site(),
+        }
+    }
+
+    /// Saved inputs of the op, or `None` if they were already freed by a backward pass.
+    pub(crate) fn parents(&self) -> Option<Vec<Tensor>> {
+        self.parents.lock().unwrap().clone()
+    }
+
+    fn release(&self) -> Option<Vec<Tensor>> {
+        self.parents.lock().unwrap().take()
+    }
 }
 
 #[derive(Debug)]
 pub(crate) enum GradNodeMeta {
     Scalar(f32),
+    Checkpoint(CheckpointMeta),
+}
+
+pub(crate) type CheckpointFn = dyn Fn(&[Tensor]) -> Result<Tensor, TensorOpError> + Send + Sync;
+
+pub(crate) struct CheckpointMeta {
+    f: Arc<CheckpointFn>,
+    // Device whose RNG state is replayed on recomputation
+    device: Device,
+    rng_state: u32,
+}
+
+impl fmt::Debug for CheckpointMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckpointMeta")
+            .field("rng_state", &self.rng_state)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Gradient hook, called with the fully accumulated gradient of a tensor. Returning `Some`
+/// replaces the gradient that is stored and propagated further.
+pub(crate) type GradHook = Arc<dyn Fn(&Tensor) -> Option<Tensor> + Send + Sync>;
+
+/// Hook called on a leaf tensor after its gradient was accumulated into the grad store.
+pub(crate) type PostAccumulateGradHook = Arc<dyn Fn(&Tensor) + Send + Sync>;
+
+static HOOK_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum HookError {
+    RequiresGradDisabled,
+    NonLeafTensor,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum HookKind {
+    Grad,
+    PostAccumulateGrad,
+}
+
+/// Handle returned by hook registration. Hooks stay registered until removed or until the
+/// tensor is dropped.
+#[derive(Debug)]
+pub struct HookHandle {
+    device: Device,
+    tensor_id: u64,
+    hook_id: u64,
+    kind: HookKind,
+}
+
+impl HookHandle {
+    pub fn remove(self) {
+        let rt = self.device.rt();
+        match self.kind {
+            HookKind::Grad => remove_hook(&rt.grad_store.hooks, self.tensor_id, self.hook_id),
+            HookKind::PostAccumulateGrad => remove_hook(
+                &rt.grad_store.post_accumulate_hooks,
+                self.tensor_id,
+                self.hook_id,
+            ),
+        }
+    }
+}
+
+fn remove_hook<H>(hooks: &Mutex<HashMap<u64, Vec<(u64, H)>>>, tensor_id: u64, hook_id: u64) {
+    let mut hooks = hooks.lock().unwrap();
+    if let Some(v) = hooks.get_mut(&tensor_id) {
+        v.retain(|(id, _)| *id != hook_id);
+        if v.is_empty() {
+            hooks.remove(&tensor_id);
+        }
+    }
+}
+
+pub(crate) fn register_hook(tensor: &Tensor, hook: GradHook) -> Result<HookHandle, HookError> {
+    if !tensor.requires_grad() {
+        return Err(HookError::RequiresGradDisabled);
+    }
+
+    let hook_id = HOOK_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    tensor
+        .rt()
+        .grad_store
+        .hooks
+        .lock()
+        .unwrap()
+        .entry(tensor.id())
+        .or_default()
+        .push((hook_id, hook));
+
+    Ok(HookHandle {
+        device: tensor.device().clone(),
+        tensor_id: tensor.id(),
+        hook_id,
+        kind: HookKind::Grad,
+    })
 }
 
+pub(crate) fn register_post_accumulate_grad_hook(
+    tensor: &Tensor,
+    hook: PostAccumulateGradHook,
+) -> Result<HookHandle, HookError> {
+    if !tensor.requires_grad() {
+        return Err(HookError::RequiresGradDisabled);
+    }
+    if tensor.inner.grad_node.is_some() {
+        return Err(HookError::NonLeafTensor);
+    }
+
+    let hook_id = HOOK_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    tensor
+        .rt()
+        .grad_store
+        .post_accumulate_hooks
+        .lock()
+        .unwrap()
+        .entry(tensor.id())
+        .or_default()
+        .push((hook_id, hook));
+
+    Ok(HookHandle {
+        device: tensor.device().clone(),
+        tensor_id: tensor.id(),
+        hook_id,
+        kind: HookKind::PostAccumulateGrad,
+    })
+}
+
+// Reverse postorder, so every tensor comes after all of the tensors that consume it
 fn topo_recursive(tensor: &Tensor, result: &mut Vec<Tensor>, visited: &mut HashSet<u64>) {
     if visited.insert(tensor.id()) {
-        result.push(tensor.clone());
         if let Some(n) = &tensor.inner.grad_node {
-            for parent in &n.parents {
+            let parents = n.parents().expect(
+                "Trying to backward through the graph a second time. \
+                 Saved tensors are freed after backward, use backward_retain_graph() to keep them",
+            );
+            for parent in &parents {
                 topo_recursive(parent, result, visited);
             }
         }
+        result.push(tensor.clone());
     }
 }
 
@@ -37,150 +199,343 @@ fn topo(tensor: &Tensor) -> Vec<Tensor> {
     let mut visited: HashSet<u64> = HashSet::new();
 
     topo_recursive(tensor, &mut result, &mut visited);
+    result.reverse();
 
-    return result;
+    result
 }
 
-pub(crate) fn backward(tensor: &Tensor) {
-    let topo = topo(&tensor);
-    let _ng = no_grad().unwrap();
+pub(crate) fn backward(tensor: &Tensor, retain_graph: bool) -> Result<(), TensorOpError> {
+    let _ng = no_grad();
 
-    rt().grad_store
-        .map
-        .lock()
-        .unwrap()
-        .insert(tensor.id(), Tensor::ones(tensor.shape(), false));
+    run_backward(
+        tensor,
+        Tensor::ones_on(tensor.device(), tensor.shape(), false),
+        retain_graph,
+        &HashSet::new(),
+    )?;
+
+    Ok(())
+}
+
+// Backpropagates `root_grad` from `root`. Gradients of the leaves listed in `captured` are
+// returned instead of being accumulated into the grad store.
+fn run_backward(
+    root: &Tensor,
+    root_grad: Tensor,
+    retain_graph: bool,
+    captured: &HashSet<u64>,
+) -> Result<HashMap<u64, Tensor>, TensorOpError> {
+    let rt = root.rt().clone();
+    let topo = topo(root);
+    let mut captured_grads = HashMap::new();
+
+    // Gradients produced by this pass. A tensor is reached in topological order only once all
+    // of its contributions are accumulated. Its gradient is then consumed and dropped, unless
+    // it is a leaf or was marked with retain_grad().
+    let mut grads: HashMap<u64, Tensor> = HashMap::new();
+    grads.insert(root.id(), root_grad);
 
     for t in topo {
-        if let Some(n) = &t.inner.grad_node {
-            let out_grad = rt()
-                .grad_store
-                .map
-                .lock()
-                .unwrap()
-                .get(&t.id())
-                .unwrap()
-                .clone();
-
-            match &n.op {
-                OpType::BinopEwizeType(typ) => match typ {
-                    ops::BinopEwizeType::Add => {
-                        bin_add_backward(&out_grad, &n.parents[0], &n.parents[1])
-                    }
-                    ops::BinopEwizeType::Mul => {
-                        bin_mul_backward(&out_grad, &n.parents[0], &n.parents[1])
-                    }
-                    ops::BinopEwizeType::Div => todo!(),
-                    ops::BinopEwizeType::Sub => todo!(),
-                },
-                OpType::UnopEwizeType(typ) => match typ {
-                    ops::UnopEwizeType::Relu => relu_backward(&out_grad, &n.parents[0]),
-                    ops::UnopEwizeType::ReluBackward => {
-                        panic!(
-                            "Relu backward cannot be called from user code with grad calculation"
-                        )
-                    }
-                    ops::UnopEwizeType::Sqrt => todo!(),
-                },
-                OpType::Reduce(typ) => match typ {
-                    ReduceOpType::Sum => sum_backward(&out_grad, &n.parents[0]),
-                    ReduceOpType::Max => todo!(), // Too much complexity with current architecture
-                },
-                OpType::Transpose => transpose_backward(&out_grad, &n.parents[0]),
-                OpType::Matmul => matmul_backward(&out_grad, &n.parents[0], &n.parents[1]),
-                OpType::ScalarEwize(typ) => match typ {
-                    ScalarEwizeType::Mul => {
-                        let GradNodeMeta::Scalar(s) = n.meta.as_ref().unwrap();
-                        scal_mul_backward(&out_grad, &n.parents[0], *s)
-                    }
-                    ScalarEwizeType::Add => todo!(),
-                },
-                OpType::Outer => outer_backward(&out_grad, &n.parents[0], &n.parents[1]),
-                OpType::CrossEntropyLoss => {
-                    cross_entropy_loss_backward(&out_grad, &n.parents[0], &n.parents[1])
+        let Some(out_grad) = grads.remove(&t.id()) else {
+            continue;
+        };
+
+        if captured.contains(&t.id()) {
+            captured_grads.insert(t.id(), out_grad);
+            continue;
+        }
+
+        let out_grad = rt.grad_store.run_hooks(&t, out_grad);
+
+        let Some(n) = &t.inner.grad_node else {
+            rt.grad_store.acc(t.id(), &out_grad)?;
+            rt.grad_store.run_post_accumulate_hooks(&t);
+            continue;
+        };
+
+        if rt.grad_store.is_retained(t.id()) {
+            rt.grad_store.acc(t.id(), &out_grad)?;
+        }
+
+        let parents = if retain_graph {
+            n.parents()
+        } else {
+            n.release()
+        }
+        .expect("Saved tensors were released while backward was running");
+
+        let prev_context = is_anomaly_enabled().then(|| {
+            anomaly::set_backward_context(Some(BackwardContext {
+                op: n.op.clone(),
+                creation_site: n.creation_site.clone(),
+            }))
+        });
+
+        let grads = &mut grads;
+        let res = match &n.op {
+            OpType::BinopEwizeType(typ) => match typ {
+                ops::BinopEwizeType::Add => {
+                    bin_add_backward(grads, &out_grad, &parents[0], &parents[1])
+                }
+                ops::BinopEwizeType::Mul => {
+                    bin_mul_backward(grads, &out_grad, &parents[0], &parents[1])
                 }
+                ops::BinopEwizeType::Div => todo!(),
+                ops::BinopEwizeType::Sub => todo!(),
+            },
+            OpType::UnopEwizeType(typ) => match typ {
+                ops::UnopEwizeType::Relu => relu_backward(grads, &out_grad, &parents[0]),
+                ops::UnopEwizeType::ReluBackward => {
+                    panic!("Relu backward cannot be called from user code with grad calculation")
+                }
+                ops::UnopEwizeType::Sqrt => todo!(),
+            },
+            OpType::Reduce(typ) => match typ {
+                ReduceOpType::Sum => sum_backward(grads, &out_grad, &parents[0]),
+                ReduceOpType::Max => todo!(), // Too much complexity with current architecture
+            },
+            OpType::Transpose => transpose_backward(grads, &out_grad, &parents[0]),
+            OpType::Matmul => matmul_backward(grads, &out_grad, &parents[0], &parents[1]),
+            OpType::ScalarEwize(typ) => match typ {
+                ScalarEwizeType::Mul => {
+                    let Some(GradNodeMeta::Scalar(s)) = &n.meta else {
+                        unreachable!("Scalar op node without a scalar")
+                    };
+                    scal_mul_backward(grads, &out_grad, &parents[0], *s)
+                }
+                ScalarEwizeType::Add => todo!(),
+            },
+            OpType::Outer => outer_backward(grads, &out_grad, &parents[0], &parents[1]),
+            OpType::CrossEntropyLoss => {
+                cross_entropy_loss_backward(grads, &out_grad, &parents[0], &parents[1])
+            }
+            OpType::Checkpoint => {
+                let Some(GradNodeMeta::Checkpoint(meta)) = &n.meta else {
+                    unreachable!("Checkpoint node without a function")
+                };
+                checkpoint_backward(grads, &out_grad, &parents, meta)
+            }
+            OpType::FusedEwize => {
+                unreachable!("Fused ops run as separate ops when the graph is recorded")
             }
+            OpType::Int8Matmul => unreachable!("Quantized layers never record the graph"),
+        };
+
+        if let Some(prev) = prev_context {
+            anomaly::set_backward_context(prev);
+        }
+        res?;
+    }
+
+    Ok(captured_grads)
+}
+
+/// Runs `f` without recording the graph and keeps only `inputs` for backward. When the
+/// gradient of the result is needed, `f` is run again with the same RNG state and backpropagated
+/// through, trading compute for activation memory.
+///
+/// Tensors captured by `f` (e.g. model parameters) receive gradients as well. The result requires
+/// grad whenever grad mode is enabled.
+pub fn checkpoint<F>(f: F, inputs: &[Tensor]) -> Result<Tensor, TensorOpError>
+where
+    F: Fn(&[Tensor]) -> Result<Tensor, TensorOpError> + Send + Sync + 'static,
+{
+    if !is_grad_enabled() {
+        return f(inputs);
+    }
+
+    let device = inputs
+        .first()
+        .map_or_else(default_device, |t| t.device().clone());
+    let rng_state = device.rng_state();
+    let out = {
+        let _ng = no_grad();
+        f(inputs)?
+    };
+
+    let node = GradNode::new(
+        OpType::Checkpoint,
+        inputs.to_vec(),
+        Some(GradNodeMeta::Checkpoint(CheckpointMeta {
+            f: Arc::new(f),
+            device,
+            rng_state,
+        })),
+    );
+
+    Ok(Tensor {
+        inner: Arc::new(TensorInner {
+            id: get_tensor_id(),
+            device: out.device().clone(),
+            inference: is_inference_tensor(false),
+            buf: out.storage().clone().into(),
+            pending: Default::default(),
+            shape: out.shape().to_vec(),
+            requires_grad: true,
+            grad_node: Some(node),
+        }),
+    })
+}
+
+fn checkpoint_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    inputs: &[Tensor],
+    meta: &CheckpointMeta,
+) -> Result<(), TensorOpError> {
+    let detached: Vec<Tensor> = inputs
+        .iter()
+        .map(|t| t.detached(t.requires_grad()))
+        .collect();
+
+    let out = {
+        let _eg = set_grad_enabled(true);
+        let rng_state = meta.device.rng_state();
+        meta.device.set_rng_state(meta.rng_state);
+        let out = (meta.f)(&detached);
+        meta.device.set_rng_state(rng_state);
+
+        out?
+    };
+
+    if !out.requires_grad() {
+        return Ok(());
+    }
+
+    let captured = detached.iter().map(|t| t.id()).collect();
+    let mut input_grads = run_backward(&out, out_grad.clone(), false, &captured)?;
+
+    for (input, d) in inputs.iter().zip(&detached) {
+        if let Some(g) = input_grads.remove(&d.id()) {
+            acc(grads, input.id(), &g)?;
         }
     }
+
+    Ok(())
 }
 
-fn outer_backward(out_grad: &Tensor, lhs: &Tensor, rhs: &Tensor) {
+fn outer_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    lhs: &Tensor,
+    rhs: &Tensor,
+) -> Result<(), TensorOpError> {
     if lhs.requires_grad() {
-        acc(lhs.id(), &ops::matmul(out_grad, rhs).unwrap());
+        acc(grads, lhs.id(), &ops::matmul(out_grad, rhs)?)?;
     }
     if rhs.requires_grad() {
         acc(
+            grads,
             rhs.id(),
-            &ops::matmul(&ops::transposed(out_grad).unwrap(), lhs).unwrap(),
-        );
+            &ops::matmul(&ops::transposed(out_grad)?, lhs)?,
+        )?;
     }
+
+    Ok(())
 }
 
-fn scal_mul_backward(out_grad: &Tensor, p: &Tensor, s: f32) {
+fn scal_mul_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    p: &Tensor,
+    s: f32,
+) -> Result<(), TensorOpError> {
     if p.requires_grad() {
-        acc(p.id(), &ops::mul_scalar(out_grad, s).unwrap());
+        acc(grads, p.id(), &ops::mul_scalar(out_grad, s)?)?;
     }
+
+    Ok(())
 }
 
-fn bin_add_backward(out_grad: &Tensor, lhs: &Tensor, rhs: &Tensor) {
+fn bin_add_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    lhs: &Tensor,
+    rhs: &Tensor,
+) -> Result<(), TensorOpError> {
     if lhs.requires_grad() {
-        acc(lhs.id(), &out_grad);
+        acc(grads, lhs.id(), out_grad)?;
     }
     if rhs.requires_grad() {
-        acc(rhs.id(), &out_grad);
+        acc(grads, rhs.id(), out_grad)?;
     }
+
+    Ok(())
 }
 
-fn bin_mul_backward(out_grad: &Tensor, lhs: &Tensor, rhs: &Tensor) {
+fn bin_mul_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    lhs: &Tensor,
+    rhs: &Tensor,
+) -> Result<(), TensorOpError> {
     if lhs.requires_grad() {
-        rt().grad_store
-            .acc(lhs.id(), &ops::mul(&out_grad, rhs).unwrap());
+        acc(grads, lhs.id(), &ops::mul(out_grad, rhs)?)?;
     }
     if rhs.requires_grad() {
-        rt().grad_store
-            .acc(rhs.id(), &ops::mul(&out_grad, lhs).unwrap());
+        acc(grads, rhs.id(), &ops::mul(out_grad, lhs)?)?;
     }
+
+    Ok(())
 }
 
-fn sum_backward(out_grad: &Tensor, p: &Tensor) {
+fn sum_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    p: &Tensor,
+) -> Result<(), TensorOpError> {
     if p.requires_grad() {
         assert!(out_grad.shape().len() == 1 && out_grad.shape()[0] == 1);
         let grad_scal = out_grad.readback()[0];
 
         acc(
+            grads,
             p.id(),
-            &Tensor::new(p.shape(), &vec![grad_scal; p.numel()], false),
-        );
+            &Tensor::new_on(p.device(), p.shape(), &vec![grad_scal; p.numel()], false),
+        )?;
     }
+
+    Ok(())
 }
 
-fn transpose_backward(out_grad: &Tensor, p: &Tensor) {
+fn transpose_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    p: &Tensor,
+) -> Result<(), TensorOpError> {
     if p.requires_grad() {
-        rt().grad_store
-            .acc(p.id(), &ops::transposed(out_grad).unwrap());
+        acc(grads, p.id(), &ops::transposed(out_grad)?)?;
     }
+
+    Ok(())
 }
 
-fn matmul_backward(out_grad: &Tensor, lhs: &Tensor, rhs: &Tensor) {
+fn matmul_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    lhs: &Tensor,
+    rhs: &Tensor,
+) -> Result<(), TensorOpError> {
     if lhs.requires_grad() {
         match (lhs.shape(), rhs.shape()) {
             ([_, _], [_, _]) | ([_], [_, _]) => {
                 // A @ B or x @ B
                 acc(
+                    grads,
                     lhs.id(),
-                    &ops::matmul(out_grad, &ops::transposed(rhs).unwrap()).unwrap(),
-                );
+                    &ops::matmul(out_grad, &ops::transposed(rhs)?)?,
+                )?;
             }
             ([_, _], [_]) => {
                 // A @ y
-                acc(lhs.id(), &ops::outer(out_grad, rhs).unwrap());
+                acc(grads, lhs.id(), &ops::outer(out_grad, rhs)?)?;
             }
             ([_], [_]) => {
                 // x @ y -> scalar
                 assert!(out_grad.shape().len() == 1 && out_grad.shape()[0] == 1);
                 let scalar = out_grad.readback()[0];
 
-                acc(lhs.id(), &ops::mul_scalar(rhs, scalar).unwrap());
+                acc(grads, lhs.id(), &ops::mul_scalar(rhs, scalar)?)?;
             }
             _ => {}
         }
@@ -191,40 +546,54 @@ fn matmul_backward(out_grad: &Tensor, lhs: &Tensor, rhs: &Tensor) {
             ([_, _], [_, _]) | ([_, _], [_]) => {
                 // A @ B or A @ y
                 acc(
+                    grads,
                     rhs.id(),
-                    &ops::matmul(&ops::transposed(lhs).unwrap(), out_grad).unwrap(),
-                );
+                    &ops::matmul(&ops::transposed(lhs)?, out_grad)?,
+                )?;
             }
             ([_], [_, _]) => {
                 // x @ B
-                acc(rhs.id(), &ops::outer(lhs, out_grad).unwrap());
+                acc(grads, rhs.id(), &ops::outer(lhs, out_grad)?)?;
             }
             ([_], [_]) => {
                 // x @ y -> scalar
                 assert!(out_grad.shape().len() == 1 && out_grad.shape()[0] == 1);
                 let scalar = out_grad.readback()[0];
 
-                acc(rhs.id(), &ops::mul_scalar(lhs, scalar).unwrap());
+                acc(grads, rhs.id(), &ops::mul_scalar(lhs, scalar)?)?;
             }
             _ => {}
         }
     }
+
+    Ok(())
 }
 
-fn relu_backward(out_grad: &Tensor, p: &Tensor) {
+fn relu_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    p: &Tensor,
+) -> Result<(), TensorOpError> {
     if p.requires_grad() {
         acc(
+            grads,
             p.id(),
             &ops::mul(
                 out_grad,
-                &ops::dispatch_unop_ewize(p, ops::UnopEwizeType::ReluBackward).unwrap(),
-            )
-            .unwrap(),
-        );
+                &ops::dispatch_unop_ewize(p, ops::UnopEwizeType::ReluBackward)?,
+            )?,
+        )?;
     }
+
+    Ok(())
 }
 
-fn cross_entropy_loss_backward(out_grad: &Tensor, logits: &Tensor, targets: &Tensor) {
+fn cross_entropy_loss_backward(
+    grads: &mut HashMap<u64, Tensor>,
+    out_grad: &Tensor,
+    logits: &Tensor,
+    targets: &Tensor,
+) -> Result<(), TensorOpError> {
     assert!(out_grad.shape().len() == 1 && out_grad.shape()[0] == 1);
 
     let out_grad_scalar = out_grad.readback()[0];
@@ -244,9 +613,10 @@ fn cross_entropy_loss_backward(out_grad: &Tensor, logits: &Tensor, targets: &Ten
             out_grad_scalar,
         );
         acc(
+            grads,
             logits.id(),
-            &Tensor::new(logits.shape(), &logits_grad, false),
-        );
+            &Tensor::new_on(logits.device(), logits.shape(), &logits_grad, false),
+        )?;
     }
 
     if targets.requires_grad() {
@@ -257,20 +627,40 @@ fn cross_entropy_loss_backward(out_grad: &Tensor, logits: &Tensor, targets: &Ten
             out_grad_scalar,
         );
         acc(
+            grads,
             targets.id(),
-            &Tensor::new(targets.shape(), &targets_grad, false),
-        );
+            &Tensor::new_on(targets.device(), targets.shape(), &targets_grad, false),
+        )?;
     }
+
+    Ok(())
 }
 
-fn acc(id: u64, t: &Tensor) {
-    rt().grad_store.acc(id, t);
+fn acc(grads: &mut HashMap<u64, Tensor>, id: u64, t: &Tensor) -> Result<(), TensorOpError> {
+    let sum = match grads.get(&id) {
+        Some(e) => ops::add(e, t)?,
+        None => t.clone(),
+    };
+    grads.insert(id, sum);
+
+    Ok(())
 }
 
-#[derive(Debug)]
 pub(crate) struct GradStore {
     pub(crate) map: Mutex<HashMap<u64, Tensor>>,
     pub(crate) orphans: Mutex<HashSet<u64>>,
+    pub(crate) retained: Mutex<HashSet<u64>>,
+    pub(crate) hooks: Mutex<HashMap<u64, Vec<(u64, GradHook)>>>,
+    pub(crate) post_accumulate_hooks: Mutex<HashMap<u64, Vec<(u64, PostAccumulateGradHook)>>>,
+}
+
+impl fmt::Debug for GradStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GradStore")
+            .field("map", &self.map)
+            .field("orphans", &self.orphans)
+            .finish_non_exhaustive()
+    }
 }
 
 impl GradStore {
@@ -278,32 +668,377 @@ impl GradStore {
         Self {
             map: Mutex::new(HashMap::new()),
             orphans: Mutex::new(HashSet::new()),
+            retained: Mutex::new(HashSet::new()),
+            hooks: Mutex::new(HashMap::new()),
+            post_accumulate_hooks: Mutex::new(HashMap::new()),
         }
     }
 
-    pub(crate) fn acc(&self, id: u64, t: &Tensor) {
-        self.map
-            .lock()
-            .unwrap()
-            .entry(id)
-            .and_modify(|e| *e = ops::add(e, t).unwrap())
-            .or_insert(t.clone());
+    pub(crate) fn acc(&self, id: u64, t: &Tensor) -> Result<(), TensorOpError> {
+        let mut map = self.map.lock().unwrap();
+        let sum = match map.get(&id) {
+            Some(e) => ops::add(e, t)?,
+            None => t.clone(),
+        };
+        map.insert(id, sum);
+
+        Ok(())
     }
 
-    pub(crate) fn cleanup(&self) {
-        if self.orphans.lock().unwrap().is_empty() {
-            return;
+    pub(crate) fn retain(&self, id: u64) {
+        self.retained.lock().unwrap().insert(id);
+    }
+
+    pub(crate) fn is_retained(&self, id: u64) -> bool {
+        self.retained.lock().unwrap().contains(&id)
+    }
+
+    pub(crate) fn run_hooks(&self, t: &Tensor, mut grad: Tensor) -> Tensor {
+        // Hooks are cloned out so they can register or remove hooks themselves
+        let hooks = match self.hooks.lock().unwrap().get(&t.id()) {
+            Some(hooks) => hooks.clone(),
+            None => return grad,
+        };
+
+        for (_, hook) in hooks {
+            if let Some(new_grad) = hook(&grad) {
+                assert!(
+                    new_grad.shape() == t.shape(),
+                    "Gradient hook returned a tensor of shape {:?} for a tensor of shape {:?}",
+                    new_grad.shape(),
+                    t.shape()
+                );
+                grad = new_grad;
+            }
         }
 
-        let orphans = self.orphans.lock().unwrap().clone();
+        grad
+    }
+
+    pub(crate) fn run_post_accumulate_hooks(&self, t: &Tensor) {
+        let hooks = match self.post_accumulate_hooks.lock().unwrap().get(&t.id()) {
+            Some(hooks) => hooks.clone(),
+            None => return,
+        };
+
+        for (_, hook) in hooks {
+            hook(t);
+        }
+    }
+
+    pub(crate) fn cleanup(&self) {
+        let orphans = std::mem::take(&mut *self.orphans.lock().unwrap());
         for orphan in orphans {
             self.map.lock().unwrap().remove(&orphan);
+            self.retained.lock().unwrap().remove(&orphan);
+            self.hooks.lock().unwrap().remove(&orphan);
+            self.post_accumulate_hooks.lock().unwrap().remove(&orphan);
+        }
+    }
+
+    /// Forgets everything stored for a dropped tensor. Gradients hold their device, so they are
+    /// removed right away to not keep it alive. If the store is in use, e.g. because the tensor
+    /// is dropped while one of the maps is locked, the removal is deferred to `cleanup()`.
+    pub(crate) fn release(&self, id: u64) {
+        let (Ok(mut map), Ok(mut retained), Ok(mut hooks), Ok(mut post_accumulate_hooks)) = (
+            self.map.try_lock(),
+            self.retained.try_lock(),
+            self.hooks.try_lock(),
+            self.post_accumulate_hooks.try_lock(),
+        ) else {
+            self.orphans.lock().unwrap().insert(id);
+            return;
+        };
+
+        let removed = (
+            map.remove(&id),
+            hooks.remove(&id),
+            post_accumulate_hooks.remove(&id),
+        );
+        retained.remove(&id);
+
+        // The removed values can hold tensors whose drop calls back into the store
+        drop((map, retained, hooks, post_accumulate_hooks));
+        drop(removed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::runtime::{self, init_test_runtime};
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn topo_places_shared_parent_after_all_consumers() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let a = x.relu().unwrap();
+        let y = a.add(&a.relu().unwrap()).unwrap();
+
+        let order: Vec<u64> = topo(&y).iter().map(|t| t.id()).collect();
+        let pos = |id| order.iter().position(|v| *v == id).unwrap();
+
+        assert_eq!(order[0], y.id());
+        assert!(pos(a.id()) > 1);
+        assert_eq!(*order.last().unwrap(), x.id());
+
+        y.backward();
+        assert_close(&x.grad().unwrap().to_vec(), &[2.0, 2.0]);
+    }
+
+    #[test]
+    fn hook_observes_and_replaces_intermediate_gradient() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[3], &[1.0, 2.0, 3.0], true);
+        let y = x.mul_s(2.0).unwrap();
+        let loss = y.sum().unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_hook = seen.clone();
+        y.register_hook(move |g| {
+            *seen_hook.lock().unwrap() = g.to_vec();
+            Some(g.mul_s(0.5).unwrap())
+        })
+        .unwrap();
+
+        y.retain_grad();
+        loss.backward();
+
+        assert_close(&seen.lock().unwrap(), &[1.0, 1.0, 1.0]);
+        assert_close(&y.grad().unwrap().to_vec(), &[0.5, 0.5, 0.5]);
+        assert_close(&x.grad().unwrap().to_vec(), &[1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn removed_hook_is_not_called() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_hook = calls.clone();
+        let handle = x
+            .register_hook(move |_| {
+                calls_hook.fetch_add(1, Ordering::Relaxed);
+                None
+            })
+            .unwrap();
+        handle.remove();
+
+        x.mul_s(3.0).unwrap().sum().unwrap().backward();
+
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        assert_close(&x.grad().unwrap().to_vec(), &[3.0, 3.0]);
+    }
+
+    #[test]
+    fn post_accumulate_hook_sees_accumulated_leaf_gradient() {
+        let _rt = init_test_runtime();
+
+        let w = Tensor::new(&[2], &[1.0, -1.0], true);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_hook = seen.clone();
+        w.register_post_accumulate_grad_hook(move |t| {
+            seen_hook.lock().unwrap().push(t.grad().unwrap().to_vec());
+        })
+        .unwrap();
+
+        w.mul_s(2.0).unwrap().sum().unwrap().backward();
+        w.mul_s(2.0).unwrap().sum().unwrap().backward();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_close(&seen[0], &[2.0, 2.0]);
+        assert_close(&seen[1], &[4.0, 4.0]);
+    }
+
+    #[test]
+    fn backward_keeps_only_leaf_and_retained_gradients() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let a = x.mul_s(2.0).unwrap();
+        let b = a.mul_s(3.0).unwrap();
+        let loss = b.sum().unwrap();
+        b.retain_grad();
+
+        loss.backward();
+
+        assert!(a.grad().is_none());
+        assert!(loss.grad().is_none());
+        assert_close(&b.grad().unwrap().to_vec(), &[1.0, 1.0]);
+        assert_close(&x.grad().unwrap().to_vec(), &[6.0, 6.0]);
+    }
+
+    #[test]
+    fn backward_frees_saved_tensors() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let loss = x.mul_s(2.0).unwrap().sum().unwrap();
+        assert_eq!(Arc::strong_count(&x.inner), 2);
+
+        loss.backward();
+
+        assert_eq!(Arc::strong_count(&x.inner), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "backward through the graph a second time")]
+    fn second_backward_without_retained_graph_panics() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let loss = x.mul_s(2.0).unwrap().sum().unwrap();
+
+        loss.backward();
+        loss.backward();
+    }
+
+    #[test]
+    fn retained_graph_can_be_backpropagated_again() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let loss = x.mul_s(2.0).unwrap().sum().unwrap();
+
+        loss.backward_retain_graph();
+        loss.backward();
+
+        assert_close(&x.grad().unwrap().to_vec(), &[4.0, 4.0]);
+    }
+
+    #[test]
+    fn checkpoint_matches_regular_backward() {
+        let _rt = init_test_runtime();
+
+        let w = Tensor::new(&[2, 2], &[1.0, -2.0, 0.5, 3.0], true);
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+
+        let block = {
+            let w = w.clone();
+            move |inputs: &[Tensor]| inputs[0].matmul(&w)?.relu()?.mul_s(2.0)
+        };
+
+        block(std::slice::from_ref(&x))
+            .unwrap()
+            .sum()
+            .unwrap()
+            .backward();
+        let expected_w = w.grad().unwrap().to_vec();
+        let expected_x = x.grad().unwrap().to_vec();
+        w.zero_grad();
+        x.zero_grad();
+
+        let out = checkpoint(block, std::slice::from_ref(&x)).unwrap();
+        assert!(out.requires_grad());
+        out.sum().unwrap().backward();
+
+        assert_close(&w.grad().unwrap().to_vec(), &expected_w);
+        assert_close(&x.grad().unwrap().to_vec(), &expected_x);
+    }
+
+    #[test]
+    fn checkpoint_replays_rng_state() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let states = Arc::new(Mutex::new(vec![]));
+        let states_f = states.clone();
+
+        let out = checkpoint(
+            move |inputs| {
+                states_f.lock().unwrap().push(runtime::rng_state());
+                default_device().rt().next_seed();
+                inputs[0].mul_s(2.0)
+            },
+            std::slice::from_ref(&x),
+        )
+        .unwrap();
+        let state_after_forward = runtime::rng_state();
+
+        out.sum().unwrap().backward();
+
+        let states = states.lock().unwrap();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0], states[1]);
+        assert_eq!(runtime::rng_state(), state_after_forward);
+        assert_close(&x.grad().unwrap().to_vec(), &[2.0, 2.0]);
+    }
+
+    #[test]
+    fn hook_registration_validates_tensor() {
+        let _rt = init_test_runtime();
+
+        let c = Tensor::new(&[1], &[1.0], false);
+        assert!(matches!(
+            c.register_hook(|_| None),
+            Err(HookError::RequiresGradDisabled)
+        ));
+
+        let x = Tensor::new(&[1], &[1.0], true);
+        let y = x.mul_s(2.0).unwrap();
+        assert!(matches!(
+            y.register_post_accumulate_grad_hook(|_| {}),
+            Err(HookError::NonLeafTensor)
+        ));
+    }
+
+    #[test]
+    fn grad_mode_guards_nest_and_restore() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[1], &[1.0], true);
+        {
+            let _outer = no_grad();
+            {
+                let _inner = no_grad();
+                assert!(!x.mul_s(2.0).unwrap().requires_grad());
+
+                let _eg = runtime::enable_grad();
+                assert!(x.mul_s(2.0).unwrap().requires_grad());
+            }
+            assert!(!runtime::is_grad_enabled());
         }
+        assert!(runtime::is_grad_enabled());
+    }
+
+    #[test]
+    fn grad_mode_is_per_thread() {
+        let _rt = init_test_runtime();
+        let _ng = no_grad();
 
-        self.orphans.lock().unwrap().clear();
+        let other_thread = std::thread::spawn(runtime::is_grad_enabled).join().unwrap();
+        assert!(other_thread);
+        assert!(!runtime::is_grad_enabled());
     }
 
-    pub fn add_orphan(&self, id: u64) {
-        self.orphans.lock().unwrap().insert(id);
+    #[test]
+    fn inference_mode_skips_graph_bookkeeping() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let _im = runtime::inference_mode();
+        let _eg = runtime::enable_grad();
+
+        let y = x.mul_s(2.0).unwrap();
+        assert!(!y.requires_grad());
+        assert!(y.inner.grad_node.is_none());
+        assert!(y.is_inference());
+        assert!(!x.is_inference());
+
+        let id = y.id();
+        drop(y);
+        assert!(!x.rt().grad_store.orphans.lock().unwrap().contains(&id));
     }
 }
@@ -0,0 +1,172 @@
+use std::{fmt, sync::RwLock};
+
+use crate::{
+    AsBindingResource,
+    amp::Precision,
+    buffer_alloc::{BufferLease, usage_marker::Storage},
+    fusion::FusedProgram,
+    ops::{BinopEwizeType, ReduceOpType, ScalarEwizeType, UnopEwizeType},
+    readback::HostCopy,
+    runtime::RuntimeStats,
+    tensor::DTYPE_SIZE,
+    trace::TracedValue,
+};
+
+pub(crate) mod cpu;
+pub(crate) mod gpu;
+
+pub(crate) use cpu::CpuBackend;
+pub(crate) use gpu::GpuBackend;
+
+/// Implementation that runs the ops of a `Device`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Kernels dispatched to a wgpu adapter.
+    Wgpu,
+    /// Multithreaded pure Rust implementation, mostly useful as a reference for the GPU kernels.
+    Cpu,
+}
+
+/// Data of a tensor, living where its backend runs the ops.
+#[derive(Debug)]
+pub(crate) enum TensorStorage {
+    Gpu(BufferLease<Storage>),
+    // Locked only to copy into it in `Tensor::assign`
+    Cpu(RwLock<Vec<f32>>),
+    // Placeholder for an op recorded by `trace`, never reaches a backend
+    Traced(TracedValue),
+}
+
+const TRACED_DATA: &str = "traced tensors have no data, run the compiled graph to compute them";
+
+impl TensorStorage {
+    /// Size in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        match self {
+            TensorStorage::Gpu(buf) => buf.size(),
+            TensorStorage::Cpu(data) => (data.read().unwrap().len() * DTYPE_SIZE) as u64,
+            TensorStorage::Traced(value) => (value.numel * DTYPE_SIZE) as u64,
+        }
+    }
+
+    pub(crate) fn numel(&self) -> usize {
+        self.size() as usize / DTYPE_SIZE
+    }
+
+    pub(crate) fn gpu(&self) -> &BufferLease<Storage> {
+        match self {
+            TensorStorage::Gpu(buf) => buf,
+            TensorStorage::Cpu(_) => panic!("expected a tensor stored on the GPU"),
+            TensorStorage::Traced(_) => panic!("{}", TRACED_DATA),
+        }
+    }
+
+    pub(crate) fn cpu(&self) -> &RwLock<Vec<f32>> {
+        match self {
+            TensorStorage::Cpu(data) => data,
+            TensorStorage::Gpu(_) => panic!("expected a tensor stored on the CPU"),
+            TensorStorage::Traced(_) => panic!("{}", TRACED_DATA),
+        }
+    }
+}
+
+impl AsBindingResource for TensorStorage {
+    fn as_binding_resource(&self) -> wgpu::BindingResource<'_> {
+        self.gpu().as_binding_resource()
+    }
+}
+
+/// Op set every backend has to provide. Inputs were already validated by `ops` and always come
+/// from this backend. Matrices are row major.
+pub(crate) trait Backend: fmt::Debug + Send + Sync {
+    fn kind(&self) -> BackendKind;
+
+    fn upload(&self, data: &[f32]) -> TensorStorage;
+    fn download(&self, src: &TensorStorage) -> Vec<f32>;
+    /// Starts copying `src` to host memory without waiting for it.
+    fn copy_to_host(&self, src: &TensorStorage) -> HostCopy;
+    /// Overwrites `dst` with the contents of `src`, both have the same size.
+    fn copy(&self, src: &TensorStorage, dst: &TensorStorage);
+
+    fn scalar_ewize(&self, typ: &ScalarEwizeType, t: &TensorStorage, s: f32) -> TensorStorage;
+    fn unop_ewize(&self, typ: &UnopEwizeType, t: &TensorStorage) -> TensorStorage;
+    fn binop_ewize(
+        &self,
+        typ: &BinopEwizeType,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+    ) -> TensorStorage;
+    /// Reduces the whole tensor to a single element.
+    fn reduce(&self, typ: &ReduceOpType, t: &TensorStorage) -> TensorStorage;
+    /// `[m, k] x [k, n]` matrix product computed in `precision`, see `amp::autocast`.
+    fn matmul(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        m: usize,
+        n: usize,
+        k: usize,
+        precision: Precision,
+    ) -> TensorStorage;
+    /// Transposes an `[m, n]` matrix.
+    fn transpose(&self, t: &TensorStorage, m: usize, n: usize) -> TensorStorage;
+    fn outer(&self, lhs: &TensorStorage, rhs: &TensorStorage, m: usize, n: usize) -> TensorStorage;
+    /// `numel` samples of a normal distribution scaled by `fact`. The same seed gives the same
+    /// samples on every backend, up to float rounding.
+    fn he_init(&self, numel: usize, fact: f32, seed: u32) -> TensorStorage;
+    /// Evaluates `program` over same-sized `inputs` in one pass.
+    fn fused_ewize(
+        &self,
+        program: &FusedProgram,
+        inputs: &[&TensorStorage],
+        scalars: &[f32],
+    ) -> TensorStorage;
+    /// Most inputs `fused_ewize` accepts, longer chains run unfused.
+    fn max_fused_inputs(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Quantizes an `[m, k]` matrix to the int8 values `round(x / scale)`, clamped to ±127. Each
+    /// row is packed four values per word, zero padded to `k.div_ceil(4)` words.
+    fn quantize_int8(&self, t: &TensorStorage, m: usize, k: usize, scale: f32) -> TensorStorage;
+    /// Product of an `[m, k]` matrix and the transpose of an `[n, k]` one, both packed by
+    /// `quantize_int8`. Products are accumulated in f32 and column `j` is scaled by `scales[j]`.
+    fn int8_matmul(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        scales: &TensorStorage,
+        m: usize,
+        n: usize,
+        k: usize,
+    ) -> TensorStorage;
+
+    /// Largest buffer, in bytes, a tensor can be stored in.
+    fn max_buffer_bytes(&self) -> u64 {
+        u64::MAX
+    }
+    /// Largest range of a buffer, in bytes, a kernel can bind at once.
+    fn max_binding_bytes(&self) -> u64 {
+        u64::MAX
+    }
+    /// Rows a kernel binds at once when it walks `rows` rows of matrices with rows of `widths`
+    /// elements, in windows when they are larger than `max_binding_bytes()`. Zero when even a
+    /// few rows don't fit in a binding.
+    fn window_rows(&self, rows: usize, _widths: &[usize]) -> usize {
+        rows
+    }
+
+    /// `anomaly::NAN_FLAG` and `anomaly::INF_FLAG` for the non-finite values found in any of
+    /// `ts`, read back to the host once.
+    fn non_finite_flags(&self, ts: &[&TensorStorage]) -> u32;
+
+    /// Waits until all the work queued on the device is done.
+    fn synchronize(&self);
+    /// Frees what isn't used anymore, called after each readback and backward pass.
+    fn cleanup(&self);
+    fn stats(&self) -> RuntimeStats;
+
+    fn as_gpu(&self) -> Option<&GpuBackend> {
+        None
+    }
+}
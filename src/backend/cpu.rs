@@ -0,0 +1,589 @@
+use std::{borrow::Cow, sync::RwLock, thread};
+
+use ndarray::{ArrayView1, ArrayView2, ArrayViewMut2, linalg::general_mat_mul, s};
+
+use crate::{
+    amp::{Precision, round_f16},
+    anomaly::{INF_FLAG, NAN_FLAG},
+    backend::{Backend, BackendKind, TensorStorage},
+    buffer_alloc::{AllocatorCounters, BufferAllocStats},
+    fusion::{FusedInstr, FusedProgram},
+    metadata_arena::MetadataArenaStats,
+    ops::{BinopEwizeType, ReduceOpType, ScalarEwizeType, UnopEwizeType},
+    quant::{pack_int8, unpack_int8},
+    readback::HostCopy,
+    runtime::RuntimeStats,
+};
+
+// Smaller outputs aren't worth spawning threads for
+const MIN_CHUNK: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct CpuBackend {
+    threads: usize,
+}
+
+impl CpuBackend {
+    pub(crate) fn new() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    // Fills `out` from several threads. Chunks are multiples of `align` elements and `f` gets the
+    // offset of its chunk
+    fn par_fill(&self, out: &mut [f32], align: usize, f: impl Fn(usize, &mut [f32]) + Sync) {
+        if out.is_empty() {
+            return;
+        }
+
+        let align = align.max(1);
+        let chunk = out
+            .len()
+            .div_ceil(self.threads)
+            .max(MIN_CHUNK)
+            .div_ceil(align)
+            * align;
+
+        if chunk >= out.len() {
+            f(0, out);
+            return;
+        }
+
+        thread::scope(|s| {
+            for (i, c) in out.chunks_mut(chunk).enumerate() {
+                let f = &f;
+                s.spawn(move || f(i * chunk, c));
+            }
+        });
+    }
+
+    fn map(&self, t: &TensorStorage, f: impl Fn(f32) -> f32 + Sync) -> TensorStorage {
+        let t = t.cpu().read().unwrap();
+        let mut out = vec![0.0; t.len()];
+
+        self.par_fill(&mut out, 1, |off, c| {
+            for (o, &x) in c.iter_mut().zip(&t[off..]) {
+                *o = f(x);
+            }
+        });
+
+        storage(out)
+    }
+
+    fn zip(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        f: impl Fn(f32, f32) -> f32 + Sync,
+    ) -> TensorStorage {
+        let lhs = lhs.cpu().read().unwrap();
+        let rhs = rhs.cpu().read().unwrap();
+        let mut out = vec![0.0; lhs.len()];
+
+        self.par_fill(&mut out, 1, |off, c| {
+            for ((o, &a), &b) in c.iter_mut().zip(&lhs[off..]).zip(&rhs[off..]) {
+                *o = f(a, b);
+            }
+        });
+
+        storage(out)
+    }
+}
+
+// Rounds the inputs and results of matmuls computing in `precision`
+fn rounding(precision: Precision) -> fn(f32) -> f32 {
+    match precision {
+        Precision::F32 => |x| x,
+        Precision::F16 => round_f16,
+    }
+}
+
+fn scalar_fn(typ: &ScalarEwizeType) -> fn(f32, f32) -> f32 {
+    match typ {
+        ScalarEwizeType::Mul => |x, s| x * s,
+        ScalarEwizeType::Add => |x, s| x + s,
+    }
+}
+
+fn unop_fn(typ: &UnopEwizeType) -> fn(f32) -> f32 {
+    match typ {
+        UnopEwizeType::Relu => |x| if x >= 0.0 { x } else { 0.0 },
+        UnopEwizeType::ReluBackward => |x| if x > 0.0 { 1.0 } else { 0.0 },
+        UnopEwizeType::Sqrt => f32::sqrt,
+    }
+}
+
+fn binop_fn(typ: &BinopEwizeType) -> fn(f32, f32) -> f32 {
+    match typ {
+        BinopEwizeType::Add => |a, b| a + b,
+        BinopEwizeType::Mul => |a, b| a * b,
+        BinopEwizeType::Div => |a, b| a / b,
+        BinopEwizeType::Sub => |a, b| a - b,
+    }
+}
+
+fn storage(data: Vec<f32>) -> TensorStorage {
+    TensorStorage::Cpu(RwLock::new(data))
+}
+
+// Same hash and Box-Muller transform as `normal.wgsl`
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+fn rand(x: u32) -> f32 {
+    ((mix(x) >> 8) as f32 + 0.5) * (1.0 / 16777216.0)
+}
+
+fn randn(x: u32) -> f32 {
+    let u1 = rand(x.wrapping_mul(2));
+    let u2 = rand(x.wrapping_mul(2).wrapping_add(1));
+
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+impl Backend for CpuBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Cpu
+    }
+
+    fn upload(&self, data: &[f32]) -> TensorStorage {
+        storage(data.to_vec())
+    }
+
+    fn download(&self, src: &TensorStorage) -> Vec<f32> {
+        src.cpu().read().unwrap().clone()
+    }
+
+    fn copy_to_host(&self, src: &TensorStorage) -> HostCopy {
+        HostCopy::ready(self.download(src))
+    }
+
+    fn copy(&self, src: &TensorStorage, dst: &TensorStorage) {
+        if std::ptr::eq(src, dst) {
+            return;
+        }
+
+        let src = src.cpu().read().unwrap();
+        dst.cpu().write().unwrap().copy_from_slice(&src);
+    }
+
+    fn scalar_ewize(&self, typ: &ScalarEwizeType, t: &TensorStorage, s: f32) -> TensorStorage {
+        let f = scalar_fn(typ);
+        self.map(t, |x| f(x, s))
+    }
+
+    fn unop_ewize(&self, typ: &UnopEwizeType, t: &TensorStorage) -> TensorStorage {
+        let f = unop_fn(typ);
+        self.map(t, f)
+    }
+
+    fn binop_ewize(
+        &self,
+        typ: &BinopEwizeType,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+    ) -> TensorStorage {
+        let f = binop_fn(typ);
+        self.zip(lhs, rhs, f)
+    }
+
+    fn reduce(&self, typ: &ReduceOpType, t: &TensorStorage) -> TensorStorage {
+        let t = t.cpu().read().unwrap();
+        let reduce_chunk = |c: &[f32]| match typ {
+            ReduceOpType::Sum => ArrayView1::from(c).sum(),
+            ReduceOpType::Max => c.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        };
+
+        let chunk = t.len().div_ceil(self.threads).max(MIN_CHUNK);
+        let partials: Vec<f32> = thread::scope(|s| {
+            let handles: Vec<_> = t
+                .chunks(chunk)
+                .map(|c| s.spawn(move || reduce_chunk(c)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        storage(vec![reduce_chunk(&partials)])
+    }
+
+    fn matmul(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        m: usize,
+        n: usize,
+        k: usize,
+        precision: Precision,
+    ) -> TensorStorage {
+        let lhs = lhs.cpu().read().unwrap();
+        let rhs = rhs.cpu().read().unwrap();
+        // Like on a GPU, f16 inputs are multiplied and accumulated in f32
+        let r = rounding(precision);
+        let (lhs, rhs): (Cow<[f32]>, Cow<[f32]>) = match precision {
+            Precision::F32 => (Cow::Borrowed(&lhs), Cow::Borrowed(&rhs)),
+            Precision::F16 => (
+                lhs.iter().map(|&x| r(x)).collect(),
+                rhs.iter().map(|&x| r(x)).collect(),
+            ),
+        };
+        let b = ArrayView2::from_shape((k, n), &rhs[..]).unwrap();
+        let mut out = vec![0.0; m * n];
+
+        // Every thread computes a block of rows of the output
+        self.par_fill(&mut out, n, |off, c| {
+            let (row, rows) = (off / n, c.len() / n);
+            let a = ArrayView2::from_shape((rows, k), &lhs[row * k..(row + rows) * k]).unwrap();
+            let mut c = ArrayViewMut2::from_shape((rows, n), c).unwrap();
+            general_mat_mul(1.0, &a, &b, 0.0, &mut c);
+            if precision == Precision::F16 {
+                c.mapv_inplace(r);
+            }
+        });
+
+        storage(out)
+    }
+
+    fn transpose(&self, t: &TensorStorage, m: usize, n: usize) -> TensorStorage {
+        let t = t.cpu().read().unwrap();
+        let transposed = ArrayView2::from_shape((m, n), &t[..])
+            .unwrap()
+            .reversed_axes();
+        let mut out = vec![0.0; m * n];
+
+        self.par_fill(&mut out, m, |off, c| {
+            let (row, rows) = (off / m, c.len() / m);
+            ArrayViewMut2::from_shape((rows, m), c)
+                .unwrap()
+                .assign(&transposed.slice(s![row..row + rows, ..]));
+        });
+
+        storage(out)
+    }
+
+    fn outer(&self, lhs: &TensorStorage, rhs: &TensorStorage, m: usize, n: usize) -> TensorStorage {
+        let lhs = lhs.cpu().read().unwrap();
+        let rhs = rhs.cpu().read().unwrap();
+        let rhs = ArrayView1::from(&rhs[..]);
+        let mut out = vec![0.0; m * n];
+
+        self.par_fill(&mut out, n, |off, c| {
+            let row = off / n;
+            let mut c = ArrayViewMut2::from_shape((c.len() / n, n), c).unwrap();
+            for (i, mut out_row) in c.rows_mut().into_iter().enumerate() {
+                out_row.assign(&rhs);
+                out_row *= lhs[row + i];
+            }
+        });
+
+        storage(out)
+    }
+
+    fn he_init(&self, numel: usize, fact: f32, seed: u32) -> TensorStorage {
+        let mut out = vec![0.0; numel];
+
+        self.par_fill(&mut out, 1, |off, c| {
+            for (i, o) in c.iter_mut().enumerate() {
+                *o = randn((off + i) as u32 ^ seed) * fact;
+            }
+        });
+
+        storage(out)
+    }
+
+    fn fused_ewize(
+        &self,
+        program: &FusedProgram,
+        inputs: &[&TensorStorage],
+        scalars: &[f32],
+    ) -> TensorStorage {
+        let inputs: Vec<_> = inputs.iter().map(|t| t.cpu().read().unwrap()).collect();
+        let mut out = vec![0.0; inputs[0].len()];
+
+        // Runs the program a whole chunk at a time
+        self.par_fill(&mut out, 1, |off, c| {
+            let mut stack: Vec<Vec<f32>> = vec![];
+            for instr in &program.instrs {
+                match instr {
+                    FusedInstr::Input(i) => stack.push(inputs[*i][off..off + c.len()].to_vec()),
+                    FusedInstr::Unop(typ) => {
+                        let f = unop_fn(typ);
+                        stack
+                            .last_mut()
+                            .unwrap()
+                            .iter_mut()
+                            .for_each(|x| *x = f(*x));
+                    }
+                    FusedInstr::Scalar(typ, i) => {
+                        let (f, s) = (scalar_fn(typ), scalars[*i]);
+                        stack
+                            .last_mut()
+                            .unwrap()
+                            .iter_mut()
+                            .for_each(|x| *x = f(*x, s));
+                    }
+                    FusedInstr::Binop(typ) => {
+                        let f = binop_fn(typ);
+                        let rhs = stack.pop().unwrap();
+                        let lhs = stack.last_mut().unwrap();
+                        lhs.iter_mut().zip(rhs).for_each(|(a, b)| *a = f(*a, b));
+                    }
+                }
+            }
+            c.copy_from_slice(&stack.pop().unwrap());
+        });
+
+        storage(out)
+    }
+
+    fn quantize_int8(&self, t: &TensorStorage, m: usize, k: usize, scale: f32) -> TensorStorage {
+        let t = t.cpu().read().unwrap();
+        let out = t[..m * k]
+            .chunks(k)
+            .flat_map(|row| pack_int8(row, scale))
+            .collect();
+
+        storage(out)
+    }
+
+    fn int8_matmul(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        scales: &TensorStorage,
+        m: usize,
+        n: usize,
+        k: usize,
+    ) -> TensorStorage {
+        let words = k.div_ceil(4);
+        let unpack = |t: &TensorStorage| -> Vec<[i8; 4]> {
+            t.cpu()
+                .read()
+                .unwrap()
+                .iter()
+                .map(|&w| unpack_int8(w))
+                .collect()
+        };
+        let (lhs, rhs) = (unpack(lhs), unpack(rhs));
+        let scales = scales.cpu().read().unwrap();
+        let mut out = vec![0.0; m * n];
+
+        // Like the kernel, every dot product of four is exact and summed in f32
+        self.par_fill(&mut out, n, |off, c| {
+            for (i, o) in c.iter_mut().enumerate() {
+                let (row, col) = ((off + i) / n, (off + i) % n);
+                let a = &lhs[row * words..(row + 1) * words];
+                let b = &rhs[col * words..(col + 1) * words];
+                let acc: f32 = a
+                    .iter()
+                    .zip(b)
+                    .map(|(a, b)| (0..4).map(|j| a[j] as i32 * b[j] as i32).sum::<i32>() as f32)
+                    .sum();
+                *o = acc * scales[col];
+            }
+        });
+
+        storage(out)
+    }
+
+    fn non_finite_flags(&self, ts: &[&TensorStorage]) -> u32 {
+        let mut flags = 0;
+        for t in ts {
+            for x in t.cpu().read().unwrap().iter() {
+                if x.is_nan() {
+                    flags |= NAN_FLAG;
+                } else if x.is_infinite() {
+                    flags |= INF_FLAG;
+                }
+            }
+        }
+        flags
+    }
+
+    // Ops run eagerly on the calling thread
+    fn synchronize(&self) {}
+
+    fn cleanup(&self) {}
+
+    fn stats(&self) -> RuntimeStats {
+        RuntimeStats {
+            storage_buffer_stats: BufferAllocStats {
+                buffers: vec![],
+                counters: AllocatorCounters::default(),
+            },
+            readback_buffer_stats: BufferAllocStats {
+                buffers: vec![],
+                counters: AllocatorCounters::default(),
+            },
+            metadata_arena_stats: MetadataArenaStats::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backend::BackendKind,
+        nn::{Adam, MLP, Model},
+        ops::TensorOpError,
+        runtime::{Device, detect_anomaly, test_adapter},
+        tensor::Tensor,
+    };
+
+    fn assert_close(cpu: &[f32], gpu: &[f32], tol: f32) {
+        assert_eq!(cpu.len(), gpu.len());
+        for (i, (c, g)) in cpu.iter().zip(gpu).enumerate() {
+            assert!(
+                c == g || (c.is_nan() && g.is_nan()) || (c - g).abs() <= tol * g.abs().max(1.0),
+                "element {}: cpu {} != gpu {}",
+                i,
+                c,
+                g
+            );
+        }
+    }
+
+    fn data(len: usize, seed: u32) -> Vec<f32> {
+        (0..len)
+            .map(|i| ((i as u32).wrapping_mul(2654435761) ^ seed) % 2001)
+            .map(|x| x as f32 / 1000.0 - 1.0)
+            .collect()
+    }
+
+    #[test]
+    fn ops_match_the_gpu_kernels() {
+        let cpu = Device::cpu(7);
+        let gpu = Device::new(test_adapter(), 7);
+        assert_eq!(cpu.backend(), BackendKind::Cpu);
+        assert_eq!(gpu.backend(), BackendKind::Wgpu);
+
+        // Big enough to be split between threads
+        let (m, k, n) = (300, 130, 170);
+        let a = data(m * k, 1);
+        let b = data(m * k, 2);
+        let w = data(k * n, 3);
+
+        let check = |f: &dyn Fn(&Device) -> Tensor, tol: f32| {
+            let (c, g) = (f(&cpu), f(&gpu));
+            assert_eq!(c.shape(), g.shape());
+            assert_close(&c.to_vec(), &g.to_vec(), tol);
+        };
+        let a_on = |d: &Device| Tensor::new_on(d, &[m, k], &a, false);
+        let b_on = |d: &Device| Tensor::new_on(d, &[m, k], &b, false);
+
+        check(&|d| a_on(d).add(&b_on(d)).unwrap(), 0.0);
+        check(&|d| a_on(d).sub(&b_on(d)).unwrap(), 0.0);
+        check(&|d| a_on(d).mul(&b_on(d)).unwrap(), 0.0);
+        check(&|d| a_on(d).div(&b_on(d)).unwrap(), 1e-6);
+        check(&|d| a_on(d).mul_s(-3.0).unwrap(), 0.0);
+        check(&|d| a_on(d).add_s(0.5).unwrap(), 0.0);
+        check(&|d| a_on(d).relu().unwrap(), 0.0);
+        check(&|d| a_on(d).relu().unwrap().sqrt().unwrap(), 1e-6);
+        check(&|d| a_on(d).sum().unwrap(), 1e-4);
+        check(&|d| a_on(d).max().unwrap(), 0.0);
+        check(&|d| a_on(d).transposed().unwrap(), 0.0);
+        check(
+            &|d| {
+                a_on(d)
+                    .matmul(&Tensor::new_on(d, &[k, n], &w, false))
+                    .unwrap()
+            },
+            1e-4,
+        );
+        check(
+            &|d| {
+                let x = Tensor::new_on(d, &[k], &a[..k], false);
+                x.matmul(&Tensor::new_on(d, &[k, n], &w, false)).unwrap()
+            },
+            1e-4,
+        );
+        check(
+            &|d| {
+                let x = Tensor::new_on(d, &[m], &a[..m], false);
+                x.outer(&Tensor::new_on(d, &[n], &b[..n], false)).unwrap()
+            },
+            0.0,
+        );
+        check(
+            &|d| {
+                let logits = Tensor::new_on(d, &[2, 3], &a[..6], false);
+                let targets = Tensor::new_on(d, &[2, 3], &[1.0, 0.0, 0.0, 0.0, 0.0, 1.0], false);
+                logits.cross_entropy_loss(&targets).unwrap()
+            },
+            1e-6,
+        );
+        check(
+            &|d| crate::ops::he_init(d, 11, 64, &[m, n], false).unwrap(),
+            1e-4,
+        );
+    }
+
+    #[test]
+    fn training_matches_the_gpu() {
+        let run = |device: &Device| {
+            let model = MLP::new_on(device, &[8, 16, 4], true);
+            let mut optim = Adam::new(&model, 0.01, 0.9, 0.999, 1e-8);
+
+            let x = Tensor::new_on(device, &[5, 8], &data(40, 4), false);
+            let mut targets = vec![0.0; 20];
+            for row in 0..5 {
+                targets[row * 4 + row % 4] = 1.0;
+            }
+            let targets = Tensor::new_on(device, &[5, 4], &targets, false);
+
+            let mut losses = vec![];
+            for _ in 0..3 {
+                optim.zero_grad();
+                let loss = model
+                    .forward(&x)
+                    .unwrap()
+                    .cross_entropy_loss(&targets)
+                    .unwrap();
+                loss.backward();
+                losses.push(loss.to_vec()[0]);
+                optim.step();
+            }
+
+            let params: Vec<Vec<f32>> = model.params().iter().map(|p| p.to_vec()).collect();
+            (losses, params)
+        };
+
+        let (cpu_losses, cpu_params) = run(&Device::cpu(3));
+        let (gpu_losses, gpu_params) = run(&Device::new(test_adapter(), 3));
+
+        assert_close(&cpu_losses, &gpu_losses, 1e-4);
+        assert!(cpu_losses[2] < cpu_losses[0]);
+        for (c, g) in cpu_params.iter().zip(&gpu_params) {
+            assert_close(c, g, 1e-3);
+        }
+    }
+
+    #[test]
+    fn cpu_device_supports_autograd_anomalies_and_transfers() {
+        let cpu = Device::cpu(0);
+        assert!(cpu.features().is_empty());
+
+        let x = Tensor::new_on(&cpu, &[3], &[1.0, 2.0, 3.0], true);
+        x.mul(&x).unwrap().sum().unwrap().backward();
+        assert_eq!(x.grad().unwrap().to_vec(), vec![2.0, 4.0, 6.0]);
+
+        {
+            let _anomaly = detect_anomaly();
+            let neg = Tensor::new_on(&cpu, &[1], &[-1.0], false);
+            assert!(matches!(neg.sqrt(), Err(TensorOpError::Anomaly(_))));
+        }
+
+        let gpu = Device::new(test_adapter(), 0);
+        let moved = x.to_device(&gpu);
+        assert_eq!(moved.device(), &gpu);
+        assert!(moved.requires_grad());
+        assert_eq!(moved.to_vec(), vec![1.0, 2.0, 3.0]);
+        assert!(matches!(x.add(&moved), Err(TensorOpError::DeviceMismatch)));
+    }
+}
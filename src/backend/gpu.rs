@@ -0,0 +1,1257 @@
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::Instant,
+};
+
+use wgpu::{BindingResource, util::DeviceExt};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    AsBindingResource,
+    amp::Precision,
+    backend::{Backend, BackendKind, TensorStorage},
+    buffer_alloc::{
+        BufferAllocatorRef, BufferLease, BufferWindow,
+        usage_marker::{Readback, Storage},
+    },
+    fusion::FusedProgram,
+    kernel_registry::{KernelEntry, KernelKey, KernelRegistry, ParamsMode},
+    metadata_arena::{MetadataArena, MetdataHandle},
+    ops::{BinopEwizeType, OpType, ReduceOpType, ScalarEwizeType, UnopEwizeType},
+    profiler::{self, GpuTime, GpuTimestamps},
+    readback::HostCopy,
+    runtime::{RuntimeConfig, RuntimeStats, WGPUContext},
+    tensor::DTYPE_SIZE,
+};
+
+#[derive(Debug)]
+pub(crate) struct GpuBackend {
+    pub(crate) ctx: WGPUContext,
+    pub(crate) storage_buffer_alloc: BufferAllocatorRef<Storage>,
+    pub(crate) readback_buffer_alloc: BufferAllocatorRef<Readback>,
+    pub(crate) metadata_arena: Mutex<MetadataArena>,
+    pub(crate) kernel_registry: Mutex<KernelRegistry>,
+    pub(crate) batch: Mutex<CommandBatch>,
+    max_batch_ops: usize,
+    max_batch_bytes: u64,
+    // The backend itself, handed to the profiler to read timestamps back later
+    this: Weak<GpuBackend>,
+    capture: Mutex<Option<Capture>>,
+    params_mode: ParamsMode,
+    // Intermediate of copies within a buffer, between slab blocks
+    scratch: Mutex<Option<wgpu::Buffer>>,
+}
+
+/// Commands recorded to be replayed later, with everything they bind. Buffers requested while
+/// capturing come from allocators of their own, so the recording can be replayed while the rest
+/// of the device keeps reusing its buffers.
+#[derive(Debug)]
+pub(crate) struct Capture {
+    alloc: BufferAllocatorRef<Storage>,
+    // Never reclaimed, holds the buffers written from the host so that replays find their data
+    pinned: BufferAllocatorRef<Storage>,
+    commands: Vec<CapturedCommand>,
+    // Whether the commands also run while they're recorded
+    execute: bool,
+    // Whether data was read back to the host, which replays can't redo
+    pub(crate) host_reads: bool,
+}
+
+// Kernel with everything it needs to be recorded into a compute pass
+#[derive(Debug)]
+struct Dispatch {
+    label: String,
+    kernel: Arc<KernelEntry>,
+    bg: wgpu::BindGroup,
+    immediates: Vec<u8>,
+    wgs: (u32, u32, u32),
+    // Size of the bound buffers
+    bytes: u64,
+}
+
+#[derive(Debug)]
+enum CapturedCommand {
+    Dispatch(Dispatch),
+    Copy {
+        src: wgpu::Buffer,
+        src_offset: u64,
+        dst: wgpu::Buffer,
+        dst_offset: u64,
+        size: u64,
+    },
+}
+
+impl Capture {
+    pub(crate) fn dispatches(&self) -> usize {
+        self.commands
+            .iter()
+            .filter(|c| matches!(c, CapturedCommand::Dispatch(_)))
+            .count()
+    }
+
+    /// Bytes of the buffers owned by the recording.
+    pub(crate) fn buffer_bytes(&self) -> u64 {
+        [&self.alloc, &self.pinned]
+            .iter()
+            .flat_map(|a| a.stats().buffers)
+            .map(|b| b.capacity)
+            .sum()
+    }
+}
+
+/// Commands recorded since the last submit.
+///
+/// Buffers only go back to the free pool in `reclaim` once the submission following their free
+/// finished, so a buffer used by a recorded command is never handed out again before the batch
+/// ran. That's why uploads with `queue.write_buffer` don't have to flush.
+#[derive(Debug, Default)]
+pub(crate) struct CommandBatch {
+    encoder: Option<wgpu::CommandEncoder>,
+    pub(crate) ops: usize,
+    // Sum of the sizes of the buffers bound by the recorded commands
+    pub(crate) bytes: u64,
+    last_submission: Option<wgpu::SubmissionIndex>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct ScalarMeta {
+    s: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct MatmulMeta {
+    m: u32,
+    n: u32,
+    k: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct MatrixMeta {
+    m: u32,
+    n: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct QuantizeMeta {
+    k: u32,
+    words: u32,
+    total: u32,
+    scale: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct HeMeta {
+    fact: f32,
+    seed: u32,
+    // Index of the first element of the window in the tensor
+    offset: u32,
+}
+
+impl GpuBackend {
+    // `weak` points to the backend itself, allocators use it to evict both pools when out of
+    // memory and the profiler to read timestamps
+    pub(crate) fn new(
+        ctx: WGPUContext,
+        config: &RuntimeConfig,
+        kernel_registry: KernelRegistry,
+        weak: &Weak<GpuBackend>,
+    ) -> Self {
+        Self {
+            ctx: ctx.clone(),
+            storage_buffer_alloc: BufferAllocatorRef::<Storage>::new(
+                ctx.clone(),
+                config.storage_cache_policy,
+                weak.clone(),
+            ),
+            readback_buffer_alloc: BufferAllocatorRef::<Readback>::new(
+                ctx.clone(),
+                config.readback_cache_policy,
+                weak.clone(),
+            ),
+            metadata_arena: Mutex::new(MetadataArena::new(ctx.clone(), config.metadata_page_size)),
+            kernel_registry: Mutex::new(kernel_registry),
+            batch: Mutex::new(CommandBatch::default()),
+            max_batch_ops: config.max_batch_ops,
+            max_batch_bytes: config.max_batch_bytes,
+            this: weak.clone(),
+            capture: Mutex::new(None),
+            params_mode: ParamsMode::of(&ctx.device),
+            scratch: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn hard_evict(&self, target: Option<u64>) {
+        // Out of memory, so waiting for the pending buffers is worth it
+        self.synchronize();
+        self.storage_buffer_alloc.reclaim();
+        self.readback_buffer_alloc.reclaim();
+        self.storage_buffer_alloc.hard_evict(target);
+        self.readback_buffer_alloc.hard_evict(target);
+    }
+
+    fn kernel(&self, key: &KernelKey) -> Arc<KernelEntry> {
+        self.kernel_registry.lock().unwrap().get(key)
+    }
+
+    pub(crate) fn request(&self, numel: usize) -> BufferLease<Storage> {
+        let size = (numel * DTYPE_SIZE) as u64;
+
+        match &*self.capture.lock().unwrap() {
+            // Buffers dropped by the captured ops can be reused for the next ones without
+            // waiting for the GPU, the recording runs in order
+            Some(capture) => {
+                capture.alloc.reclaim_all();
+                capture.alloc.request(size)
+            }
+            None => self.storage_buffer_alloc.request(size),
+        }
+    }
+
+    // Buffer about to be written from the host. While capturing it must not be reused:
+    // `queue.write_buffer` runs before the commands of the open batch that read the old data
+    fn request_written(&self, size: u64) -> BufferLease<Storage> {
+        match &*self.capture.lock().unwrap() {
+            Some(capture) => capture.pinned.request(size),
+            None => self.storage_buffer_alloc.request(size),
+        }
+    }
+
+    // Scalars of fused kernels, bound as a storage buffer
+    fn meta(&self, bytes: &[u8]) -> MetdataHandle {
+        if self.capture.lock().unwrap().is_some() {
+            // Dropping the lease keeps the buffer pending in the pinned allocator
+            let lease = self.request_written(bytes.len() as u64);
+            lease.set(bytes);
+            return MetdataHandle {
+                offset: lease.offset(),
+                size: lease.size(),
+                buf: lease.raw().clone(),
+            };
+        }
+
+        self.metadata_arena.lock().unwrap().allocate(bytes).unwrap()
+    }
+
+    // Parameters bound as a uniform buffer, when the device has no immediates
+    fn uniform(&self, bytes: &[u8]) -> MetdataHandle {
+        if self.capture.lock().unwrap().is_some() {
+            // Owned by the bind group of the recording
+            let buf = self
+                .ctx
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("captured params"),
+                    contents: bytes,
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
+            return MetdataHandle {
+                offset: 0,
+                size: bytes.len() as u64,
+                buf,
+            };
+        }
+
+        self.metadata_arena.lock().unwrap().allocate(bytes).unwrap()
+    }
+
+    fn note_host_read(&self) {
+        if let Some(capture) = &mut *self.capture.lock().unwrap() {
+            capture.host_reads = true;
+        }
+    }
+
+    /// Starts recording the commands of this device, which only run when `execute` is set.
+    /// Other threads must not use the device until `end_capture()`.
+    pub(crate) fn begin_capture(&self, execute: bool) {
+        let mut capture = self.capture.lock().unwrap();
+        assert!(capture.is_none(), "Already capturing");
+
+        let alloc = || {
+            BufferAllocatorRef::<Storage>::new(
+                self.ctx.clone(),
+                Default::default(),
+                self.this.clone(),
+            )
+        };
+        *capture = Some(Capture {
+            alloc: alloc(),
+            pinned: alloc(),
+            commands: vec![],
+            execute,
+            host_reads: false,
+        });
+    }
+
+    pub(crate) fn is_capturing(&self) -> bool {
+        self.capture.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn end_capture(&self) -> Capture {
+        self.capture.lock().unwrap().take().expect("Not capturing")
+    }
+
+    /// Records the captured commands into the open batch.
+    pub(crate) fn replay(&self, capture: &Capture) {
+        for command in &capture.commands {
+            match command {
+                CapturedCommand::Dispatch(dispatch) => self.dispatch_pass(dispatch, None),
+                CapturedCommand::Copy {
+                    src,
+                    src_offset,
+                    dst,
+                    dst_offset,
+                    size,
+                } => self.record(size * 2, |encoder| {
+                    encoder.copy_buffer_to_buffer(src, *src_offset, dst, *dst_offset, Some(*size));
+                }),
+            }
+        }
+    }
+
+    // Dispatches a kernel whose bindings are `entries` in order, passing it `params` as the
+    // `ParamsMode` of the device wants
+    fn run(
+        &self,
+        key: &KernelKey,
+        label: &str,
+        entries: &[&dyn AsBindingResource],
+        params: &[u8],
+        wgs: (u32, u32, u32),
+    ) {
+        let uniform;
+        let mut entries = entries.to_vec();
+        let immediates = match self.params_mode {
+            ParamsMode::Immediates => params,
+            ParamsMode::Uniform => {
+                if !params.is_empty() {
+                    uniform = self.uniform(params);
+                    entries.push(&uniform);
+                }
+                &[]
+            }
+        };
+
+        let bytes = entries
+            .iter()
+            .map(|e| match e.as_binding_resource() {
+                BindingResource::Buffer(b) => b.size.map_or(0, |s| s.get()),
+                _ => 0,
+            })
+            .sum();
+        let prepare = || {
+            let kernel = self.kernel(key);
+            Dispatch {
+                label: label.to_string(),
+                bg: self.create_bg(label, &entries, kernel.bind_group_layout()),
+                kernel,
+                immediates: immediates.to_vec(),
+                wgs,
+                bytes,
+            }
+        };
+
+        if let Some(capture) = &mut *self.capture.lock().unwrap() {
+            let dispatch = prepare();
+            if capture.execute {
+                self.dispatch_pass(&dispatch, None);
+            }
+            capture.commands.push(CapturedCommand::Dispatch(dispatch));
+            return;
+        }
+
+        if !profiler::is_profiling() {
+            self.dispatch_pass(&prepare(), None);
+            return;
+        }
+
+        let name = match key {
+            KernelKey::Op(op) => format!("{:?}", op),
+            KernelKey::Fused(program) => format!("Fused({})", program),
+            _ => format!("{:?}", key),
+        };
+
+        if self
+            .ctx
+            .device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+        {
+            let start = Instant::now();
+            let dispatch = prepare();
+            let timestamps = GpuTimestamps::new(self.this.clone(), &self.ctx.device);
+            self.dispatch_pass(&dispatch, Some(&timestamps));
+
+            let cpu = start.elapsed();
+            profiler::record(name, wgs, start, cpu, GpuTime::Timestamps(timestamps));
+        } else {
+            // Wait for the queued work so that only this dispatch is timed
+            self.synchronize();
+
+            let start = Instant::now();
+            self.dispatch_pass(&prepare(), None);
+            let cpu = start.elapsed();
+
+            let gpu_start = Instant::now();
+            self.synchronize();
+            let gpu = GpuTime::HostWait {
+                start: gpu_start,
+                duration: gpu_start.elapsed(),
+            };
+            profiler::record(name, wgs, start, cpu, gpu);
+        }
+    }
+
+    // Runs an elementwise kernel binding windows of the same elements of `tensors`, then `extra`,
+    // until it went over all of them
+    fn run_ewize(
+        &self,
+        key: &KernelKey,
+        label: &str,
+        tensors: &[&BufferLease<Storage>],
+        extra: &[&dyn AsBindingResource],
+        params: &[u8],
+    ) {
+        let numel = tensors[0].size() as usize / DTYPE_SIZE;
+
+        for range in self.row_windows(numel, &[1], usize::MAX) {
+            let windows: Vec<_> = tensors.iter().map(|t| window(t, &range, 1)).collect();
+            let mut entries: Vec<&dyn AsBindingResource> = windows.iter().map(|w| w as _).collect();
+            entries.extend_from_slice(extra);
+            self.run(key, label, &entries, params, ewize_wgs(range.len()));
+        }
+    }
+
+    // Splits the `rows` rows of matrices with rows of `widths` elements into the windows kernels
+    // bind one after the other, with at most `max_rows` rows each. Ops made sure up front that
+    // rows fit in a binding
+    fn row_windows(&self, rows: usize, widths: &[usize], max_rows: usize) -> Vec<Range<usize>> {
+        let mut per_window = self.window_rows(rows, widths);
+        if per_window > max_rows {
+            let step = self.row_step(widths);
+            per_window = max_rows / step * step;
+        }
+        if rows == 0 {
+            return vec![];
+        }
+        assert!(per_window > 0, "rows too large to be bound");
+
+        (0..rows)
+            .step_by(per_window)
+            .map(|start| start..rows.min(start + per_window))
+            .collect()
+    }
+
+    // Rows between two offsets aligned for storage bindings in all the matrices. Alignments are
+    // powers of two
+    fn row_step(&self, widths: &[usize]) -> usize {
+        let align = self.ctx.device.limits().min_storage_buffer_offset_alignment as usize;
+
+        widths
+            .iter()
+            .filter(|&&w| w > 0)
+            .map(|w| {
+                align
+                    >> (w * DTYPE_SIZE)
+                        .trailing_zeros()
+                        .min(align.trailing_zeros())
+            })
+            .max()
+            .unwrap_or(1)
+    }
+
+    // Reduces the `numel` elements bound by `input` to a single one
+    fn reduce_binding(
+        &self,
+        typ: &ReduceOpType,
+        input: &dyn AsBindingResource,
+        numel: usize,
+    ) -> BufferLease<Storage> {
+        let op = OpType::Reduce(typ.clone());
+        let key = KernelKey::Op(op.clone());
+
+        let mut output_size = numel.div_ceil(256).min(65535);
+        let mut inp_buf = self.request(output_size);
+
+        self.run(
+            &key,
+            op.as_ref(),
+            &[input, &inp_buf],
+            &[],
+            (output_size as u32, 1, 1),
+        );
+
+        while output_size > 1 {
+            output_size = output_size.div_ceil(256).min(65535);
+            let out_buf = self.request(output_size);
+
+            self.run(
+                &key,
+                op.as_ref(),
+                &[&inp_buf, &out_buf],
+                &[],
+                (output_size as u32, 1, 1),
+            );
+
+            inp_buf = out_buf;
+        }
+
+        inp_buf
+    }
+
+    // Copies `size` bytes between two buffers, recorded into the capture if there is one
+    fn copy_range(
+        &self,
+        src: &wgpu::Buffer,
+        src_offset: u64,
+        dst: &wgpu::Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        // wgpu rejects copies within a buffer, they go through a scratch buffer. Commands run in
+        // order, so every copy can reuse it
+        if src == dst {
+            let scratch = self.scratch(size);
+            self.copy_range(src, src_offset, &scratch, 0, size);
+            self.copy_range(&scratch, 0, dst, dst_offset, size);
+            return;
+        }
+
+        if let Some(capture) = &mut *self.capture.lock().unwrap() {
+            capture.commands.push(CapturedCommand::Copy {
+                src: src.clone(),
+                src_offset,
+                dst: dst.clone(),
+                dst_offset,
+                size,
+            });
+            if !capture.execute {
+                return;
+            }
+        }
+
+        self.record(size * 2, |encoder| {
+            encoder.copy_buffer_to_buffer(src, src_offset, dst, dst_offset, Some(size));
+        });
+    }
+
+    // Scratch buffer of at least `size` bytes, replaced by a larger one when needed. Recorded
+    // commands keep the old one alive
+    fn scratch(&self, size: u64) -> wgpu::Buffer {
+        let mut scratch = self.scratch.lock().unwrap();
+        match &*scratch {
+            Some(buf) if buf.size() >= size => buf.clone(),
+            _ => {
+                let buf = self.ctx.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("copy scratch"),
+                    size: size.next_power_of_two(),
+                    usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                *scratch = Some(buf.clone());
+                buf
+            }
+        }
+    }
+
+    // Records a command into the open batch, submitting it once it gets too big
+    fn record(&self, bytes: u64, f: impl FnOnce(&mut wgpu::CommandEncoder)) {
+        let mut batch = self.batch.lock().unwrap();
+
+        let encoder = batch.encoder.get_or_insert_with(|| {
+            self.ctx
+                .device
+                .create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor {
+                    label: Some("batch encoder"),
+                })
+        });
+        f(encoder);
+
+        batch.ops += 1;
+        batch.bytes += bytes;
+        if batch.ops >= self.max_batch_ops || batch.bytes >= self.max_batch_bytes {
+            self.submit(&mut batch);
+        }
+    }
+
+    // Returns the index of the latest submission, if there was one
+    fn submit(&self, batch: &mut CommandBatch) -> Option<wgpu::SubmissionIndex> {
+        if let Some(encoder) = batch.encoder.take() {
+            batch.last_submission = Some(self.ctx.submit(Some(encoder.finish())));
+        }
+        batch.ops = 0;
+        batch.bytes = 0;
+
+        batch.last_submission.clone()
+    }
+
+    /// Submits the recorded commands without waiting for them. Returns the index of the
+    /// submission holding them, or of the latest one when nothing was recorded.
+    pub(crate) fn flush(&self) -> Option<wgpu::SubmissionIndex> {
+        self.submit(&mut self.batch.lock().unwrap())
+    }
+
+    fn dispatch_pass(&self, dispatch: &Dispatch, timestamps: Option<&GpuTimestamps>) {
+        let wgs = dispatch.wgs;
+        self.record(dispatch.bytes, |encoder| {
+            let pass_label = format!("{} pass", dispatch.label);
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&pass_label),
+                timestamp_writes: timestamps.map(|ts| wgpu::ComputePassTimestampWrites {
+                    query_set: &ts.query_set,
+                    beginning_of_pass_write_index: Some(0),
+                    end_of_pass_write_index: Some(1),
+                }),
+            });
+
+            compute_pass.set_pipeline(dispatch.kernel.pipeline());
+            compute_pass.set_bind_group(0, &dispatch.bg, &[]);
+            if !dispatch.immediates.is_empty() {
+                compute_pass.set_immediates(0, &dispatch.immediates);
+            }
+            compute_pass.dispatch_workgroups(wgs.0, wgs.1, wgs.2);
+            drop(compute_pass);
+
+            if let Some(ts) = timestamps {
+                encoder.resolve_query_set(&ts.query_set, 0..2, &ts.resolve, 0);
+                encoder.copy_buffer_to_buffer(&ts.resolve, 0, &ts.readback, 0, None);
+            }
+        });
+    }
+
+    pub(crate) fn create_bg(
+        &self,
+        prefix: &str,
+        entries: &[&dyn AsBindingResource],
+        bgl: &wgpu::BindGroupLayout,
+    ) -> wgpu::BindGroup {
+        let label = format!("{} bg", prefix);
+
+        let bge: Vec<wgpu::BindGroupEntry<'_>> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: e.as_binding_resource(),
+            })
+            .collect();
+
+        self.ctx
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&label),
+                layout: bgl,
+                entries: &bge,
+            })
+    }
+}
+
+// Whether WGSL has `dot4I8Packed`. Backends without the hardware instruction get a polyfill from
+// the shader compiler, so this only depends on the wgpu build
+fn packed_dot4() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        wgpu::Instance::default()
+            .wgsl_language_features()
+            .contains(wgpu::WgslLanguageFeatures::Packed4x8IntegerDotProduct)
+    })
+}
+
+// Workgroups of 64 threads for an elementwise kernel over `numel` elements
+fn ewize_wgs(numel: usize) -> (u32, u32, u32) {
+    ((numel.div_ceil(64) as u32).min(65535), 1, 1)
+}
+
+// Rows `rows` of a matrix with rows of `width` elements stored in `buf`
+fn window<'a>(
+    buf: &'a BufferLease<Storage>,
+    rows: &Range<usize>,
+    width: usize,
+) -> BufferWindow<'a> {
+    let row = (width * DTYPE_SIZE) as u64;
+    buf.window(rows.start as u64 * row, rows.len() as u64 * row)
+}
+
+impl Backend for GpuBackend {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Wgpu
+    }
+
+    fn upload(&self, data: &[f32]) -> TensorStorage {
+        let buf = self.request_written((data.len() * DTYPE_SIZE) as u64);
+        buf.set(bytemuck::cast_slice(data));
+        TensorStorage::Gpu(buf)
+    }
+
+    fn download(&self, src: &TensorStorage) -> Vec<f32> {
+        self.note_host_read();
+        self.flush();
+        let staging = self.readback_buffer_alloc.request(src.size());
+        staging.download(src.gpu()).unwrap()
+    }
+
+    fn copy_to_host(&self, src: &TensorStorage) -> HostCopy {
+        self.note_host_read();
+        let staging = self.readback_buffer_alloc.request(src.size());
+        self.record(src.size() * 2, |encoder| {
+            let src = src.gpu();
+            encoder.copy_buffer_to_buffer(
+                src.raw(),
+                src.offset(),
+                staging.raw(),
+                0,
+                Some(src.size()),
+            );
+        });
+        // The staging buffer can only be mapped once the copy is submitted
+        let submission = self.flush().expect("The copy was just submitted");
+
+        HostCopy::mapping(self.ctx.clone(), staging, submission)
+    }
+
+    fn copy(&self, src: &TensorStorage, dst: &TensorStorage) {
+        let (src, dst) = (src.gpu(), dst.gpu());
+        self.copy_range(src.raw(), src.offset(), dst.raw(), dst.offset(), dst.size());
+    }
+
+    fn scalar_ewize(&self, typ: &ScalarEwizeType, t: &TensorStorage, s: f32) -> TensorStorage {
+        let op = OpType::ScalarEwize(typ.clone());
+        let out = self.request(t.numel());
+
+        let meta = ScalarMeta { s };
+
+        self.run_ewize(
+            &KernelKey::Op(op.clone()),
+            op.as_ref(),
+            &[t.gpu(), &out],
+            &[],
+            bytemuck::bytes_of(&meta),
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn unop_ewize(&self, typ: &UnopEwizeType, t: &TensorStorage) -> TensorStorage {
+        let op = OpType::UnopEwizeType(typ.clone());
+        let out = self.request(t.numel());
+
+        self.run_ewize(
+            &KernelKey::Op(op.clone()),
+            op.as_ref(),
+            &[t.gpu(), &out],
+            &[],
+            &[],
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn binop_ewize(
+        &self,
+        typ: &BinopEwizeType,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+    ) -> TensorStorage {
+        let op = OpType::BinopEwizeType(typ.clone());
+        let out = self.request(lhs.numel());
+
+        self.run_ewize(
+            &KernelKey::Op(op.clone()),
+            op.as_ref(),
+            &[lhs.gpu(), rhs.gpu(), &out],
+            &[],
+            &[],
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn reduce(&self, typ: &ReduceOpType, t: &TensorStorage) -> TensorStorage {
+        let windows = self.row_windows(t.numel(), &[1], usize::MAX);
+        if windows.len() <= 1 {
+            return TensorStorage::Gpu(self.reduce_binding(typ, t, t.numel()));
+        }
+
+        // Every window is reduced on its own, then their results
+        let partials = self.request(windows.len());
+        for (i, range) in windows.iter().enumerate() {
+            let partial = self.reduce_binding(typ, &window(t.gpu(), range, 1), range.len());
+            let offset = partials.offset() + (i * DTYPE_SIZE) as u64;
+            let size = DTYPE_SIZE as u64;
+            self.copy_range(
+                partial.raw(),
+                partial.offset(),
+                partials.raw(),
+                offset,
+                size,
+            );
+        }
+
+        TensorStorage::Gpu(self.reduce_binding(typ, &partials, windows.len()))
+    }
+
+    fn matmul(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        m: usize,
+        n: usize,
+        k: usize,
+        precision: Precision,
+    ) -> TensorStorage {
+        let op = OpType::Matmul;
+        let key = KernelKey::matmul(precision);
+        let out = self.request(m * n);
+
+        // Workgroups compute tiles of 32 rows, at most 65535 of them per dispatch
+        for rows in self.row_windows(m, &[k, n], 65535 * 32) {
+            let meta = MatmulMeta {
+                m: rows.len() as u32,
+                n: n as u32,
+                k: k as u32,
+            };
+
+            self.run(
+                &key,
+                op.as_ref(),
+                &[&window(lhs.gpu(), &rows, k), rhs, &window(&out, &rows, n)],
+                bytemuck::bytes_of(&meta),
+                ((n as u32).div_ceil(32), (rows.len() as u32).div_ceil(32), 1),
+            );
+        }
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn transpose(&self, t: &TensorStorage, m: usize, n: usize) -> TensorStorage {
+        let op = OpType::Transpose;
+        let out = self.request(t.numel());
+
+        let meta = MatrixMeta {
+            m: m as u32,
+            n: n as u32,
+        };
+
+        self.run(
+            &KernelKey::Op(op.clone()),
+            op.as_ref(),
+            &[t, &out],
+            bytemuck::bytes_of(&meta),
+            ewize_wgs(t.numel()),
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn outer(&self, lhs: &TensorStorage, rhs: &TensorStorage, m: usize, n: usize) -> TensorStorage {
+        let op = OpType::Outer;
+        let out = self.request(m * n);
+
+        // Workgroups compute tiles of 8 rows, at most 65535 of them per dispatch
+        for rows in self.row_windows(m, &[1, n], 65535 * 8) {
+            let meta = MatrixMeta {
+                m: rows.len() as u32,
+                n: n as u32,
+            };
+
+            self.run(
+                &KernelKey::Op(op.clone()),
+                op.as_ref(),
+                &[&window(lhs.gpu(), &rows, 1), rhs, &window(&out, &rows, n)],
+                bytemuck::bytes_of(&meta),
+                ((n as u32).div_ceil(8), (rows.len() as u32).div_ceil(8), 1),
+            );
+        }
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn he_init(&self, numel: usize, fact: f32, seed: u32) -> TensorStorage {
+        let out = self.request(numel);
+
+        for range in self.row_windows(numel, &[1], usize::MAX) {
+            let meta = HeMeta {
+                fact,
+                seed,
+                offset: range.start as u32,
+            };
+
+            self.run(
+                &KernelKey::HeInit,
+                "he init",
+                &[&window(&out, &range, 1)],
+                bytemuck::bytes_of(&meta),
+                ewize_wgs(range.len()),
+            );
+        }
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn fused_ewize(
+        &self,
+        program: &FusedProgram,
+        inputs: &[&TensorStorage],
+        scalars: &[f32],
+    ) -> TensorStorage {
+        let numel = inputs[0].numel();
+        let out = self.request(numel);
+
+        // Storage bindings can't be empty
+        let scalars = if scalars.is_empty() { &[0.0] } else { scalars };
+        let meta = self.meta(bytemuck::cast_slice(scalars));
+
+        let mut tensors: Vec<_> = inputs.iter().map(|t| t.gpu()).collect();
+        tensors.push(&out);
+        self.run_ewize(
+            &KernelKey::Fused(program.clone()),
+            OpType::FusedEwize.as_ref(),
+            &tensors,
+            &[&meta],
+            &[],
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    // Leaves room for the output and scalars bindings
+    fn max_fused_inputs(&self) -> usize {
+        self.ctx
+            .device
+            .limits()
+            .max_storage_buffers_per_shader_stage as usize
+            - 2
+    }
+
+    fn quantize_int8(&self, t: &TensorStorage, m: usize, k: usize, scale: f32) -> TensorStorage {
+        let words = k.div_ceil(4);
+        let out = self.request(m * words);
+
+        let meta = QuantizeMeta {
+            k: k as u32,
+            words: words as u32,
+            total: (m * words) as u32,
+            scale,
+        };
+
+        self.run(
+            &KernelKey::QuantizeInt8,
+            "quantize int8",
+            &[t, &out],
+            bytemuck::bytes_of(&meta),
+            ewize_wgs(m * words),
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn int8_matmul(
+        &self,
+        lhs: &TensorStorage,
+        rhs: &TensorStorage,
+        scales: &TensorStorage,
+        m: usize,
+        n: usize,
+        k: usize,
+    ) -> TensorStorage {
+        let op = OpType::Int8Matmul;
+        let out = self.request(m * n);
+        let (m, n) = (m as u32, n as u32);
+
+        // The kernel walks K in packed words
+        let meta = MatmulMeta {
+            m,
+            n,
+            k: k.div_ceil(4) as u32,
+        };
+
+        self.run(
+            &KernelKey::Int8Matmul {
+                packed_dot4: packed_dot4(),
+            },
+            op.as_ref(),
+            &[lhs, rhs, scales, &out],
+            bytemuck::bytes_of(&meta),
+            (n.div_ceil(8), m.div_ceil(8), 1),
+        );
+
+        TensorStorage::Gpu(out)
+    }
+
+    fn non_finite_flags(&self, ts: &[&TensorStorage]) -> u32 {
+        let flags = self.request_written(DTYPE_SIZE as u64);
+        flags.set(bytemuck::bytes_of(&0u32));
+
+        // Every check ORs its bits into the same flags
+        for t in ts {
+            self.run_ewize(
+                &KernelKey::CheckFinite,
+                "check finite",
+                &[t.gpu()],
+                &[&flags],
+                &[],
+            );
+        }
+
+        self.note_host_read();
+        self.flush();
+        let staging = self.readback_buffer_alloc.request(DTYPE_SIZE as u64);
+        staging.download(&flags).unwrap()[0].to_bits()
+    }
+
+    fn synchronize(&self) {
+        self.flush();
+        let _ = self.ctx.device.poll(wgpu::PollType::wait_indefinitely());
+    }
+
+    fn cleanup(&self) {
+        self.flush();
+        self.storage_buffer_alloc.reclaim();
+        self.readback_buffer_alloc.reclaim();
+        self.storage_buffer_alloc.try_evict();
+        self.readback_buffer_alloc.try_evict();
+        self.metadata_arena.lock().unwrap().reset();
+    }
+
+    fn stats(&self) -> RuntimeStats {
+        RuntimeStats {
+            storage_buffer_stats: self.storage_buffer_alloc.stats(),
+            readback_buffer_stats: self.readback_buffer_alloc.stats(),
+            metadata_arena_stats: self.metadata_arena.lock().unwrap().stats(),
+        }
+    }
+
+    fn max_buffer_bytes(&self) -> u64 {
+        self.ctx.device.limits().max_buffer_size
+    }
+
+    fn max_binding_bytes(&self) -> u64 {
+        self.ctx.device.limits().max_storage_buffer_binding_size
+    }
+
+    fn window_rows(&self, rows: usize, widths: &[usize]) -> usize {
+        let max = self.max_binding_bytes() as usize;
+        let widest = widths.iter().max().map_or(0, |w| w * DTYPE_SIZE);
+        if widest == 0 || rows * widest <= max {
+            return rows;
+        }
+
+        // Windows start at aligned offsets, they hold a multiple of the step
+        let step = self.row_step(widths);
+        max / widest / step * step
+    }
+
+    fn as_gpu(&self) -> Option<&GpuBackend> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        capture::capture,
+        kernel_registry::ParamsMode,
+        nn::MLP,
+        ops::{self, TensorOpError},
+        runtime::{Device, RuntimeConfig, test_adapter},
+        tensor::Tensor,
+    };
+
+    fn pending_ops(device: &Device) -> usize {
+        device
+            .rt()
+            .backend
+            .as_gpu()
+            .unwrap()
+            .batch
+            .lock()
+            .unwrap()
+            .ops
+    }
+
+    #[test]
+    fn ops_are_batched_until_readback() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+
+        // Deferred, then recorded as one fused kernel before the copy
+        let y = x.mul_s(2.0).unwrap().add_s(1.0).unwrap().relu().unwrap();
+        assert_eq!(pending_ops(&device), 0);
+
+        let mut z = Tensor::new_on(&device, &[3], &[0.0; 3], false);
+        z.assign(&y);
+        assert_eq!(pending_ops(&device), 2);
+
+        assert_eq!(z.to_vec(), vec![3.0, 5.0, 7.0]);
+        assert_eq!(pending_ops(&device), 0);
+
+        let _ = x.add(&x).unwrap();
+        device.synchronize();
+        assert_eq!(pending_ops(&device), 0);
+    }
+
+    #[test]
+    fn batches_are_submitted_at_the_thresholds() {
+        let config = RuntimeConfig::new().max_batch_ops(3);
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+
+        let mut x = Tensor::new_on(&device, &[2], &[1.0, 2.0], false);
+        // `buf()` runs each deferred op right away
+        for _ in 0..4 {
+            x = x.mul_s(2.0).unwrap();
+            x.buf();
+        }
+        assert_eq!(pending_ops(&device), 1);
+        assert_eq!(x.to_vec(), vec![16.0, 32.0]);
+
+        // Every op binds two 4KB buffers
+        let config = RuntimeConfig::new().max_batch_bytes(16 * 1024);
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+
+        let x = Tensor::new_on(&device, &[1024], &[1.0; 1024], false);
+        let y = x.mul_s(3.0).unwrap();
+        y.buf();
+        assert_eq!(pending_ops(&device), 1);
+        let z = y.add_s(1.0).unwrap();
+        z.buf();
+        assert_eq!(pending_ops(&device), 0);
+        assert_eq!(z.to_vec(), vec![4.0; 1024]);
+    }
+
+    #[test]
+    fn params_are_passed_as_immediates_or_uniforms() {
+        let uniform_limits = wgpu::Limits {
+            max_immediate_size: 0,
+            ..test_adapter().limits()
+        };
+        let devices = [
+            Device::new(test_adapter(), 5),
+            Device::with_config(
+                test_adapter(),
+                &RuntimeConfig::new().seed(5).limits(uniform_limits),
+            )
+            .unwrap(),
+        ];
+        let gpu = |d: &Device| d.rt().backend.as_gpu().unwrap().params_mode;
+        if test_adapter()
+            .features()
+            .contains(wgpu::Features::IMMEDIATES)
+        {
+            assert_eq!(gpu(&devices[0]), ParamsMode::Immediates);
+        }
+        assert_eq!(gpu(&devices[1]), ParamsMode::Uniform);
+
+        let run = |device: &Device| {
+            let a = Tensor::new_on(device, &[2, 3], &[1.0, -2.0, 3.0, 0.5, 4.0, -1.0], false);
+            let w = ops::he_init(device, 9, 3, &[3, 2], false).unwrap();
+            let v = Tensor::new_on(device, &[2], &[2.0, -3.0], false);
+            let out = a
+                .transposed()
+                .unwrap()
+                .matmul(&v)
+                .unwrap()
+                .mul_s(0.5)
+                .unwrap();
+            let mut res = a.matmul(&w).unwrap().to_vec();
+            res.extend(out.outer(&v).unwrap().to_vec());
+            res
+        };
+        let expected = run(&Device::cpu(5));
+        for device in &devices {
+            for (a, e) in run(device).iter().zip(&expected) {
+                assert!((a - e).abs() < 1e-5, "{a} != {e}");
+            }
+
+            // Replays keep the parameters recorded at capture
+            let x = Tensor::new_on(device, &[2], &[1.0, 2.0], false);
+            let (step, y) = capture(std::slice::from_ref(&x), |inputs| {
+                inputs[0].add_s(3.0).unwrap()
+            })
+            .unwrap();
+            step.replay(&[Tensor::new_on(device, &[2], &[-1.0, 0.5], false)])
+                .unwrap();
+            assert_eq!(y.to_vec(), vec![2.0, 3.5]);
+        }
+    }
+
+    #[test]
+    fn tensors_larger_than_a_binding_run_in_windows() {
+        // 1024 elements per binding, 16K per buffer
+        let limits = wgpu::Limits {
+            max_storage_buffer_binding_size: 4096,
+            max_buffer_size: 64 * 1024,
+            ..test_adapter().limits()
+        };
+        let config = RuntimeConfig::new()
+            .seed(3)
+            .limits(limits)
+            .metadata_page_size(4096);
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+
+        let run = |device: &Device| {
+            let data: Vec<f32> = (0..3000).map(|i| ((i * 7) % 23) as f32 - 11.0).collect();
+            let x = Tensor::new_on(device, &[3000], &data, false);
+            let a = Tensor::new_on(device, &[600, 5], &data[..3000], false);
+            let b = Tensor::new_on(device, &[5, 6], &data[..30], false);
+            let v = Tensor::new_on(device, &[5], &data[..5], false);
+
+            let y = x.mul_s(0.5).unwrap().add(&x.relu().unwrap()).unwrap();
+            let fused = (x.fuse().relu() * &y).add_s(1.0).eval().unwrap();
+            let mut res = fused.to_vec();
+            res.extend(y.sum().unwrap().to_vec());
+            res.extend(x.max().unwrap().to_vec());
+            res.extend(a.matmul(&b).unwrap().to_vec());
+            res.extend(x.outer(&v).unwrap().to_vec());
+            res.extend(ops::he_init(device, 1, 4, &[3000], false).unwrap().to_vec());
+            res
+        };
+        let expected = run(&Device::cpu(3));
+        let actual = run(&device);
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!((a - e).abs() < 1e-3, "{a} != {e}");
+        }
+
+        // Reductions copy the result of every window, replays too
+        let x = Tensor::new_on(&device, &[3000], &[1.0; 3000], false);
+        let (step, y) =
+            capture(std::slice::from_ref(&x), |inputs| inputs[0].sum().unwrap()).unwrap();
+        // Two passes for each of the three windows, one over their results
+        assert_eq!(step.dispatches(), 7);
+        step.replay(&[Tensor::new_on(&device, &[3000], &[2.0; 3000], false)])
+            .unwrap();
+        assert_eq!(y.to_vec(), vec![6000.0]);
+
+        // Tensors bound at once and outputs past the max buffer size are rejected up front
+        let too_large = |res: Result<Tensor, TensorOpError>| match res {
+            Err(TensorOpError::TooLarge { bytes, max }) => (bytes, max),
+            res => panic!("expected TooLarge, got {res:?}"),
+        };
+        let rhs = Tensor::new_on(&device, &[40, 40], &[1.0; 1600], false);
+        let lhs = Tensor::new_on(&device, &[2, 40], &[1.0; 80], false);
+        assert_eq!(too_large(lhs.matmul(&rhs)), (6400, 4096));
+        assert_eq!(too_large(rhs.transposed()), (6400, 4096));
+        let x = Tensor::new_on(&device, &[3000], &[1.0; 3000], false);
+        assert_eq!(too_large(x.outer(&x)), (36_000_000, 64 * 1024));
+
+        // So are tensors and layers created past it
+        let data = vec![0.0; 20_000];
+        let res = Tensor::try_new_on(&device, &[20_000], &data, false);
+        assert_eq!(too_large(res), (80_000, 64 * 1024));
+        assert_eq!(
+            too_large(ops::he_init(&device, 0, 200, &[200, 100], true)),
+            (80_000, 64 * 1024)
+        );
+        assert!(matches!(
+            MLP::try_new_on(&device, &[10, 200, 100], true),
+            Err(TensorOpError::TooLarge { .. })
+        ));
+        assert!(MLP::try_new_on(&device, &[10, 20, 10], true).is_ok());
+    }
+}
@@ -1,8 +1,9 @@
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     marker::PhantomData,
     num::NonZeroU64,
-    sync::{Arc, Mutex, mpsc::channel},
+    sync::{Arc, Mutex, Weak, mpsc::channel},
+    time::Instant,
 };
 
 use serde::Serialize;
@@ -10,9 +11,8 @@ use slotmap::{SlotMap, new_key_type};
 use wgpu::{BufferDescriptor, BufferUsages};
 
 use crate::{
-    AsBindingResource,
-    buffer_alloc::usage_marker::Storage,
-    runtime::{WGPUContext, rt},
+    AsBindingResource, backend::GpuBackend, buffer_alloc::usage_marker::Storage,
+    runtime::WGPUContext,
 };
 
 new_key_type! {
@@ -38,30 +38,230 @@ pub enum Usage {
     Readback,
 }
 
+/// How an allocator picks the buffer serving a request, trading the bytes wasted by oversized
+/// buffers against the number of buffers it creates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AllocationStrategy {
+    /// Reuses the smallest free buffer that is larger than the request by less than
+    /// `max_waste_ratio` of it and at most `max_waste_bytes`, and allocates the exact size
+    /// otherwise.
+    BestFit {
+        max_waste_ratio: f64,
+        max_waste_bytes: u64,
+    },
+    /// Rounds sizes up to powers of two, any free buffer of the size class serves a request.
+    /// Fewer distinct sizes get reused more often, at the cost of up to half of each buffer.
+    PowerOfTwo,
+    /// Sub-allocates storage requests of at most `max_block_size` bytes out of buffers of
+    /// `slab_size` bytes, in power-of-two blocks aligned for bindings. Larger requests, and
+    /// readback buffers, use the default `BestFit`.
+    Slab { slab_size: u64, max_block_size: u64 },
+}
+
+impl Default for AllocationStrategy {
+    fn default() -> Self {
+        AllocationStrategy::BestFit {
+            max_waste_ratio: 0.5,
+            max_waste_bytes: 256 * 1024,
+        }
+    }
+}
+
+impl AllocationStrategy {
+    // Whether a free buffer of `capacity` bytes serves a request of `size` bytes
+    fn reuses(&self, capacity: u64, size: u64) -> bool {
+        match self {
+            AllocationStrategy::BestFit {
+                max_waste_ratio,
+                max_waste_bytes,
+            } => {
+                let waste = capacity.saturating_sub(size);
+                (waste as f64) < size as f64 * max_waste_ratio && waste <= *max_waste_bytes
+            }
+            AllocationStrategy::PowerOfTwo => capacity == size.next_power_of_two(),
+            AllocationStrategy::Slab { .. } => AllocationStrategy::default().reuses(capacity, size),
+        }
+    }
+
+    // Capacity of a new buffer for a request of `size` bytes
+    fn capacity(&self, size: u64) -> u64 {
+        match self {
+            AllocationStrategy::PowerOfTwo => size.next_power_of_two(),
+            _ => size,
+        }
+    }
+}
+
+/// Controls how many freed buffers an allocator keeps around for reuse, and how it allocates.
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct MemoryCachePolicy {
-    pub(crate) cache_to_used_proportion: f64,
-    pub(crate) soft_cache_limit: u64,
-    pub(crate) hard_cache_limit: u64,
-    pub(crate) max_ttl: usize,
-    pub(crate) eviction_debounce: usize,
+pub struct CachePolicy {
+    /// Cached bytes allowed per byte in use, clamped between the soft and hard limits.
+    pub cache_to_used_proportion: f64,
+    pub soft_cache_limit: u64,
+    pub hard_cache_limit: u64,
+    /// Number of allocator ticks a free buffer survives without being reused.
+    pub max_ttl: usize,
+    /// Minimal number of ticks between two evictions.
+    pub eviction_debounce: usize,
+    pub strategy: AllocationStrategy,
 }
 
-impl Default for MemoryCachePolicy {
+impl Default for CachePolicy {
     fn default() -> Self {
-        MemoryCachePolicy {
+        CachePolicy {
             cache_to_used_proportion: 0.5,
             soft_cache_limit: 1024 * 1024 * 1024,
             hard_cache_limit: 1024 * 1024 * 1024 * 2,
             max_ttl: 128,
             eviction_debounce: 128,
+            strategy: AllocationStrategy::default(),
         }
     }
 }
 
+impl CachePolicy {
+    /// Checks that the settings make sense, returning what's wrong otherwise.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.cache_to_used_proportion.is_finite() || self.cache_to_used_proportion < 0.0 {
+            return Err("cache_to_used_proportion must be a non-negative number");
+        }
+        if self.soft_cache_limit > self.hard_cache_limit {
+            return Err("soft_cache_limit must not exceed hard_cache_limit");
+        }
+
+        match self.strategy {
+            AllocationStrategy::BestFit {
+                max_waste_ratio, ..
+            } if !max_waste_ratio.is_finite() || max_waste_ratio < 0.0 => {
+                Err("max_waste_ratio must be a non-negative number")
+            }
+            AllocationStrategy::Slab {
+                slab_size,
+                max_block_size,
+            } if max_block_size == 0 || max_block_size > slab_size => {
+                Err("max_block_size must be positive and fit in a slab")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Running totals of an allocator since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct AllocatorCounters {
+    /// Bytes of the buffers and slab blocks currently leased.
+    pub in_use_bytes: u64,
+    pub peak_in_use_bytes: u64,
+    /// Requests served by a free buffer or slab block.
+    pub pool_hits: u64,
+    /// Requests that created a new buffer or slab.
+    pub new_allocations: u64,
+    pub requested_bytes: u64,
+    /// Bytes handed out beyond the requested sizes, by reusing larger buffers or rounding sizes
+    /// up.
+    pub wasted_bytes: u64,
+    pub ttl_evictions: u64,
+    pub cache_limit_evictions: u64,
+    pub out_of_memory_evictions: u64,
+    pub evicted_bytes: u64,
+}
+
+/// Why a free buffer was released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum EvictionCause {
+    /// Not reused for `max_ttl` ticks.
+    Ttl,
+    /// The cache outgrew the limit of its policy.
+    CacheLimit,
+    /// Released to retry an allocation that ran out of device memory.
+    OutOfMemory,
+}
+
+/// What happened in an `AllocatorEvent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind")]
+pub enum AllocatorEventKind {
+    /// Request served by a new buffer, or a block of a new slab.
+    Allocate {
+        size: u64,
+        capacity: u64,
+    },
+    /// Request served by a free buffer or slab block.
+    Reuse {
+        size: u64,
+        capacity: u64,
+    },
+    /// Lease dropped, its buffer or block waits for the GPU before being reused.
+    Free {
+        capacity: u64,
+    },
+    /// Pending buffers and blocks moved to the free pool.
+    Reclaim {
+        bytes: u64,
+    },
+    Evict {
+        capacity: u64,
+        cause: EvictionCause,
+    },
+    /// End of a step, see `Device::mark_step`. Steps are numbered from 0 in each log.
+    Step {
+        step: usize,
+    },
+}
+
+/// Entry of the event log of an allocator, see `Device::record_allocator_events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AllocatorEvent {
+    /// Microseconds since the recording started.
+    pub time_us: u64,
+    /// Allocator tick, incremented by every request.
+    pub clock: usize,
+    #[serde(flatten)]
+    pub kind: AllocatorEventKind,
+    /// Bytes of the allocator in each state after the event.
+    pub in_use_bytes: u64,
+    pub pending_bytes: u64,
+    pub free_bytes: u64,
+}
+
+/// Events recorded by the storage and readback allocators of a device.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AllocatorEvents {
+    pub storage: Vec<AllocatorEvent>,
+    pub readback: Vec<AllocatorEvent>,
+}
+
+impl AllocatorEvents {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Events serialize to JSON")
+    }
+}
+
+#[derive(Debug)]
+struct EventLog {
+    start: Instant,
+    events: Vec<AllocatorEvent>,
+    steps: usize,
+}
+
+// Buffer carved into blocks of a single size
+#[derive(Debug)]
+struct Slab {
+    block: u64,
+    // Offsets of the blocks that can be handed out
+    free: Vec<u64>,
+    blocks: usize,
+}
+
+impl Slab {
+    fn is_empty(&self) -> bool {
+        self.free.len() == self.blocks
+    }
+}
+
 #[derive(Debug)]
 struct BufferAllocator {
-    mcp: MemoryCachePolicy,
+    mcp: CachePolicy,
 
     ctx: WGPUContext,
 
@@ -70,16 +270,17 @@ struct BufferAllocator {
 
     store: SlotMap<BufferId, BufferEntry>,
     free_pool: BTreeMap<u64, Vec<BufferId>>,
-    pending: HashSet<BufferId>,
+    // Freed buffers and blocks, with the submission that has to finish before they're reused
+    pending: HashMap<BufferId, u64>,
+    // Buffers sub-allocated by the slab strategy, never in the free pool themselves
+    slabs: HashMap<BufferId, Slab>,
+    pending_blocks: HashMap<(BufferId, u64), u64>,
 
     clock: usize,
     last_eviction: usize,
-}
 
-const MAX_ABSOLUTE_LIMIT: u64 = 256 * 1024;
-
-fn validate_size(cs: u64, rs: u64) -> bool {
-    cs.saturating_sub(rs) < (rs / 2) && cs.saturating_sub(rs) <= MAX_ABSOLUTE_LIMIT
+    counters: AllocatorCounters,
+    events: Option<EventLog>,
 }
 
 #[derive(Debug)]
@@ -99,22 +300,22 @@ fn try_create_buffer(
     let err = pollster::block_on(scope.pop());
 
     match err {
-        Some(wgpu::Error::OutOfMemory { source: _ }) => {
-            return Err(AllocatorError::OutOfMemory);
-        }
+        Some(wgpu::Error::OutOfMemory { source: _ }) => Err(AllocatorError::OutOfMemory),
         Some(_) => {
             panic!("Something unexpected happened when creating a buffer")
         }
-        None => return Ok(buffer),
+        None => Ok(buffer),
     }
 }
 
 impl BufferAllocator {
-    fn new(ctx: WGPUContext, usage: Usage, mcp: MemoryCachePolicy) -> Self {
+    fn new(ctx: WGPUContext, usage: Usage, mcp: CachePolicy) -> Self {
         Self {
             store: SlotMap::with_key(),
             free_pool: BTreeMap::new(),
-            pending: HashSet::new(),
+            pending: HashMap::new(),
+            slabs: HashMap::new(),
+            pending_blocks: HashMap::new(),
             ctx,
             debug_label: BufferDebugLabel {
                 prefix: format!("{:?}", usage),
@@ -123,6 +324,8 @@ impl BufferAllocator {
             usage,
             clock: 0,
             last_eviction: 0,
+            counters: AllocatorCounters::default(),
+            events: None,
             mcp,
         }
     }
@@ -131,10 +334,13 @@ impl BufferAllocator {
         self.clock += 1;
 
         let mut delete_entry = None;
+        let capacity = self.new_capacity(size);
+        let strategy = self.mcp.strategy;
 
         let res_id =
             if let Some((candidate_size, entries)) = self.free_pool.range_mut(size..).next() {
-                if validate_size(*candidate_size, size) {
+                // Buffers of the exact capacity are always reused
+                if *candidate_size == capacity || strategy.reuses(*candidate_size, size) {
                     let candidate = entries
                         .pop()
                         .expect("If the element in the map exists then it must be a non empty vec");
@@ -144,19 +350,155 @@ impl BufferAllocator {
                     }
 
                     self.store[candidate].timestamp = self.clock;
-                    Ok(candidate)
+                    Ok((candidate, true))
                 } else {
-                    self.allocate_new_buffer(size)
+                    self.allocate_new_buffer(capacity).map(|id| (id, false))
                 }
             } else {
-                self.allocate_new_buffer(size)
+                self.allocate_new_buffer(capacity).map(|id| (id, false))
             };
 
         if let Some(k) = delete_entry {
             self.free_pool.remove(&k);
         }
 
-        res_id
+        let (id, reused) = res_id?;
+        self.hand_out(size, self.store[id].capacity, reused);
+
+        Ok(id)
+    }
+
+    // Accounts a request of `size` bytes served by `capacity` bytes
+    fn hand_out(&mut self, size: u64, capacity: u64, reused: bool) {
+        let counters = &mut self.counters;
+        counters.in_use_bytes += capacity;
+        counters.peak_in_use_bytes = counters.peak_in_use_bytes.max(counters.in_use_bytes);
+        counters.requested_bytes += size;
+        counters.wasted_bytes += capacity - size;
+
+        if reused {
+            counters.pool_hits += 1;
+            self.record(AllocatorEventKind::Reuse { size, capacity });
+        } else {
+            counters.new_allocations += 1;
+            self.record(AllocatorEventKind::Allocate { size, capacity });
+        }
+    }
+
+    fn record(&mut self, kind: AllocatorEventKind) {
+        if self.events.is_none() {
+            return;
+        }
+
+        let (pending_bytes, free_bytes) = (self.pending_size(), self.free_size());
+        let log = self.events.as_mut().unwrap();
+        log.events.push(AllocatorEvent {
+            time_us: log.start.elapsed().as_micros() as u64,
+            clock: self.clock,
+            kind,
+            in_use_bytes: self.counters.in_use_bytes,
+            pending_bytes,
+            free_bytes,
+        });
+    }
+
+    // Starts a new event log when given its start time, or stops recording
+    fn record_events(&mut self, start: Option<Instant>) {
+        self.events = start.map(|start| EventLog {
+            start,
+            events: vec![],
+            steps: 0,
+        });
+    }
+
+    fn mark_step(&mut self) {
+        if let Some(log) = &mut self.events {
+            let step = log.steps;
+            log.steps += 1;
+            self.record(AllocatorEventKind::Step { step });
+        }
+    }
+
+    // Capacity of the buffer allocated for a request of `size` bytes
+    fn new_capacity(&self, size: u64) -> u64 {
+        let max = self.ctx.device.limits().max_buffer_size;
+        self.mcp.strategy.capacity(size).min(max.max(size))
+    }
+
+    // Size of the slab block serving a request of `size` bytes, if it's sub-allocated
+    fn block_size(&self, size: u64) -> Option<u64> {
+        match (&self.usage, self.mcp.strategy) {
+            (Usage::Storage, AllocationStrategy::Slab { max_block_size, .. })
+                if size <= max_block_size =>
+            {
+                // Blocks are bound at their offset
+                let align = self.ctx.device.limits().min_storage_buffer_offset_alignment as u64;
+                Some(size.next_power_of_two().max(align))
+            }
+            _ => None,
+        }
+    }
+
+    // Hands out a free block of `block` bytes, carving a new slab if none is left. Returns the
+    // slab and the offset of the block
+    fn request_block(&mut self, size: u64, block: u64) -> Result<(BufferId, u64), AllocatorError> {
+        self.clock += 1;
+
+        let slab = self
+            .slabs
+            .iter()
+            .find(|(_, slab)| slab.block == block && !slab.free.is_empty())
+            .map(|(id, _)| *id);
+        let reused = slab.is_some();
+        let slab = match slab {
+            Some(id) => id,
+            None => {
+                let AllocationStrategy::Slab { slab_size, .. } = self.mcp.strategy else {
+                    unreachable!("blocks are only requested by the slab strategy");
+                };
+                let blocks = (slab_size / block).max(1) as usize;
+                let id = self.allocate_new_buffer(block * blocks as u64)?;
+                self.slabs.insert(
+                    id,
+                    Slab {
+                        block,
+                        // Handed out from the front
+                        free: (0..blocks as u64).rev().map(|i| i * block).collect(),
+                        blocks,
+                    },
+                );
+                id
+            }
+        };
+
+        self.store[slab].timestamp = self.clock;
+        let offset = self.slabs.get_mut(&slab).unwrap().free.pop().unwrap();
+        self.hand_out(size, block, reused);
+
+        Ok((slab, offset))
+    }
+
+    fn free_block(&mut self, slab: BufferId, offset: u64) -> Result<(), AllocatorError> {
+        let submission = self.ctx.submissions.next();
+        if self
+            .pending_blocks
+            .insert((slab, offset), submission)
+            .is_none()
+        {
+            self.took_back(self.slabs[&slab].block);
+            Ok(())
+        } else {
+            Err(AllocatorError::DoubleFreeAttempt)
+        }
+    }
+
+    // Empty slabs, which can be released like free buffers
+    fn empty_slabs(&self) -> Vec<BufferId> {
+        self.slabs
+            .iter()
+            .filter(|(_, slab)| slab.is_empty())
+            .map(|(id, _)| *id)
+            .collect()
     }
 
     fn allocate_new_buffer(&mut self, size: u64) -> Result<BufferId, AllocatorError> {
@@ -189,25 +531,40 @@ impl BufferAllocator {
     }
 
     fn free(&mut self, buf: BufferId) -> Result<(), AllocatorError> {
-        if self.pending.insert(buf) {
+        let submission = self.ctx.submissions.next();
+        if self.pending.insert(buf, submission).is_none() {
+            self.took_back(self.store[buf].capacity);
             Ok(())
         } else {
             Err(AllocatorError::DoubleFreeAttempt)
         }
     }
 
+    fn took_back(&mut self, capacity: u64) {
+        self.counters.in_use_bytes -= capacity;
+        self.record(AllocatorEventKind::Free { capacity });
+    }
+
+    // Moves the pending buffers whose submissions already finished to the free pool, without
+    // waiting for the others
     fn reclaim(&mut self) {
-        if self.pending.is_empty() {
+        if self.pending.is_empty() && self.pending_blocks.is_empty() {
             return;
         }
 
-        // Wait for all pending work to be complete
-        let _ = self
-            .ctx
-            .device
-            .poll(wgpu::wgt::PollType::wait_indefinitely());
+        let finished = self.ctx.finished_submission();
+        self.reclaim_until(finished);
+    }
 
-        for buf in self.pending.drain() {
+    fn reclaim_until(&mut self, finished: u64) {
+        let before = self.pending_size();
+
+        let bufs: Vec<BufferId> = self
+            .pending
+            .extract_if(|_, submission| *submission <= finished)
+            .map(|(buf, _)| buf)
+            .collect();
+        for buf in bufs {
             let capacity = self.store[buf].capacity;
 
             self.free_pool
@@ -215,27 +572,51 @@ impl BufferAllocator {
                 .and_modify(|v| v.push(buf))
                 .or_insert(vec![buf]);
         }
+        let blocks: Vec<(BufferId, u64)> = self
+            .pending_blocks
+            .extract_if(|_, submission| *submission <= finished)
+            .map(|(block, _)| block)
+            .collect();
+        for (slab, offset) in blocks {
+            self.slabs.get_mut(&slab).unwrap().free.push(offset);
+        }
+
+        let bytes = before - self.pending_size();
+        if bytes > 0 {
+            self.record(AllocatorEventKind::Reclaim { bytes });
+        }
     }
 
     fn evict(&mut self, hard: bool, target: Option<u64>) {
-        if self.free_pool.is_empty() {
+        let empty_slabs = self.empty_slabs();
+        if self.free_pool.is_empty() && empty_slabs.is_empty() {
             return;
         };
 
         // TTL eviction pass
         if !hard {
+            let mut expired: Vec<BufferId> = empty_slabs
+                .into_iter()
+                .filter(|id| self.clock - self.store[*id].timestamp >= self.mcp.max_ttl)
+                .collect();
+
             self.free_pool.iter_mut().for_each(|(_, v)| {
                 v.retain(|id| {
                     let pred = (self.clock - self.store[*id].timestamp) < self.mcp.max_ttl;
 
                     if !pred {
-                        self.store.remove(*id);
+                        expired.push(*id);
                     }
 
                     pred
                 });
             });
             self.free_pool.retain(|_, v| !v.is_empty());
+
+            for id in expired {
+                self.slabs.remove(&id);
+                self.release(id, EvictionCause::Ttl);
+            }
         }
 
         let cache_limit = (self.in_use_size() as f64 * self.mcp.cache_to_used_proportion) as u64;
@@ -249,8 +630,13 @@ impl BufferAllocator {
                 .free_pool
                 .iter()
                 .fold(0, |sum, (k, v)| sum + k * v.len() as u64);
+            let slabs: u64 = self
+                .empty_slabs()
+                .iter()
+                .map(|id| self.store[*id].capacity)
+                .sum();
 
-            fps.saturating_sub(cache_limit)
+            (fps + slabs).saturating_sub(cache_limit)
         };
 
         if target > 0 {
@@ -258,7 +644,9 @@ impl BufferAllocator {
                 .free_pool
                 .values()
                 .flatten()
-                .map(|id| (*id, self.store[*id].timestamp))
+                .copied()
+                .chain(self.empty_slabs())
+                .map(|id| (id, self.store[id].timestamp))
                 .collect::<Vec<(BufferId, usize)>>();
             ids.sort_by_key(|(_, time)| *time);
 
@@ -274,22 +662,40 @@ impl BufferAllocator {
             }
 
             self.free_pool.iter_mut().for_each(|(_, v)| {
-                v.retain(|id| {
-                    let pred = for_deletion.contains(id);
-
-                    if pred {
-                        self.store.remove(*id);
-                    }
-
-                    !pred
-                });
+                v.retain(|id| !for_deletion.contains(id));
             });
             self.free_pool.retain(|_, v| !v.is_empty());
+            self.slabs.retain(|id, _| !for_deletion.contains(id));
+
+            let cause = if hard {
+                EvictionCause::OutOfMemory
+            } else {
+                EvictionCause::CacheLimit
+            };
+            // Oldest first
+            for (id, _) in ids.into_iter().take(for_deletion.len()) {
+                self.release(id, cause);
+            }
         }
 
         self.last_eviction = self.clock;
     }
 
+    // Deletes a buffer taken out of the free pool or the slabs
+    fn release(&mut self, id: BufferId, cause: EvictionCause) {
+        let capacity = self.store.remove(id).unwrap().capacity;
+
+        let counters = &mut self.counters;
+        match cause {
+            EvictionCause::Ttl => counters.ttl_evictions += 1,
+            EvictionCause::CacheLimit => counters.cache_limit_evictions += 1,
+            EvictionCause::OutOfMemory => counters.out_of_memory_evictions += 1,
+        }
+        counters.evicted_bytes += capacity;
+
+        self.record(AllocatorEventKind::Evict { capacity, cause });
+    }
+
     fn try_evict(&mut self) {
         if self.clock - self.last_eviction > self.mcp.eviction_debounce {
             self.evict(false, None);
@@ -301,16 +707,29 @@ impl BufferAllocator {
     }
 
     fn pending_size(&self) -> u64 {
+        let blocks: u64 = self
+            .pending_blocks
+            .keys()
+            .map(|(slab, _)| self.slabs[slab].block)
+            .sum();
+
         self.pending
-            .iter()
-            .fold(0, |acc, id| acc + self.store[*id].capacity)
+            .keys()
+            .fold(blocks, |acc, id| acc + self.store[*id].capacity)
     }
 
+    // Free buffers and the free blocks of slabs
     fn free_size(&self) -> u64 {
+        let blocks: u64 = self
+            .slabs
+            .values()
+            .map(|slab| slab.block * slab.free.len() as u64)
+            .sum();
+
         self.free_pool
             .values()
             .flatten()
-            .fold(0, |acc, id| acc + self.store[*id].capacity)
+            .fold(blocks, |acc, id| acc + self.store[*id].capacity)
     }
 
     fn in_use_size(&self) -> u64 {
@@ -334,6 +753,8 @@ pub mod usage_marker {
 pub struct BufferAllocatorRef<T: usage_marker::BufferUsageMarker> {
     alloc: Arc<Mutex<BufferAllocator>>,
     ctx: WGPUContext,
+    // Owning backend, evicted as a whole when an allocation fails
+    backend: Weak<GpuBackend>,
     _tag: PhantomData<T>,
 }
 
@@ -353,6 +774,7 @@ pub struct BufferStat {
 #[derive(Serialize)]
 pub struct BufferAllocStats {
     pub buffers: Vec<BufferStat>,
+    pub counters: AllocatorCounters,
 }
 
 impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
@@ -363,7 +785,16 @@ impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
                 .store
                 .iter()
                 .map(|(k, v)| {
-                    let status = if alloc.pending.contains(&k) {
+                    let status = if let Some(slab) = alloc.slabs.get(&k) {
+                        let pending = alloc.pending_blocks.keys().filter(|(s, _)| *s == k).count();
+                        if slab.is_empty() {
+                            BufferStatus::Free
+                        } else if slab.free.len() + pending == slab.blocks {
+                            BufferStatus::Pending
+                        } else {
+                            BufferStatus::InUse
+                        }
+                    } else if alloc.pending.contains_key(&k) {
                         BufferStatus::Pending
                     } else if alloc.free_pool.contains_key(&v.capacity) {
                         if alloc.free_pool[&v.capacity].contains(&k) {
@@ -380,13 +811,38 @@ impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
                     }
                 })
                 .collect(),
+            counters: alloc.counters,
         }
     }
 
+    /// Starts a new event log, timed from `start`, or stops recording when `None`.
+    pub(crate) fn record_events(&self, start: Option<Instant>) {
+        self.alloc.lock().unwrap().record_events(start);
+    }
+
+    pub(crate) fn mark_step(&self) {
+        self.alloc.lock().unwrap().mark_step();
+    }
+
+    /// Events recorded since the log started, empty when not recording.
+    pub(crate) fn events(&self) -> Vec<AllocatorEvent> {
+        let alloc = self.alloc.lock().unwrap();
+        alloc
+            .events
+            .as_ref()
+            .map_or(vec![], |log| log.events.clone())
+    }
+
     pub fn reclaim(&self) {
         self.alloc.lock().unwrap().reclaim();
     }
 
+    /// Moves every pending buffer to the free pool without waiting, for recordings whose
+    /// commands run in order.
+    pub(crate) fn reclaim_all(&self) {
+        self.alloc.lock().unwrap().reclaim_until(u64::MAX);
+    }
+
     pub fn try_evict(&self) {
         self.alloc.lock().unwrap().try_evict();
     }
@@ -397,28 +853,30 @@ impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
 }
 
 impl BufferAllocatorRef<usage_marker::Storage> {
-    pub fn new(ctx: WGPUContext) -> Self {
+    pub(crate) fn new(ctx: WGPUContext, mcp: CachePolicy, backend: Weak<GpuBackend>) -> Self {
         Self {
             alloc: Arc::new(Mutex::new(BufferAllocator::new(
                 ctx.clone(),
                 Usage::Storage,
-                MemoryCachePolicy::default(),
+                mcp,
             ))),
             ctx,
+            backend,
             _tag: PhantomData,
         }
     }
 }
 
 impl BufferAllocatorRef<usage_marker::Readback> {
-    pub fn new(ctx: WGPUContext) -> Self {
+    pub(crate) fn new(ctx: WGPUContext, mcp: CachePolicy, backend: Weak<GpuBackend>) -> Self {
         Self {
             alloc: Arc::new(Mutex::new(BufferAllocator::new(
                 ctx.clone(),
                 Usage::Readback,
-                MemoryCachePolicy::default(),
+                mcp,
             ))),
             ctx,
+            backend,
             _tag: PhantomData,
         }
     }
@@ -429,16 +887,19 @@ impl<T: usage_marker::BufferUsageMarker> Clone for BufferAllocatorRef<T> {
         Self {
             alloc: self.alloc.clone(),
             ctx: self.ctx.clone(),
+            backend: self.backend.clone(),
             _tag: self._tag,
         }
     }
 }
 
-/// RAII Handle to a wgpu buffer. The buffer is placed in the "pending reuse" queue on the drop of this handle
+/// RAII Handle to a wgpu buffer, or to a block of a slab starting at `offset()`. The buffer is
+/// placed in the "pending reuse" queue on the drop of this handle
 #[derive(Debug)]
 pub struct BufferLease<T: usage_marker::BufferUsageMarker> {
     raw: Arc<wgpu::Buffer>,
     buf: BufferId,
+    offset: u64,
     size: u64,
     alloc: BufferAllocatorRef<T>,
 }
@@ -446,22 +907,33 @@ pub struct BufferLease<T: usage_marker::BufferUsageMarker> {
 impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
     pub fn request(&self, size: u64) -> BufferLease<T> {
         assert!(
-            size % 4 == 0,
+            size.is_multiple_of(4),
             "Only 4 bytes buffer size alignment supported for now"
         );
+        let max = self.ctx.device.limits().max_buffer_size;
+        assert!(
+            size <= max,
+            "Buffer of {size} bytes is larger than the max buffer size of the device, {max} bytes"
+        );
 
-        let buf = self.alloc.lock().unwrap().request(size);
-        let buf =
-            match buf {
-                Ok(buf) => buf,
-                Err(_) => {
-                    rt().hard_evict(Some(size));
-
-                    self.alloc.lock().unwrap().request(size).expect(
-                        "Buffer allocation failed after emergency eviction attempt. Aborting!",
-                    )
+        let try_request = || {
+            let mut alloc = self.alloc.lock().unwrap();
+            match alloc.block_size(size) {
+                Some(block) => alloc.request_block(size, block),
+                None => alloc.request(size).map(|buf| (buf, 0)),
+            }
+        };
+        let (buf, offset) = match try_request() {
+            Ok(res) => res,
+            Err(_) => {
+                if let Some(backend) = self.backend.upgrade() {
+                    backend.hard_evict(Some(size));
                 }
-            };
+
+                try_request()
+                    .expect("Buffer allocation failed after emergency eviction attempt. Aborting!")
+            }
+        };
 
         let alloc = self.alloc.lock().unwrap();
         let raw = alloc.store[buf].raw.clone();
@@ -470,6 +942,7 @@ impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
         BufferLease {
             raw,
             buf,
+            offset,
             size,
             alloc: self.clone(),
         }
@@ -478,12 +951,13 @@ impl<T: usage_marker::BufferUsageMarker> BufferAllocatorRef<T> {
 
 impl<T: usage_marker::BufferUsageMarker> Drop for BufferLease<T> {
     fn drop(&mut self) {
-        self.alloc
-            .alloc
-            .lock()
-            .unwrap()
-            .free(self.buf)
-            .expect("buffer double-free detected");
+        let mut alloc = self.alloc.alloc.lock().unwrap();
+        let res = if alloc.slabs.contains_key(&self.buf) {
+            alloc.free_block(self.buf, self.offset)
+        } else {
+            alloc.free(self.buf)
+        };
+        res.expect("buffer double-free detected");
     }
 }
 
@@ -491,6 +965,37 @@ impl<T: usage_marker::BufferUsageMarker> BufferLease<T> {
     pub fn size(&self) -> u64 {
         self.size
     }
+
+    /// Offset of the data in `raw()`, zero unless the lease is a slab block.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn raw(&self) -> &wgpu::Buffer {
+        &self.raw
+    }
+}
+
+/// Byte range of a storage buffer, bound on its own by kernels that walk a tensor too large for
+/// a single binding.
+pub(crate) struct BufferWindow<'a> {
+    raw: &'a wgpu::Buffer,
+    offset: u64,
+    size: u64,
+}
+
+impl BufferLease<usage_marker::Storage> {
+    pub(crate) fn window(&self, offset: u64, size: u64) -> BufferWindow<'_> {
+        assert!(
+            offset + size <= self.size,
+            "window past the end of the buffer"
+        );
+        BufferWindow {
+            raw: &self.raw,
+            offset: self.offset + offset,
+            size,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -511,15 +1016,12 @@ impl BufferLease<usage_marker::Readback> {
                 .create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor {
                     label: Some("staging buffer copy encoder"),
                 });
-        encoder.copy_buffer_to_buffer(&storage.raw, 0, &self.raw, 0, storage.size);
-        self.alloc.ctx.queue.submit(Some(encoder.finish()));
+        encoder.copy_buffer_to_buffer(&storage.raw, storage.offset, &self.raw, 0, storage.size);
+        self.alloc.ctx.submit(Some(encoder.finish()));
 
         let (tx, rx) = channel();
 
-        self.raw
-            .map_async(wgpu::MapMode::Read, ..self.size, move |result| {
-                tx.send(result).unwrap()
-            });
+        self.map_async(move |result| tx.send(result).unwrap());
 
         let _ = self
             .alloc
@@ -529,6 +1031,20 @@ impl BufferLease<usage_marker::Readback> {
 
         rx.recv().unwrap().unwrap();
 
+        Ok(self.read_mapped())
+    }
+
+    /// Starts mapping the whole buffer. `callback` runs inside a later `Device::poll`.
+    pub(crate) fn map_async(
+        &self,
+        callback: impl FnOnce(Result<(), wgpu::BufferAsyncError>) + Send + 'static,
+    ) {
+        self.raw
+            .map_async(wgpu::MapMode::Read, ..self.size, callback);
+    }
+
+    /// Copies out the contents of the mapped buffer and unmaps it.
+    pub(crate) fn read_mapped(&self) -> Vec<f32> {
         let result = {
             let bytes = self.raw.get_mapped_range(..self.size);
             bytemuck::cast_slice::<u8, f32>(&bytes).to_vec()
@@ -536,7 +1052,7 @@ impl BufferLease<usage_marker::Readback> {
 
         self.raw.unmap();
 
-        Ok(result)
+        result
     }
 }
 
@@ -546,11 +1062,7 @@ impl BufferLease<usage_marker::Storage> {
 
         let queue = self.alloc.ctx.queue.clone();
 
-        queue.write_buffer(&self.raw, 0, data);
-    }
-
-    pub(crate) fn raw(&self) -> &wgpu::Buffer {
-        &self.raw
+        queue.write_buffer(&self.raw, self.offset, data);
     }
 }
 
@@ -558,8 +1070,18 @@ impl AsBindingResource for BufferLease<usage_marker::Storage> {
     fn as_binding_resource(&self) -> wgpu::BindingResource<'_> {
         wgpu::BindingResource::Buffer(wgpu::BufferBinding {
             buffer: &self.raw,
-            offset: 0,
-            size: Some(NonZeroU64::new(self.size as u64).unwrap()),
+            offset: self.offset,
+            size: Some(NonZeroU64::new(self.size).unwrap()),
+        })
+    }
+}
+
+impl AsBindingResource for BufferWindow<'_> {
+    fn as_binding_resource(&self) -> wgpu::BindingResource<'_> {
+        wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+            buffer: self.raw,
+            offset: self.offset,
+            size: Some(NonZeroU64::new(self.size).unwrap()),
         })
     }
 }
@@ -573,23 +1095,18 @@ mod tests {
     fn test_ctx() -> WGPUContext {
         static CTX: OnceLock<WGPUContext> = OnceLock::new();
 
-        CTX.get_or_init(|| {
-            let adapter = WGPUContext::list_adapters()
-                .into_iter()
-                .next()
-                .expect("No WGPU adapter available for buffer allocator tests");
-            WGPUContext::new(adapter)
-        })
-        .clone()
+        CTX.get_or_init(|| WGPUContext::new(crate::runtime::test_adapter()))
+            .clone()
     }
 
-    fn test_policy() -> MemoryCachePolicy {
-        MemoryCachePolicy {
+    fn test_policy() -> CachePolicy {
+        CachePolicy {
             cache_to_used_proportion: 0.0,
             soft_cache_limit: 0,
             hard_cache_limit: 1024,
             max_ttl: 4,
             eviction_debounce: 2,
+            strategy: AllocationStrategy::default(),
         }
     }
 
@@ -597,13 +1114,25 @@ mod tests {
         BufferAllocator::new(test_ctx(), Usage::Storage, test_policy())
     }
 
+    // Submits and waits, so that everything freed until now can be reclaimed
+    fn finish_work(ctx: &WGPUContext) {
+        ctx.submit([]);
+        let _ = ctx.device.poll(wgpu::PollType::wait_indefinitely());
+    }
+
+    fn reclaim(alloc: &mut BufferAllocator) {
+        finish_work(&alloc.ctx);
+        alloc.reclaim();
+    }
+
     #[test]
-    fn validate_size_accepts_close_fit_and_rejects_wasteful_fit() {
-        assert!(validate_size(16, 12));
-        assert!(validate_size(1024 + 128, 1024));
+    fn best_fit_accepts_close_fit_and_rejects_wasteful_fit() {
+        let best_fit = AllocationStrategy::default();
+        assert!(best_fit.reuses(16, 12));
+        assert!(best_fit.reuses(1024 + 128, 1024));
 
-        assert!(!validate_size(16, 8));
-        assert!(!validate_size(1024 * 1024, 4));
+        assert!(!best_fit.reuses(16, 8));
+        assert!(!best_fit.reuses(1024 * 1024, 4));
     }
 
     #[test]
@@ -628,11 +1157,11 @@ mod tests {
         let id = alloc.request(16).unwrap();
 
         alloc.free(id).unwrap();
-        assert!(alloc.pending.contains(&id));
+        assert!(alloc.pending.contains_key(&id));
         assert_eq!(alloc.pending_size(), 16);
         assert_eq!(alloc.free_size(), 0);
 
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         assert!(alloc.pending.is_empty());
         assert_eq!(alloc.pending_size(), 0);
@@ -640,12 +1169,29 @@ mod tests {
         assert_eq!(alloc.free_pool.get(&16).unwrap(), &vec![id]);
     }
 
+    #[test]
+    fn reclaim_waits_for_the_submission_after_the_free() {
+        // Its own queue, the submissions of the other tests would finish it
+        let ctx = WGPUContext::new(crate::runtime::test_adapter());
+        let mut alloc = BufferAllocator::new(ctx, Usage::Storage, test_policy());
+        let id = alloc.request(16).unwrap();
+        alloc.free(id).unwrap();
+
+        // Commands using the buffer may still be recorded for the next submission
+        alloc.reclaim();
+        assert!(alloc.pending.contains_key(&id));
+
+        reclaim(&mut alloc);
+        assert!(alloc.pending.is_empty());
+        assert_eq!(alloc.free_pool.get(&16).unwrap(), &vec![id]);
+    }
+
     #[test]
     fn request_reuses_compatible_free_buffer_and_refreshes_timestamp() {
         let mut alloc = storage_alloc();
         let id = alloc.request(16).unwrap();
         alloc.free(id).unwrap();
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         let reused = alloc.request(12).unwrap();
 
@@ -662,7 +1208,7 @@ mod tests {
         let mut alloc = storage_alloc();
         let large = alloc.request(1024).unwrap();
         alloc.free(large).unwrap();
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         let small = alloc.request(4).unwrap();
 
@@ -679,7 +1225,7 @@ mod tests {
         let mut alloc = storage_alloc();
         let id = alloc.request(16).unwrap();
         alloc.free(id).unwrap();
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         alloc.clock = alloc.store[id].timestamp + alloc.mcp.max_ttl;
         alloc.evict(false, None);
@@ -693,11 +1239,11 @@ mod tests {
         let mut alloc = storage_alloc();
         let old = alloc.request(16).unwrap();
         alloc.free(old).unwrap();
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         let new = alloc.request(32).unwrap();
         alloc.free(new).unwrap();
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         alloc.evict(true, Some(17));
 
@@ -711,7 +1257,7 @@ mod tests {
         let mut alloc = storage_alloc();
         let id = alloc.request(16).unwrap();
         alloc.free(id).unwrap();
-        alloc.reclaim();
+        reclaim(&mut alloc);
 
         alloc.clock = alloc.mcp.eviction_debounce;
         alloc.try_evict();
@@ -724,11 +1270,184 @@ mod tests {
         assert_eq!(alloc.last_eviction, alloc.clock);
     }
 
+    #[test]
+    fn power_of_two_reuses_buffers_of_the_size_class() {
+        let policy = CachePolicy {
+            strategy: AllocationStrategy::PowerOfTwo,
+            ..test_policy()
+        };
+        let mut alloc = BufferAllocator::new(test_ctx(), Usage::Storage, policy);
+
+        let id = alloc.request(12).unwrap();
+        assert_eq!(alloc.store[id].capacity, 16);
+        alloc.free(id).unwrap();
+        reclaim(&mut alloc);
+
+        // Smaller classes get their own buffers
+        let small = alloc.request(8).unwrap();
+        assert_ne!(small, id);
+        assert_eq!(alloc.store[small].capacity, 8);
+        assert_eq!(alloc.request(16).unwrap(), id);
+    }
+
+    #[test]
+    fn slabs_hand_out_aligned_blocks_and_release_empty_slabs() {
+        let policy = CachePolicy {
+            strategy: AllocationStrategy::Slab {
+                slab_size: 4096,
+                max_block_size: 1024,
+            },
+            ..test_policy()
+        };
+        let alloc_ref = BufferAllocatorRef::<usage_marker::Storage> {
+            alloc: Arc::new(Mutex::new(BufferAllocator::new(
+                test_ctx(),
+                Usage::Storage,
+                policy,
+            ))),
+            ctx: test_ctx(),
+            backend: Weak::new(),
+            _tag: PhantomData,
+        };
+        let align = test_ctx()
+            .device
+            .limits()
+            .min_storage_buffer_offset_alignment as u64;
+        let block = 128u64.max(align);
+
+        // Both rounded up to the same block size
+        let a = alloc_ref.request(100);
+        let b = alloc_ref.request(128);
+        let large = alloc_ref.request(2048);
+        assert!(Arc::ptr_eq(&a.raw, &b.raw));
+        assert_eq!((a.offset(), b.offset()), (0, block));
+        assert_eq!((large.offset(), large.size()), (0, 2048));
+
+        // Blocks are only reused once reclaimed
+        drop(a);
+        assert_eq!(alloc_ref.request(128).offset(), 2 * block);
+        finish_work(&alloc_ref.ctx);
+        alloc_ref.reclaim();
+        let c = alloc_ref.request(120);
+        assert!(Arc::ptr_eq(&c.raw, &b.raw));
+        assert!(c.offset() < 3 * block);
+        {
+            let alloc = alloc_ref.alloc.lock().unwrap();
+            assert_eq!(alloc.total_size(), 4096 / block * block + 2048);
+            assert_eq!(alloc.in_use_size(), 2 * block + 2048);
+        }
+
+        drop((b, c, large));
+        finish_work(&alloc_ref.ctx);
+        alloc_ref.reclaim();
+        alloc_ref.hard_evict(None);
+        let alloc = alloc_ref.alloc.lock().unwrap();
+        assert!(alloc.store.is_empty());
+        assert!(alloc.slabs.is_empty());
+    }
+
+    #[test]
+    fn counters_track_peak_hits_waste_and_evictions_by_cause() {
+        let mut alloc = storage_alloc();
+        let small = alloc.request(16).unwrap();
+        let large = alloc.request(1024).unwrap();
+        alloc.free(small).unwrap();
+        alloc.free(large).unwrap();
+        reclaim(&mut alloc);
+        assert_eq!(alloc.counters.in_use_bytes, 0);
+
+        let reused = alloc.request(12).unwrap();
+        assert_eq!(reused, small);
+        // Over the cache limit of 0 bytes in use
+        alloc.evict(false, None);
+
+        let expired = alloc.request(32).unwrap();
+        alloc.free(expired).unwrap();
+        reclaim(&mut alloc);
+        alloc.clock += alloc.mcp.max_ttl;
+        alloc.evict(false, None);
+
+        let oom = alloc.request(64).unwrap();
+        alloc.free(oom).unwrap();
+        reclaim(&mut alloc);
+        alloc.evict(true, Some(1));
+
+        assert_eq!(alloc.counters.in_use_bytes, alloc.in_use_size());
+        assert_eq!(
+            alloc.counters,
+            AllocatorCounters {
+                in_use_bytes: 16,
+                peak_in_use_bytes: 1040,
+                pool_hits: 1,
+                new_allocations: 4,
+                requested_bytes: 16 + 1024 + 12 + 32 + 64,
+                wasted_bytes: 4,
+                ttl_evictions: 1,
+                cache_limit_evictions: 1,
+                out_of_memory_evictions: 1,
+                evicted_bytes: 1024 + 32 + 64,
+            }
+        );
+    }
+
+    #[test]
+    fn event_log_records_states_after_each_event() {
+        let mut alloc = storage_alloc();
+        let before = alloc.request(16).unwrap();
+        alloc.record_events(Some(Instant::now()));
+
+        let id = alloc.request(8).unwrap();
+        alloc.free(id).unwrap();
+        reclaim(&mut alloc);
+        alloc.request(6).unwrap();
+        alloc.free(before).unwrap();
+
+        let log = &alloc.events.as_ref().unwrap().events;
+        let kinds: Vec<_> = log.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AllocatorEventKind::Allocate {
+                    size: 8,
+                    capacity: 8
+                },
+                AllocatorEventKind::Free { capacity: 8 },
+                AllocatorEventKind::Reclaim { bytes: 8 },
+                AllocatorEventKind::Reuse {
+                    size: 6,
+                    capacity: 8
+                },
+                AllocatorEventKind::Free { capacity: 16 },
+            ]
+        );
+        let states: Vec<_> = log
+            .iter()
+            .map(|e| (e.in_use_bytes, e.pending_bytes, e.free_bytes))
+            .collect();
+        assert_eq!(
+            states,
+            vec![(24, 0, 0), (16, 8, 0), (16, 0, 8), (24, 0, 0), (8, 16, 0)]
+        );
+        assert!(log.windows(2).all(|w| w[0].time_us <= w[1].time_us));
+
+        let json = AllocatorEvents {
+            storage: log.clone(),
+            readback: vec![],
+        }
+        .to_json();
+        assert!(json.contains(r#""kind": "Reuse""#), "{json}");
+
+        alloc.record_events(None);
+        alloc.request(4).unwrap();
+        assert!(alloc.events.is_none());
+    }
+
     #[test]
     fn stats_reports_in_use_pending_and_free_buffers() {
         let alloc_ref = BufferAllocatorRef::<usage_marker::Storage> {
             alloc: Arc::new(Mutex::new(storage_alloc())),
             ctx: test_ctx(),
+            backend: Weak::new(),
             _tag: PhantomData,
         };
 
@@ -737,6 +1456,7 @@ mod tests {
         drop(pending);
         let free = alloc_ref.request(64);
         drop(free);
+        finish_work(&alloc_ref.ctx);
         alloc_ref.reclaim();
 
         let stats = alloc_ref.stats();
@@ -0,0 +1,241 @@
+use std::fmt;
+
+use crate::{
+    backend::{Backend, gpu::Capture},
+    fusion,
+    runtime::Device,
+    tensor::Tensor,
+};
+
+#[derive(Debug)]
+pub enum CaptureError {
+    /// Only GPU devices record their commands.
+    UnsupportedBackend,
+    DeviceMismatch,
+    /// The replay inputs don't match the captured ones.
+    MismatchedShapes,
+    /// The step read data back to the host, like `to_vec()`, `cross_entropy_loss` or the
+    /// backward of `sum` do. Replays only run the recorded GPU commands, so they would reuse the
+    /// old host results.
+    HostRead,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::UnsupportedBackend => write!(f, "only GPU devices can capture commands"),
+            CaptureError::DeviceMismatch => write!(f, "tensors are on different devices"),
+            CaptureError::MismatchedShapes => write!(f, "inputs don't match the captured ones"),
+            CaptureError::HostRead => write!(f, "captured step reads data back to the host"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+/// Dispatches and copies of one run of a step, replayed over the same buffers and bind groups.
+///
+/// Like a CUDA graph, replays read and write the buffers the step used when it was captured:
+/// tensors it returned are overwritten by every replay, and tensors it read, like parameters,
+/// must stay alive and only be updated in place. Scalars, like learning rates, are fixed at
+/// capture, so optimizers whose scalars change every step, like `Adam`, must step outside it.
+#[derive(Debug)]
+pub struct CapturedStep {
+    device: Device,
+    inputs: Vec<Tensor>,
+    capture: Capture,
+}
+
+// Ends the capture, even if the step panics
+struct CaptureGuard<'a>(&'a Device);
+
+impl Drop for CaptureGuard<'_> {
+    fn drop(&mut self) {
+        let gpu = self
+            .0
+            .rt()
+            .backend
+            .as_gpu()
+            .expect("Only GPU devices capture");
+        gpu.end_capture();
+    }
+}
+
+/// Runs `f` on `inputs` while recording the GPU commands it issues, and returns the recording
+/// with the result of `f`.
+///
+/// Other threads must not use the device while `f` runs.
+pub fn capture<R>(
+    inputs: &[Tensor],
+    f: impl FnOnce(&[Tensor]) -> R,
+) -> Result<(CapturedStep, R), CaptureError> {
+    assert!(!inputs.is_empty(), "capture() needs at least one input");
+
+    let device = inputs[0].device().clone();
+    if inputs.iter().any(|t| t.device() != &device) {
+        return Err(CaptureError::DeviceMismatch);
+    }
+    let gpu = device
+        .rt()
+        .backend
+        .as_gpu()
+        .ok_or(CaptureError::UnsupportedBackend)?;
+
+    // Deferred ops that ran while capturing would be replayed, overwriting the inputs
+    fusion::materialize_all(device.rt());
+    gpu.begin_capture(true);
+    let guard = CaptureGuard(&device);
+    let res = f(inputs);
+    std::mem::forget(guard);
+    let capture = gpu.end_capture();
+
+    if capture.host_reads {
+        return Err(CaptureError::HostRead);
+    }
+
+    let step = CapturedStep {
+        device: device.clone(),
+        inputs: inputs.to_vec(),
+        capture,
+    };
+
+    Ok((step, res))
+}
+
+impl CapturedStep {
+    /// Copies `inputs` into the captured inputs, overwriting them, and runs the recorded commands
+    /// again.
+    pub fn replay(&self, inputs: &[Tensor]) -> Result<(), CaptureError> {
+        if inputs.len() != self.inputs.len()
+            || inputs
+                .iter()
+                .zip(&self.inputs)
+                .any(|(t, captured)| t.shape() != captured.shape())
+        {
+            return Err(CaptureError::MismatchedShapes);
+        }
+        if inputs.iter().any(|t| t.device() != &self.device) {
+            return Err(CaptureError::DeviceMismatch);
+        }
+
+        let gpu = self
+            .device
+            .rt()
+            .backend
+            .as_gpu()
+            .expect("Captured on a GPU");
+        fusion::materialize_all(self.device.rt());
+        for (t, captured) in inputs.iter().zip(&self.inputs) {
+            if t.id() != captured.id() {
+                gpu.copy(t.buf(), captured.buf());
+            }
+        }
+        gpu.replay(&self.capture);
+
+        Ok(())
+    }
+
+    /// Kernels dispatched by each replay.
+    pub fn dispatches(&self) -> usize {
+        self.capture.dispatches()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        nn::{Adam, MLP, Model},
+        runtime::test_adapter,
+    };
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-4, "{actual:?} != {expected:?}");
+        }
+    }
+
+    fn batch(device: &Device, step: usize) -> Tensor {
+        let data: Vec<f32> = (0..8)
+            .map(|i| ((i + step) % 5) as f32 * 0.5 - 1.0)
+            .collect();
+        Tensor::new_on(device, &[2, 4], &data, false)
+    }
+
+    fn train_step(model: &MLP, opt: &Adam, x: &Tensor) -> Tensor {
+        opt.zero_grad();
+        let out = model.forward(x).unwrap();
+        // Backward of `sum` reads the gradient back to the host
+        let loss = out.mul(&out).unwrap();
+        loss.backward();
+        loss
+    }
+
+    #[test]
+    fn replayed_steps_match_eager_training() {
+        // Same seed, same initial parameters
+        let (eager_device, device) = (
+            Device::new(test_adapter(), 7),
+            Device::new(test_adapter(), 7),
+        );
+        let eager_model = MLP::new_on(&eager_device, &[4, 8, 2], true);
+        let model = MLP::new_on(&device, &[4, 8, 2], true);
+        let mut eager_opt = Adam::new(&eager_model, 0.01, 0.9, 0.999, 1e-8);
+        let mut opt = Adam::new(&model, 0.01, 0.9, 0.999, 1e-8);
+
+        let x = batch(&device, 0);
+        let (step, loss) = capture(std::slice::from_ref(&x), |inputs| {
+            train_step(&model, &opt, &inputs[0])
+        })
+        .unwrap();
+        assert!(step.dispatches() > 0);
+
+        for i in 0..4 {
+            let expected = train_step(&eager_model, &eager_opt, &batch(&eager_device, i));
+            if i > 0 {
+                step.replay(&[batch(&device, i)]).unwrap();
+            }
+            assert_close(&loss.to_vec(), &expected.to_vec());
+
+            eager_opt.step();
+            opt.step();
+        }
+
+        for (p, e) in model.params().iter().zip(eager_model.params()) {
+            assert_close(&p.to_vec(), &e.to_vec());
+        }
+    }
+
+    #[test]
+    fn steps_reading_back_or_on_cpu_are_rejected() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[1, 2], &[1.0, 2.0], false);
+        let targets = Tensor::new_on(&device, &[1, 2], &[0.0, 1.0], false);
+
+        let res = capture(std::slice::from_ref(&x), |inputs| {
+            inputs[0].cross_entropy_loss(&targets).unwrap()
+        });
+        assert!(matches!(res, Err(CaptureError::HostRead)));
+
+        // The device works as usual afterwards
+        let (step, y) = capture(std::slice::from_ref(&x), |inputs| {
+            inputs[0].mul_s(3.0).unwrap()
+        })
+        .unwrap();
+        assert!(matches!(
+            step.replay(&[targets.clone(), targets]),
+            Err(CaptureError::MismatchedShapes)
+        ));
+        step.replay(&[Tensor::new_on(&device, &[1, 2], &[-1.0, 0.5], false)])
+            .unwrap();
+        assert_eq!(y.to_vec(), vec![-3.0, 1.5]);
+        assert_eq!(x.to_vec(), vec![-1.0, 0.5]);
+
+        let cpu = Device::cpu(0);
+        let z = Tensor::new_on(&cpu, &[1], &[1.0], false);
+        assert!(matches!(
+            capture(&[z], |inputs| inputs[0].relu()),
+            Err(CaptureError::UnsupportedBackend)
+        ));
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    fmt,
+    ops::{Add, Div, Mul, Sub},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use crate::{
+    backend::TensorStorage,
+    ops::{
+        self, BinopEwizeType, OpType, ScalarEwizeType, TensorOpError, UnopEwizeType,
+        dispatch_binop_ewize, dispatch_scalar_ewize, dispatch_unop_ewize,
+    },
+    profiler,
+    runtime::{Device, Runtime, is_anomaly_enabled},
+    tensor::{Tensor, TensorInner, get_tensor_id, is_inference_tensor},
+    trace::{self, TracedOp},
+};
+
+// Longest chain of deferred ops, longer ones start over from the result of the chain
+const MAX_DEFERRED_INSTRS: usize = 64;
+
+/// Signature of a fused kernel: its ops in evaluation order, without the scalar values. Kernels
+/// are cached by it, so the same chain with other scalars reuses the compiled kernel.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FusedProgram {
+    pub(crate) instrs: Vec<FusedInstr>,
+    pub(crate) inputs: usize,
+    pub(crate) scalars: usize,
+}
+
+// Postfix instructions, each pops its operands and pushes its result
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum FusedInstr {
+    Input(usize),
+    Unop(UnopEwizeType),
+    Binop(BinopEwizeType),
+    // Index in the scalars of the program
+    Scalar(ScalarEwizeType, usize),
+}
+
+impl fmt::Display for FusedProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut stack = vec![];
+        for instr in &self.instrs {
+            let expr = match instr {
+                FusedInstr::Input(i) => format!("x{}", i),
+                FusedInstr::Unop(typ) => format!("{:?}({})", typ, stack.pop().unwrap()),
+                FusedInstr::Scalar(typ, i) => {
+                    format!("{:?}({}, s{})", typ, stack.pop().unwrap(), i)
+                }
+                FusedInstr::Binop(typ) => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    format!("{:?}({}, {})", typ, a, b)
+                }
+            };
+            stack.push(expr);
+        }
+
+        write!(f, "{}", stack.pop().unwrap_or_default())
+    }
+}
+
+// Program under construction over inputs of type `I`, shared by `FusedExpr` and the trace
+// compiler
+#[derive(Debug, Clone)]
+pub(crate) struct FusedBody<I> {
+    pub(crate) inputs: Vec<I>,
+    pub(crate) instrs: Vec<FusedInstr>,
+    pub(crate) scalars: Vec<f32>,
+}
+
+impl<I: Clone> FusedBody<I> {
+    pub(crate) fn leaf(input: I) -> Self {
+        Self {
+            inputs: vec![input],
+            instrs: vec![FusedInstr::Input(0)],
+            scalars: vec![],
+        }
+    }
+
+    pub(crate) fn unop(&mut self, typ: UnopEwizeType) {
+        self.instrs.push(FusedInstr::Unop(typ));
+    }
+
+    pub(crate) fn scalar(&mut self, typ: ScalarEwizeType, s: f32) {
+        self.instrs
+            .push(FusedInstr::Scalar(typ, self.scalars.len()));
+        self.scalars.push(s);
+    }
+
+    // Appends `other` and combines both results. An input on both sides stays one input
+    pub(crate) fn binop(
+        &mut self,
+        other: FusedBody<I>,
+        typ: BinopEwizeType,
+        same: impl Fn(&I, &I) -> bool,
+    ) {
+        let input_map: Vec<usize> = other
+            .inputs
+            .iter()
+            .map(|t| match self.inputs.iter().position(|i| same(i, t)) {
+                Some(i) => i,
+                None => {
+                    self.inputs.push(t.clone());
+                    self.inputs.len() - 1
+                }
+            })
+            .collect();
+        let scalar_offset = self.scalars.len();
+
+        self.instrs
+            .extend(other.instrs.into_iter().map(|instr| match instr {
+                FusedInstr::Input(i) => FusedInstr::Input(input_map[i]),
+                FusedInstr::Scalar(typ, i) => FusedInstr::Scalar(typ, i + scalar_offset),
+                instr => instr,
+            }));
+        self.scalars.extend(other.scalars);
+        self.instrs.push(FusedInstr::Binop(typ));
+    }
+
+    pub(crate) fn program(&self) -> FusedProgram {
+        FusedProgram {
+            instrs: self.instrs.clone(),
+            inputs: self.inputs.len(),
+            scalars: self.scalars.len(),
+        }
+    }
+}
+
+// Eager elementwise ops don't run right away: the result holds the chain of ops computing it,
+// built onto the chains of its operands, and the chain runs as one fused kernel when the result
+// is first used by another op or read back. Ops recorded for backward, checked for anomalies
+// or captured still run one by one
+pub(crate) fn can_defer(device: &Device, inputs: &[&Tensor]) -> bool {
+    !ops::should_grad(inputs)
+        && !is_anomaly_enabled()
+        && !device
+            .rt()
+            .backend
+            .as_gpu()
+            .is_some_and(|gpu| gpu.is_capturing())
+}
+
+// Deferred ops computing `t`, or `t` itself once they ran
+fn chain(t: &Tensor) -> FusedBody<Tensor> {
+    t.inner
+        .pending
+        .lock()
+        .unwrap()
+        .clone()
+        .filter(|body| body.instrs.len() < MAX_DEFERRED_INSTRS)
+        .unwrap_or_else(|| FusedBody::leaf(t.clone()))
+}
+
+pub(crate) fn defer_unop(t: &Tensor, typ: UnopEwizeType) -> Tensor {
+    let mut body = chain(t);
+    body.unop(typ);
+    pending(t, body)
+}
+
+pub(crate) fn defer_scalar(t: &Tensor, typ: ScalarEwizeType, s: f32) -> Tensor {
+    let mut body = chain(t);
+    body.scalar(typ, s);
+    pending(t, body)
+}
+
+pub(crate) fn defer_binop(lhs: &Tensor, rhs: &Tensor, typ: BinopEwizeType) -> Tensor {
+    let (mut body, mut other) = (chain(lhs), chain(rhs));
+    if body.inputs.len() + other.inputs.len() > lhs.rt().backend.max_fused_inputs() {
+        (body, other) = (FusedBody::leaf(lhs.clone()), FusedBody::leaf(rhs.clone()));
+    }
+    body.binop(other, typ, |a, b| a.id() == b.id());
+    pending(lhs, body)
+}
+
+// Tensor shaped like `like` computed by `body` on first use
+fn pending(like: &Tensor, body: FusedBody<Tensor>) -> Tensor {
+    let t = Tensor {
+        inner: Arc::new(TensorInner {
+            id: get_tensor_id(),
+            device: like.device().clone(),
+            inference: is_inference_tensor(false),
+            buf: OnceLock::new(),
+            pending: Mutex::new(Some(body)),
+            shape: like.shape().to_vec(),
+            requires_grad: false,
+            grad_node: None,
+        }),
+    };
+    let mut pending = t.rt().pending.lock().unwrap();
+    // Tensors dropped before their ops ran are pruned before the list grows
+    if pending.len() == pending.capacity() {
+        pending.retain(|t| t.strong_count() > 0);
+    }
+    pending.push(Arc::downgrade(&t.inner));
+    drop(pending);
+    t
+}
+
+// Runs the deferred ops of `t`
+pub(crate) fn materialize(t: &Tensor) -> TensorStorage {
+    let body = t
+        .inner
+        .pending
+        .lock()
+        .unwrap()
+        .take()
+        .expect("Tensors without storage have deferred ops");
+    let storages: Vec<_> = body.inputs.iter().map(|t| t.buf()).collect();
+    let buf = t
+        .rt()
+        .backend
+        .fused_ewize(&body.program(), &storages, &body.scalars);
+    if profiler::is_profiling() {
+        let input_shapes: Vec<&[usize]> = body.inputs.iter().map(|t| t.shape()).collect();
+        profiler::set_shapes(&input_shapes, t.shape());
+    }
+
+    buf
+}
+
+// Runs every deferred op of the device. Needed before a buffer is written in place, since the
+// ops read their inputs only when they run
+pub(crate) fn materialize_all(rt: &Runtime) {
+    let pending = std::mem::take(&mut *rt.pending.lock().unwrap());
+    for inner in pending.iter().filter_map(|t| t.upgrade()) {
+        Tensor { inner }.buf();
+    }
+}
+
+/// Chain of elementwise ops over tensors of the same shape. Nothing runs until `eval()`, which
+/// dispatches a single generated kernel and allocates only the result.
+///
+/// When the graph has to be recorded for backward, the ops run one by one instead.
+///
+/// ```ignore
+/// let update = (m_hat.fuse() / v_hat.fuse().sqrt().add_s(eps)).mul_s(-lr).eval()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct FusedExpr {
+    body: FusedBody<Tensor>,
+}
+
+impl From<&Tensor> for FusedExpr {
+    fn from(t: &Tensor) -> Self {
+        Self::new(t)
+    }
+}
+
+impl<T: Into<FusedExpr>> Add<T> for FusedExpr {
+    type Output = FusedExpr;
+
+    fn add(self, other: T) -> FusedExpr {
+        self.binop(other.into(), BinopEwizeType::Add)
+    }
+}
+
+impl<T: Into<FusedExpr>> Sub<T> for FusedExpr {
+    type Output = FusedExpr;
+
+    fn sub(self, other: T) -> FusedExpr {
+        self.binop(other.into(), BinopEwizeType::Sub)
+    }
+}
+
+impl<T: Into<FusedExpr>> Mul<T> for FusedExpr {
+    type Output = FusedExpr;
+
+    fn mul(self, other: T) -> FusedExpr {
+        self.binop(other.into(), BinopEwizeType::Mul)
+    }
+}
+
+impl<T: Into<FusedExpr>> Div<T> for FusedExpr {
+    type Output = FusedExpr;
+
+    fn div(self, other: T) -> FusedExpr {
+        self.binop(other.into(), BinopEwizeType::Div)
+    }
+}
+
+impl FusedExpr {
+    pub fn new(t: &Tensor) -> Self {
+        Self {
+            body: FusedBody::leaf(t.clone()),
+        }
+    }
+
+    pub fn add_s(mut self, s: f32) -> Self {
+        self.body.scalar(ScalarEwizeType::Add, s);
+        self
+    }
+
+    pub fn mul_s(mut self, s: f32) -> Self {
+        self.body.scalar(ScalarEwizeType::Mul, s);
+        self
+    }
+
+    pub fn relu(mut self) -> Self {
+        self.body.unop(UnopEwizeType::Relu);
+        self
+    }
+
+    pub fn sqrt(mut self) -> Self {
+        self.body.unop(UnopEwizeType::Sqrt);
+        self
+    }
+
+    fn binop(mut self, other: FusedExpr, typ: BinopEwizeType) -> Self {
+        self.body.binop(other.body, typ, |a, b| a.id() == b.id());
+        self
+    }
+
+    /// Signature the kernel is cached by.
+    pub fn program(&self) -> FusedProgram {
+        self.body.program()
+    }
+
+    pub fn eval(&self) -> Result<Tensor, TensorOpError> {
+        let inputs: Vec<&Tensor> = self.body.inputs.iter().collect();
+        let shape = inputs[0].shape();
+        if inputs.iter().any(|t| t.shape() != shape) {
+            return Err(TensorOpError::MismatchedShapes);
+        }
+        let device = ops::same_device(&inputs)?;
+        if trace::is_tracing() {
+            let op = TracedOp::Fused(self.program(), self.body.scalars.clone());
+            return Ok(trace::record(op, &inputs, shape));
+        }
+
+        let backend = &device.rt().backend;
+        if ops::should_grad(&inputs) || inputs.len() > backend.max_fused_inputs() {
+            return self.eval_unfused();
+        }
+
+        let storages: Vec<_> = inputs.iter().map(|t| t.buf()).collect();
+        let buf = backend.fused_ewize(&self.program(), &storages, &self.body.scalars);
+
+        ops::op_output(
+            device,
+            OpType::FusedEwize,
+            &inputs,
+            None,
+            buf,
+            shape.to_vec(),
+        )
+    }
+
+    fn eval_unfused(&self) -> Result<Tensor, TensorOpError> {
+        let mut stack: Vec<Tensor> = vec![];
+        for instr in &self.body.instrs {
+            let t = match instr {
+                FusedInstr::Input(i) => self.body.inputs[*i].clone(),
+                FusedInstr::Unop(typ) => dispatch_unop_ewize(&stack.pop().unwrap(), typ.clone())?,
+                FusedInstr::Scalar(typ, i) => dispatch_scalar_ewize(
+                    &stack.pop().unwrap(),
+                    typ.clone(),
+                    self.body.scalars[*i],
+                )?,
+                FusedInstr::Binop(typ) => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+                    dispatch_binop_ewize(&lhs, &rhs, typ.clone())?
+                }
+            };
+            stack.push(t);
+        }
+
+        Ok(stack.pop().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::BackendKind,
+        runtime::{Device, no_grad, test_adapter},
+    };
+
+    fn kernel_count(device: &Device) -> usize {
+        let gpu = device.rt().backend.as_gpu().unwrap();
+        gpu.kernel_registry.lock().unwrap().len()
+    }
+
+    #[test]
+    fn fused_chain_matches_separate_ops_in_one_dispatch() {
+        let data_a = [1.0, -2.0, 3.0, 0.5, -0.25, 8.0];
+        let data_b = [4.0, 1.0, 0.0, 2.0, 9.0, 16.0];
+
+        for device in [Device::new(test_adapter(), 0), Device::cpu(0)] {
+            let a = Tensor::new_on(&device, &[2, 3], &data_a, false);
+            let b = Tensor::new_on(&device, &[2, 3], &data_b, false);
+
+            let expected = a
+                .relu()
+                .unwrap()
+                .div(&b.sqrt().unwrap().add_s(1e-3).unwrap())
+                .unwrap()
+                .sub(&a.mul(&a).unwrap())
+                .unwrap()
+                .mul_s(-0.5)
+                .unwrap();
+
+            let guard = profiler::profile();
+            let fused = (a.fuse().relu() / b.fuse().sqrt().add_s(1e-3) - a.fuse() * &a)
+                .mul_s(-0.5)
+                .eval()
+                .unwrap();
+            let profile = guard.finish();
+
+            assert_eq!(fused.shape(), &[2, 3]);
+            assert_eq!(fused.to_vec(), expected.to_vec());
+            if device.backend() == BackendKind::Wgpu {
+                assert_eq!(profile.events.len(), 1);
+                assert_eq!(
+                    profile.events[0].label,
+                    "Fused(Mul(Sub(Div(Relu(x0), Add(Sqrt(x1), s0)), Mul(x0, x0)), s1))"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_are_cached_by_signature() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+        let y = Tensor::new_on(&device, &[3], &[4.0, 5.0, 6.0], false);
+
+        let before = kernel_count(&device);
+        let a = (x.fuse().mul_s(2.0) + &y).eval().unwrap();
+        assert_eq!(kernel_count(&device), before + 1);
+
+        // Other scalars and tensors with the same chain reuse the kernel
+        let b = (y.fuse().mul_s(-1.0) + &x).eval().unwrap();
+        assert_eq!(kernel_count(&device), before + 1);
+        assert_eq!(a.to_vec(), vec![6.0, 9.0, 12.0]);
+        assert_eq!(b.to_vec(), vec![-3.0, -3.0, -3.0]);
+
+        (x.fuse() + &y).mul_s(2.0).eval().unwrap();
+        assert_eq!(kernel_count(&device), before + 2);
+    }
+
+    #[test]
+    fn chains_needing_grad_run_unfused() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[2], &[1.0, 2.0], true);
+        let y = Tensor::new_on(&device, &[2], &[3.0, 4.0], false);
+
+        let z = (x.fuse() * &y).mul_s(2.0).eval().unwrap();
+        assert!(z.requires_grad());
+        z.sum().unwrap().backward();
+        assert_eq!(x.grad().unwrap().to_vec(), vec![6.0, 8.0]);
+
+        let w = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+        assert!(matches!(
+            (y.fuse() + &w).eval(),
+            Err(TensorOpError::MismatchedShapes)
+        ));
+    }
+
+    #[test]
+    fn eager_elementwise_ops_are_deferred_into_one_kernel() {
+        let m_hat_data = [0.5, -1.0, 2.0, 0.25];
+        let v_hat_data = [4.0, 1.0, 0.0, 16.0];
+        let (eps, lr) = (1e-3, 0.1);
+
+        for device in [Device::new(test_adapter(), 0), Device::cpu(0)] {
+            let m_hat = Tensor::new_on(&device, &[2, 2], &m_hat_data, false);
+            let v_hat = Tensor::new_on(&device, &[2, 2], &v_hat_data, false);
+
+            let guard = profiler::profile();
+            let update = m_hat
+                .div(&v_hat.sqrt().unwrap().add_s(eps).unwrap())
+                .unwrap()
+                .mul_s(-lr)
+                .unwrap();
+            assert!(update.inner.buf.get().is_none());
+            let values = update.to_vec();
+            let profile = guard.finish();
+
+            let expected: Vec<f32> = m_hat_data
+                .iter()
+                .zip(v_hat_data)
+                .map(|(m, v)| m / (v.sqrt() + eps) * -lr)
+                .collect();
+            assert_eq!(values, expected);
+            if device.backend() == BackendKind::Wgpu {
+                assert_eq!(profile.events.len(), 1);
+                assert_eq!(
+                    profile.events[0].label,
+                    "Fused(Mul(Div(x0, Add(Sqrt(x1), s0)), s1))"
+                );
+                assert_eq!(profile.events[0].input_shapes, vec![vec![2, 2]; 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn deferred_ops_run_before_their_inputs_are_assigned() {
+        let device = Device::new(test_adapter(), 0);
+        let mut x = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+        let y = x.mul_s(2.0).unwrap();
+
+        x.assign(&Tensor::new_on(&device, &[3], &[0.0; 3], false));
+        assert_eq!(y.to_vec(), vec![2.0, 4.0, 6.0]);
+        assert_eq!(x.to_vec(), vec![0.0; 3]);
+    }
+
+    #[test]
+    fn ops_recorded_for_backward_run_right_away() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[2], &[1.0, 2.0], true);
+
+        let y = x.mul_s(2.0).unwrap();
+        assert!(y.inner.buf.get().is_some());
+        assert!(y.requires_grad());
+
+        let z = {
+            let _ng = no_grad();
+            x.mul_s(2.0).unwrap()
+        };
+        assert!(z.inner.buf.get().is_none());
+        assert_eq!(z.to_vec(), vec![2.0, 4.0]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{collections::HashSet, io, path::Path};
+
+use graphviz_rust::{
+    cmd::{CommandArg, Format},
+    dot_structures::{Attribute, Edge, EdgeTy, Graph, Id, Node, NodeId, Stmt, Vertex},
+    printer::{DotPrinter, PrinterContext},
+};
+use serde::Serialize;
+
+use crate::tensor::Tensor;
+
+/// A tensor in the computation graph.
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphNode {
+    pub id: u64,
+    /// Op that produced the tensor, `None` for leaves.
+    pub op: Option<String>,
+    pub shape: Vec<usize>,
+    pub requires_grad: bool,
+    pub buffer_size: u64,
+    /// The saved inputs of the op were already freed by a backward pass, so the graph is cut here.
+    pub released: bool,
+}
+
+/// Data flow edge from an op input to its output.
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphEdge {
+    pub from: u64,
+    pub to: u64,
+    /// Position of `from` among the inputs of the op.
+    pub input_index: usize,
+}
+
+/// Snapshot of the autograd graph that produced a tensor.
+#[derive(Debug, Clone, Serialize)]
+pub struct ComputationGraph {
+    pub root: u64,
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RenderFormat {
+    Svg,
+    Png,
+}
+
+impl ComputationGraph {
+    pub(crate) fn from_tensor(root: &Tensor) -> Self {
+        let mut graph = ComputationGraph {
+            root: root.id(),
+            nodes: vec![],
+            edges: vec![],
+        };
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![root.clone()];
+
+        while let Some(t) = stack.pop() {
+            if !visited.insert(t.id()) {
+                continue;
+            }
+
+            let parents = t.inner.grad_node.as_ref().map(|n| n.parents());
+
+            graph.nodes.push(GraphNode {
+                id: t.id(),
+                op: t.inner.grad_node.as_ref().map(|n| format!("{:?}", n.op)),
+                shape: t.shape().to_vec(),
+                requires_grad: t.requires_grad(),
+                buffer_size: t.buf().size(),
+                released: matches!(parents, Some(None)),
+            });
+
+            for (input_index, parent) in parents.flatten().into_iter().flatten().enumerate() {
+                graph.edges.push(GraphEdge {
+                    from: parent.id(),
+                    to: t.id(),
+                    input_index,
+                });
+                stack.push(parent);
+            }
+        }
+
+        graph
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Graph is always serializable")
+    }
+
+    pub fn to_graphviz(&self) -> Graph {
+        let mut stmts = vec![Stmt::Attribute(attr("rankdir", "BT"))];
+
+        for n in &self.nodes {
+            let title = n.op.as_deref().unwrap_or("Leaf");
+            let label = format!(
+                "{}\\nid: {}\\nshape: {:?}\\n{} bytes{}",
+                title,
+                n.id,
+                n.shape,
+                n.buffer_size,
+                if n.released {
+                    "\\nsaved tensors freed"
+                } else {
+                    ""
+                }
+            );
+
+            let mut attributes = vec![
+                attr("label", &escaped(&label)),
+                attr("shape", if n.op.is_some() { "ellipse" } else { "box" }),
+            ];
+            if n.requires_grad {
+                attributes.push(attr("style", "filled"));
+                attributes.push(attr("fillcolor", "lightblue"));
+            }
+            if n.id == self.root {
+                attributes.push(attr("peripheries", "2"));
+            }
+
+            stmts.push(Stmt::Node(Node::new(node_id(n.id), attributes)));
+        }
+
+        for e in &self.edges {
+            stmts.push(Stmt::Edge(Edge {
+                ty: EdgeTy::Pair(Vertex::N(node_id(e.from)), Vertex::N(node_id(e.to))),
+                attributes: vec![attr("label", &e.input_index.to_string())],
+            }));
+        }
+
+        Graph::DiGraph {
+            id: Id::Plain("torchic".to_string()),
+            strict: false,
+            stmts,
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        self.to_graphviz().print(&mut PrinterContext::default())
+    }
+
+    /// Renders the graph with the graphviz `dot` executable, which has to be installed.
+    pub fn render(&self, format: RenderFormat, path: impl AsRef<Path>) -> io::Result<()> {
+        let format = match format {
+            RenderFormat::Svg => Format::Svg,
+            RenderFormat::Png => Format::Png,
+        };
+
+        graphviz_rust::exec(
+            self.to_graphviz(),
+            &mut PrinterContext::default(),
+            vec![
+                format.into(),
+                CommandArg::Output(path.as_ref().to_string_lossy().into_owned()),
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+fn node_id(id: u64) -> NodeId {
+    NodeId(Id::Plain(format!("t{}", id)), None)
+}
+
+fn escaped(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\\\""))
+}
+
+fn attr(key: &str, value: &str) -> Attribute {
+    let value = if value.starts_with('"') {
+        Id::Escaped(value.to_string())
+    } else {
+        Id::Plain(value.to_string())
+    };
+
+    Attribute(Id::Plain(key.to_string()), value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::init_test_runtime;
+
+    #[test]
+    fn graph_contains_ops_leaves_and_edges() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2, 3], &[0.0; 6], false);
+        let w = Tensor::new(&[3, 4], &[0.0; 12], true);
+        let y = x.matmul(&w).unwrap().relu().unwrap();
+
+        let graph = y.graph();
+
+        assert_eq!(graph.root, y.id());
+        assert_eq!(graph.nodes.len(), 4);
+        assert_eq!(graph.edges.len(), 3);
+
+        let root = graph.nodes.iter().find(|n| n.id == y.id()).unwrap();
+        assert_eq!(root.op.as_deref(), Some("UnopEwizeType(Relu)"));
+        assert_eq!(root.shape, vec![2, 4]);
+        assert_eq!(root.buffer_size, 32);
+
+        let leaf = graph.nodes.iter().find(|n| n.id == w.id()).unwrap();
+        assert!(leaf.op.is_none());
+        assert!(leaf.requires_grad);
+
+        let x_edge = graph.edges.iter().find(|e| e.from == x.id()).unwrap();
+        assert_eq!(x_edge.input_index, 0);
+    }
+
+    #[test]
+    fn graph_exports_dot_and_json() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let y = x.mul_s(2.0).unwrap();
+        let graph = y.graph();
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph torchic"));
+        assert!(dot.contains(&format!("t{} -> t{}", x.id(), y.id())));
+        assert!(dot.contains("ScalarEwize(Mul)"));
+
+        let json: serde_json::Value = serde_json::from_str(&graph.to_json()).unwrap();
+        assert_eq!(json["root"], y.id());
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(json["edges"][0]["from"], x.id());
+    }
+
+    #[test]
+    fn graph_marks_released_nodes() {
+        let _rt = init_test_runtime();
+
+        let x = Tensor::new(&[2], &[1.0, 2.0], true);
+        let y = x.mul_s(2.0).unwrap();
+        y.sum().unwrap().backward();
+
+        let graph = y.graph();
+        assert_eq!(graph.nodes.len(), 1);
+        assert!(graph.nodes[0].released);
+        assert!(graph.edges.is_empty());
+    }
+}
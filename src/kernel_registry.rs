@@ -1,6 +1,13 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::{
+    amp::Precision,
+    fusion::{FusedInstr, FusedProgram},
     ops::{BinopEwizeType, OpType, ReduceOpType, ScalarEwizeType, UnopEwizeType},
     runtime::WGPUContext,
 };
@@ -24,23 +31,150 @@ impl KernelEntry {
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
 pub enum KernelKey {
     Op(OpType),
+    /// Matmul loading its tiles in f16, see `amp::autocast`.
+    F16Matmul,
+    /// Generated kernel evaluating a chain of elementwise ops.
+    Fused(FusedProgram),
     HeInit,
+    CheckFinite,
+    QuantizeInt8,
+    /// With `packed_dot4` the dot products use the WGSL `dot4I8Packed` builtin, otherwise they
+    /// are emulated with shifts.
+    Int8Matmul {
+        packed_dot4: bool,
+    },
+}
+
+impl KernelKey {
+    pub(crate) fn matmul(precision: Precision) -> Self {
+        match precision {
+            Precision::F32 => KernelKey::Op(OpType::Matmul),
+            Precision::F16 => KernelKey::F16Matmul,
+        }
+    }
+}
+
+/// How kernels receive their small parameters, like shapes and scalars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamsMode {
+    /// Set on the compute pass, on devices with `IMMEDIATES`.
+    Immediates,
+    /// Bound as a uniform buffer after the storage buffers.
+    Uniform,
+}
+
+// Largest parameters struct of the kernels
+pub(crate) const MAX_PARAMS_SIZE: u32 = 16;
+
+impl ParamsMode {
+    pub(crate) fn of(device: &wgpu::Device) -> Self {
+        if device.features().contains(wgpu::Features::IMMEDIATES)
+            && device.limits().max_immediate_size >= MAX_PARAMS_SIZE
+        {
+            ParamsMode::Immediates
+        } else {
+            ParamsMode::Uniform
+        }
+    }
+}
+
+// What the kernels of a device can use, picked when they're generated
+#[derive(Debug, Clone, Copy)]
+struct ShaderSupport {
+    params_mode: ParamsMode,
+    // Reductions combine values with subgroup ops instead of workgroup memory only
+    subgroups: bool,
+    // Storage buffers are all bound read-write, so that bindings can be ranges of the same
+    // buffer: wgpu rejects a buffer bound both read-only and writable in a dispatch
+    writable_bindings: bool,
+}
+
+impl ShaderSupport {
+    fn of(device: &wgpu::Device) -> Self {
+        Self {
+            params_mode: ParamsMode::of(device),
+            subgroups: device.features().contains(wgpu::Features::SUBGROUP),
+            writable_bindings: false,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct KernelRegistry {
     ctx: WGPUContext,
     map: HashMap<KernelKey, Arc<KernelEntry>>,
+    cache: Option<KernelCache>,
+    support: ShaderSupport,
+}
+
+// Kernels kept on disk across processes. Stored WGSL is used instead of generating it again when
+// it was generated by the same crate version for the same device support; compiled pipelines are
+// only kept on backends with `PIPELINE_CACHE`
+#[derive(Debug)]
+struct KernelCache {
+    dir: PathBuf,
+    pipelines: Option<(wgpu::PipelineCache, PathBuf)>,
 }
 
 impl KernelRegistry {
     pub fn new(ctx: WGPUContext) -> Self {
         Self {
+            support: ShaderSupport::of(&ctx.device),
             ctx,
             map: HashMap::new(),
+            cache: None,
         }
     }
 
+    /// Registry keeping its kernels in `dir`. `pipeline_cache_key` names the pipeline cache
+    /// file, it's `None` when the adapter can't cache pipelines.
+    pub(crate) fn with_cache_dir(
+        ctx: WGPUContext,
+        dir: &Path,
+        pipeline_cache_key: Option<String>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir.join("wgsl"))?;
+
+        let pipelines = pipeline_cache_key
+            .filter(|_| {
+                ctx.device
+                    .features()
+                    .contains(wgpu::Features::PIPELINE_CACHE)
+            })
+            .map(|key| {
+                let path = dir.join(format!("{}.bin", key));
+                let data = fs::read(&path).ok();
+                // SAFETY: the data was written by `persist()` for an adapter with the same key,
+                // wgpu checks that it still matches the driver
+                let cache = unsafe {
+                    ctx.device
+                        .create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                            label: Some("kernel pipeline cache"),
+                            data: data.as_deref(),
+                            fallback: true,
+                        })
+                };
+                (cache, path)
+            });
+
+        Ok(Self {
+            support: ShaderSupport::of(&ctx.device),
+            ctx,
+            map: HashMap::new(),
+            cache: Some(KernelCache {
+                dir: dir.to_path_buf(),
+                pipelines,
+            }),
+        })
+    }
+
+    /// Binds every storage buffer read-write, for allocators handing out ranges of the same
+    /// buffer to the inputs and outputs of a kernel.
+    pub(crate) fn with_writable_bindings(mut self) -> Self {
+        self.support.writable_bindings = true;
+        self
+    }
+
     fn load_with_source(&mut self, key: &KernelKey, src: &str) {
         let label = format!("{:?} shader", key);
         let shader = self
@@ -51,7 +185,11 @@ impl KernelRegistry {
                 source: wgpu::ShaderSource::Wgsl(src.into()),
             });
 
-        let bind_group_layout = kernel_key_to_bgl(&key, self.ctx.device.clone());
+        let bind_group_layout = kernel_key_to_bgl(key, self.support, self.ctx.device.clone());
+        let immediate_size = match self.support.params_mode {
+            ParamsMode::Immediates if has_params(key) => MAX_PARAMS_SIZE,
+            _ => 0,
+        };
 
         let label = format!("{:?} pipeline layout", key);
         let pl = self
@@ -60,7 +198,7 @@ impl KernelRegistry {
             .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some(&label),
                 bind_group_layouts: &[Some(&bind_group_layout)],
-                immediate_size: 0,
+                immediate_size,
             });
 
         let label = format!("{:?} compute pipeline", key);
@@ -73,7 +211,11 @@ impl KernelRegistry {
                 module: &shader,
                 entry_point: Some("main"),
                 compilation_options: Default::default(),
-                cache: None,
+                cache: self
+                    .cache
+                    .as_ref()
+                    .and_then(|c| c.pipelines.as_ref())
+                    .map(|(cache, _)| cache),
             });
 
         self.map.insert(
@@ -86,100 +228,17 @@ impl KernelRegistry {
     }
 
     fn load_known(&mut self, key: &KernelKey) {
-        match key {
-            KernelKey::Op(OpType::BinopEwizeType(typ)) => {
-                let template_base = include_str!("shader_templates/binop_ewize.wgsl");
-                let mut variables = HashMap::new();
-                match typ {
-                    BinopEwizeType::Add => {
-                        variables.insert("operation", "output[idx] = input1[idx] + input2[idx];");
-                    }
-                    BinopEwizeType::Mul => {
-                        variables.insert("operation", "output[idx] = input1[idx] * input2[idx];");
-                    }
-                    BinopEwizeType::Div => {
-                        variables.insert("operation", "output[idx] = input1[idx] / input2[idx];");
-                    }
-                    BinopEwizeType::Sub => {
-                        variables.insert("operation", "output[idx] = input1[idx] - input2[idx];");
-                    }
-                }
-                let src = subst::substitute(template_base, &variables)
-                    .expect("Shader template not substituted correcty!");
-                self.load_with_source(key, &src);
-            }
-            KernelKey::Op(OpType::UnopEwizeType(typ)) => {
-                let template_base = include_str!("shader_templates/unop_ewize.wgsl");
-                let mut variables = HashMap::new();
-                match typ {
-                    UnopEwizeType::Relu => {
-                        variables.insert(
-                            "operation",
-                            "output[idx] = select(0.0, input[idx], input[idx] >= 0.0);",
-                        );
-                    }
-                    UnopEwizeType::ReluBackward => {
-                        variables.insert(
-                            "operation",
-                            "output[idx] = select(0.0, 1.0, input[idx] > 0.0);",
-                        );
-                    }
-                    UnopEwizeType::Sqrt => {
-                        variables.insert("operation", "output[idx] = sqrt(input[idx]);");
-                    }
-                }
-                let src = subst::substitute(template_base, &variables)
-                    .expect("Shader template not substituted correcty!");
-                self.load_with_source(key, &src);
-            }
-            KernelKey::Op(OpType::Reduce(typ)) => {
-                let template_base = include_str!("shader_templates/reduce.wgsl");
-                let mut variables = HashMap::new();
-                match typ {
-                    ReduceOpType::Sum => {
-                        variables.insert("identity", "0.0");
-                        variables.insert("map", "acc + x");
-                    }
-                    ReduceOpType::Max => {
-                        variables.insert("identity", "-1.0 / 0.0");
-                        variables.insert("map", "max(acc, x)");
-                    }
-                }
-                let src = subst::substitute(template_base, &variables)
-                    .expect("Shader template not substituted correcty!");
-                self.load_with_source(key, &src);
-            }
-            KernelKey::Op(OpType::Matmul) => {
-                self.load_with_source(key, include_str!("shader_templates/matmul.wgsl"));
+        let header = KernelCache::header(key, self.support);
+        let stored = self.cache.as_ref().and_then(|c| c.load_wgsl(&header));
+        let src = stored.unwrap_or_else(|| {
+            let src = known_source(key, self.support);
+            if let Some(cache) = &self.cache {
+                // Losing the copy on disk doesn't stop the kernel from compiling
+                let _ = cache.store_wgsl(&header, &src);
             }
-            KernelKey::Op(OpType::Transpose) => {
-                self.load_with_source(key, include_str!("shader_templates/transpose.wgsl"));
-            }
-            KernelKey::Op(OpType::ScalarEwize(typ)) => {
-                let template_base = include_str!("shader_templates/scalar_ewize.wgsl");
-                let mut variables = HashMap::new();
-                match typ {
-                    ScalarEwizeType::Mul => {
-                        variables.insert("operation", "output[idx] = input[idx] * s;");
-                    }
-                    ScalarEwizeType::Add => {
-                        variables.insert("operation", "output[idx] = input[idx] + s;");
-                    }
-                }
-                let src = subst::substitute(template_base, &variables)
-                    .expect("Shader template not substituted correcty!");
-                self.load_with_source(key, &src);
-            }
-            KernelKey::Op(OpType::Outer) => {
-                self.load_with_source(key, include_str!("shader_templates/outer.wgsl"));
-            }
-            KernelKey::Op(OpType::CrossEntropyLoss) => {
-                panic!("CrossEntropyLoss is implemented as a CPU-side fused op")
-            }
-            KernelKey::HeInit => {
-                self.load_with_source(key, include_str!("shader_templates/normal.wgsl"));
-            }
-        }
+            src
+        });
+        self.load_with_source(key, &src);
     }
 
     pub fn get(&mut self, key: &KernelKey) -> Arc<KernelEntry> {
@@ -189,25 +248,375 @@ impl KernelRegistry {
 
         self.map.get(key).unwrap().clone()
     }
+
+    /// Compiles the kernels of `keys` now instead of on first use, then saves the pipeline cache.
+    pub fn warmup(&mut self, keys: &[KernelKey]) -> io::Result<()> {
+        for key in keys {
+            self.get(key);
+        }
+
+        self.persist()
+    }
+
+    /// Writes the compiled pipelines to the cache directory, if the backend can cache them.
+    pub fn persist(&self) -> io::Result<()> {
+        let Some((cache, path)) = self.cache.as_ref().and_then(|c| c.pipelines.as_ref()) else {
+            return Ok(());
+        };
+        let Some(data) = cache.get_data() else {
+            return Ok(());
+        };
+
+        // Written aside first so that a crash never leaves a truncated cache
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Number of compiled kernels.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+impl Drop for KernelRegistry {
+    fn drop(&mut self) {
+        let _ = self.persist();
+    }
+}
+
+impl KernelCache {
+    // First line of a stored kernel. The same key generates other WGSL on devices with other
+    // support, and templates change between versions
+    fn header(key: &KernelKey, support: ShaderSupport) -> String {
+        format!("// {} {:?} {:?}", env!("CARGO_PKG_VERSION"), key, support)
+    }
+
+    // Keys can be too long for a file name, fused programs in particular
+    fn wgsl_path(&self, header: &str) -> PathBuf {
+        // FNV-1a, stable across processes unlike `DefaultHasher`
+        let hash = header.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+
+        self.dir.join("wgsl").join(format!("{:016x}.wgsl", hash))
+    }
+
+    fn load_wgsl(&self, header: &str) -> Option<String> {
+        let stored = fs::read_to_string(self.wgsl_path(header)).ok()?;
+        let (first, src) = stored.split_once('\n')?;
+        (first == header).then(|| src.to_string())
+    }
+
+    fn store_wgsl(&self, header: &str, src: &str) -> io::Result<()> {
+        // Written aside first so that other processes never load a truncated kernel
+        let path = self.wgsl_path(header);
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, format!("{}\n{}", header, src))?;
+        fs::rename(&tmp, path)
+    }
+}
+
+// WGSL of a kernel, with the templates substituted
+fn known_source(key: &KernelKey, support: ShaderSupport) -> String {
+    // Start of the declaration of the parameters, after the storage buffers when they're bound
+    let params = match support.params_mode {
+        _ if !has_params(key) => String::new(),
+        ParamsMode::Immediates => "var<immediate>".to_string(),
+        ParamsMode::Uniform => format!(
+            "@group(0) @binding({}) var<uniform>",
+            read_only_mask(key).len()
+        ),
+    };
+
+    let src = match key {
+        KernelKey::Op(OpType::BinopEwizeType(typ)) => {
+            let template_base = include_str!("shader_templates/binop_ewize.wgsl");
+            let mut variables = HashMap::new();
+            let operation = format!(
+                "output[idx] = {};",
+                binop_expr(typ, "input1[idx]", "input2[idx]")
+            );
+            variables.insert("operation", operation.as_str());
+            subst::substitute(template_base, &variables)
+                .expect("Shader template not substituted correcty!")
+        }
+        KernelKey::Op(OpType::UnopEwizeType(typ)) => {
+            let template_base = include_str!("shader_templates/unop_ewize.wgsl");
+            let mut variables = HashMap::new();
+            let operation = format!("output[idx] = {};", unop_expr(typ, "input[idx]"));
+            variables.insert("operation", operation.as_str());
+            subst::substitute(template_base, &variables)
+                .expect("Shader template not substituted correcty!")
+        }
+        KernelKey::Op(OpType::Reduce(typ)) => reduce_source(typ, support.subgroups),
+        KernelKey::Op(OpType::Matmul) => matmul_source(Precision::F32, &params),
+        KernelKey::Op(OpType::Transpose) => {
+            with_params(include_str!("shader_templates/transpose.wgsl"), &params)
+        }
+        KernelKey::Op(OpType::ScalarEwize(typ)) => {
+            let template_base = include_str!("shader_templates/scalar_ewize.wgsl");
+            let mut variables = HashMap::new();
+            let operation = format!("output[idx] = {};", scalar_expr(typ, "input[idx]", "s"));
+            variables.insert("operation", operation.as_str());
+            variables.insert("params", params.as_str());
+            subst::substitute(template_base, &variables)
+                .expect("Shader template not substituted correcty!")
+        }
+        KernelKey::Op(OpType::Outer) => {
+            with_params(include_str!("shader_templates/outer.wgsl"), &params)
+        }
+        KernelKey::Op(OpType::CrossEntropyLoss) => {
+            panic!("CrossEntropyLoss is implemented as a CPU-side fused op")
+        }
+        KernelKey::Op(OpType::Checkpoint) => {
+            panic!("Checkpoint is a graph node that recomputes other ops")
+        }
+        KernelKey::Op(OpType::FusedEwize) => {
+            panic!("FusedEwize kernels are keyed by their program")
+        }
+        KernelKey::Op(OpType::Int8Matmul) => {
+            panic!("Int8Matmul kernels are keyed by KernelKey::Int8Matmul")
+        }
+        KernelKey::F16Matmul => matmul_source(Precision::F16, &params),
+        KernelKey::Fused(program) => fused_source(program),
+        KernelKey::HeInit => with_params(include_str!("shader_templates/normal.wgsl"), &params),
+        KernelKey::CheckFinite => include_str!("shader_templates/check_finite.wgsl").to_string(),
+        KernelKey::QuantizeInt8 => {
+            with_params(include_str!("shader_templates/quantize_int8.wgsl"), &params)
+        }
+        KernelKey::Int8Matmul { packed_dot4 } => int8_matmul_source(*packed_dot4, &params),
+    };
+
+    // Access modes of the shader and the layout must match
+    if support.writable_bindings {
+        src.replace("var<storage, read>", "var<storage, read_write>")
+    } else {
+        src
+    }
 }
 
-fn kernel_key_to_bgl(key: &KernelKey, device: Arc<wgpu::Device>) -> wgpu::BindGroupLayout {
-    let read_only_mask = match key {
+// WGSL expressions of the elementwise ops, shared by the single op templates and fused kernels
+
+fn unop_expr(typ: &UnopEwizeType, x: &str) -> String {
+    match typ {
+        UnopEwizeType::Relu => format!("select(0.0, {x}, {x} >= 0.0)"),
+        UnopEwizeType::ReluBackward => format!("select(0.0, 1.0, {x} > 0.0)"),
+        UnopEwizeType::Sqrt => format!("sqrt({x})"),
+    }
+}
+
+fn binop_expr(typ: &BinopEwizeType, a: &str, b: &str) -> String {
+    match typ {
+        BinopEwizeType::Add => format!("{a} + {b}"),
+        BinopEwizeType::Mul => format!("{a} * {b}"),
+        BinopEwizeType::Div => format!("{a} / {b}"),
+        BinopEwizeType::Sub => format!("{a} - {b}"),
+    }
+}
+
+fn scalar_expr(typ: &ScalarEwizeType, x: &str, s: &str) -> String {
+    match typ {
+        ScalarEwizeType::Mul => format!("{x} * {s}"),
+        ScalarEwizeType::Add => format!("{x} + {s}"),
+    }
+}
+
+// Tree reduction in workgroup memory, or over subgroups first when the device has them
+fn reduce_source(typ: &ReduceOpType, subgroups: bool) -> String {
+    let template_base = if subgroups {
+        include_str!("shader_templates/reduce_subgroup.wgsl")
+    } else {
+        include_str!("shader_templates/reduce.wgsl")
+    };
+
+    let mut variables = HashMap::new();
+    match typ {
+        ReduceOpType::Sum => {
+            variables.insert("identity", "0.0");
+            variables.insert("map", "acc + x");
+            variables.insert("subgroup_op", "subgroupAdd");
+        }
+        ReduceOpType::Max => {
+            // Lowest finite f32, WGSL rejects infinite constants
+            variables.insert("identity", "-3.40282347e+38");
+            variables.insert("map", "max(acc, x)");
+            variables.insert("subgroup_op", "subgroupMax");
+        }
+    }
+
+    subst::substitute(template_base, &variables).expect("Shader template not substituted correcty!")
+}
+
+// Templates whose only variable is the declaration of their parameters
+fn with_params(template_base: &str, params: &str) -> String {
+    let mut variables = HashMap::new();
+    variables.insert("params", params);
+    subst::substitute(template_base, &variables).expect("Shader template not substituted correcty!")
+}
+
+// Tiles are loaded in `precision`, products are always accumulated in f32
+fn matmul_source(precision: Precision, params: &str) -> String {
+    let mut variables = HashMap::new();
+    variables.insert("params", params);
+    match precision {
+        Precision::F32 => {
+            variables.insert("enable", "");
+            variables.insert("tile", "f32");
+            variables.insert("store", "acc[i][j]");
+        }
+        Precision::F16 => {
+            variables.insert("enable", "enable f16;\n\n");
+            variables.insert("tile", "f16");
+            variables.insert("store", "f32(f16(acc[i][j]))");
+        }
+    }
+
+    subst::substitute(include_str!("shader_templates/matmul.wgsl"), &variables)
+        .expect("Shader template not substituted correcty!")
+}
+
+fn int8_matmul_source(packed_dot4: bool, params: &str) -> String {
+    let dot4 = if packed_dot4 {
+        "return dot4I8Packed(a, b);"
+    } else {
+        // Sign extends each byte by shifting it to the top and back
+        "let shifts = vec4<u32>(24u, 16u, 8u, 0u);
+  let x = bitcast<vec4<i32>>(vec4<u32>(a) << shifts) >> vec4<u32>(24u);
+  let y = bitcast<vec4<i32>>(vec4<u32>(b) << shifts) >> vec4<u32>(24u);
+  return dot(x, y);"
+    };
+
+    let mut variables = HashMap::new();
+    variables.insert("dot4", dot4);
+    variables.insert("params", params);
+    subst::substitute(
+        include_str!("shader_templates/int8_matmul.wgsl"),
+        &variables,
+    )
+    .expect("Shader template not substituted correcty!")
+}
+
+// One `let` per instruction, the program is already in evaluation order
+fn fused_source(program: &FusedProgram) -> String {
+    let inputs: String = (0..program.inputs)
+        .map(|i| format!("@group(0) @binding({i}) var<storage, read> input{i}: array<f32>;\n"))
+        .collect();
+
+    let mut body = String::new();
+    let mut stack: Vec<String> = vec![];
+    for (i, instr) in program.instrs.iter().enumerate() {
+        let value = match instr {
+            FusedInstr::Input(j) => format!("input{j}[idx]"),
+            FusedInstr::Unop(typ) => unop_expr(typ, &stack.pop().unwrap()),
+            FusedInstr::Scalar(typ, j) => {
+                scalar_expr(typ, &stack.pop().unwrap(), &format!("scalars[{j}]"))
+            }
+            FusedInstr::Binop(typ) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                binop_expr(typ, &a, &b)
+            }
+        };
+        body.push_str(&format!("        let v{i} = {value};\n"));
+        stack.push(format!("v{i}"));
+    }
+    body.push_str(&format!("        output[idx] = {};", stack.pop().unwrap()));
+
+    let output_binding = program.inputs.to_string();
+    let scalars_binding = (program.inputs + 1).to_string();
+    let mut variables = HashMap::new();
+    variables.insert("inputs", inputs.as_str());
+    variables.insert("output_binding", output_binding.as_str());
+    variables.insert("scalars_binding", scalars_binding.as_str());
+    variables.insert("body", body.as_str());
+
+    subst::substitute(
+        include_str!("shader_templates/fused_ewize.wgsl"),
+        &variables,
+    )
+    .expect("Shader template not substituted correcty!")
+}
+
+// Whether the storage buffers of a kernel are read only, in binding order
+fn read_only_mask(key: &KernelKey) -> Vec<bool> {
+    match key {
         KernelKey::Op(OpType::BinopEwizeType(_)) => vec![true, true, false],
         KernelKey::Op(OpType::Reduce(_)) => vec![true, false],
-        KernelKey::Op(OpType::Matmul) => vec![true, true, false, true],
-        KernelKey::Op(OpType::Transpose) => vec![true, false, true],
+        KernelKey::Op(OpType::Matmul) => vec![true, true, false],
+        KernelKey::Op(OpType::Transpose) => vec![true, false],
         KernelKey::Op(OpType::UnopEwizeType(_)) => vec![true, false],
-        KernelKey::Op(OpType::ScalarEwize(_)) => vec![true, false, true],
-        KernelKey::Op(OpType::Outer) => vec![true, true, false, true],
+        KernelKey::Op(OpType::ScalarEwize(_)) => vec![true, false],
+        KernelKey::Op(OpType::Outer) => vec![true, true, false],
         KernelKey::Op(OpType::CrossEntropyLoss) => {
             panic!("CrossEntropyLoss does not use a GPU kernel bind group layout")
         }
-        KernelKey::HeInit => vec![true, false],
-    };
+        KernelKey::Op(OpType::Checkpoint) => {
+            panic!("Checkpoint does not use a GPU kernel bind group layout")
+        }
+        KernelKey::Op(OpType::FusedEwize) => {
+            panic!("FusedEwize kernels are keyed by their program")
+        }
+        KernelKey::Op(OpType::Int8Matmul) => {
+            panic!("Int8Matmul kernels are keyed by KernelKey::Int8Matmul")
+        }
+        // Inputs, then the output and the scalars, which can be too many for parameters
+        KernelKey::Fused(program) => {
+            let mut mask = vec![true; program.inputs];
+            mask.extend([false, true]);
+            mask
+        }
+        KernelKey::HeInit => vec![false],
+        KernelKey::CheckFinite => vec![true, false],
+        KernelKey::QuantizeInt8 => vec![true, false],
+        KernelKey::Int8Matmul { .. } => vec![true, true, true, false],
+        // Same bindings as the f32 kernel
+        KernelKey::F16Matmul => read_only_mask(&KernelKey::Op(OpType::Matmul)),
+    }
+}
 
-    let prefix = format!("{:?}", key);
-    create_bgl(&prefix, &read_only_mask, device)
+// Whether the kernel takes a `Params` struct, passed according to the `ParamsMode`
+pub(crate) fn has_params(key: &KernelKey) -> bool {
+    matches!(
+        key,
+        KernelKey::Op(OpType::Matmul | OpType::Transpose | OpType::ScalarEwize(_) | OpType::Outer)
+            | KernelKey::F16Matmul
+            | KernelKey::HeInit
+            | KernelKey::QuantizeInt8
+            | KernelKey::Int8Matmul { .. }
+    )
+}
+
+fn kernel_key_to_bgl(
+    key: &KernelKey,
+    support: ShaderSupport,
+    device: Arc<wgpu::Device>,
+) -> wgpu::BindGroupLayout {
+    let mask = read_only_mask(key);
+    let mut entries: Vec<wgpu::BindGroupLayoutEntry> = mask
+        .iter()
+        .enumerate()
+        .map(|(i, e)| entry(i as u32, *e && !support.writable_bindings))
+        .collect();
+    if support.params_mode == ParamsMode::Uniform && has_params(key) {
+        entries.push(wgpu::BindGroupLayoutEntry {
+            binding: mask.len() as u32,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+    }
+
+    let label = format!("{:?} bgl", key);
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(&label),
+        entries: &entries,
+    })
 }
 
 fn entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
@@ -223,19 +632,185 @@ fn entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
     }
 }
 
-fn create_bgl(
-    prefix: &str,
-    read_only: &[bool],
-    device: Arc<wgpu::Device>,
-) -> wgpu::BindGroupLayout {
-    let label = format!("{} bgl", prefix);
-    let entries: Vec<wgpu::BindGroupLayoutEntry> = read_only
-        .iter()
-        .enumerate()
-        .map(|(i, e)| entry(i as u32, *e))
-        .collect();
-    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-        label: Some(&label),
-        entries: &entries,
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{Device, RuntimeConfig, test_adapter};
+
+    #[test]
+    fn warmup_compiles_kernels_and_stores_them_on_disk() {
+        let dir = std::env::temp_dir().join(format!("torchic-kernel-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let keys = [
+            KernelKey::Op(OpType::Matmul),
+            KernelKey::Op(OpType::UnopEwizeType(UnopEwizeType::Relu)),
+            KernelKey::CheckFinite,
+        ];
+
+        for _ in 0..2 {
+            let config = RuntimeConfig::new().kernel_cache_dir(&dir);
+            let device = Device::with_config(test_adapter(), &config).unwrap();
+            device.warmup_kernels(&keys).unwrap();
+
+            let gpu = device.rt().backend.as_gpu().unwrap();
+            assert_eq!(gpu.kernel_registry.lock().unwrap().len(), keys.len());
+
+            let stored: Vec<String> = fs::read_dir(dir.join("wgsl"))
+                .unwrap()
+                .map(|e| fs::read_to_string(e.unwrap().path()).unwrap())
+                .collect();
+            assert_eq!(stored.len(), keys.len());
+            let header = KernelCache::header(
+                &KernelKey::Op(OpType::Matmul),
+                ShaderSupport::of(&device.rt().backend.as_gpu().unwrap().ctx.device),
+            );
+            assert!(
+                stored
+                    .iter()
+                    .any(|s| s.starts_with(&format!("{}\n", header)))
+            );
+
+            if device.features().contains(wgpu::Features::PIPELINE_CACHE) {
+                let bins = fs::read_dir(&dir)
+                    .unwrap()
+                    .filter(|e| e.as_ref().unwrap().path().extension() == Some("bin".as_ref()))
+                    .count();
+                assert_eq!(bins, 1);
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stored_wgsl_is_loaded_for_the_same_key_and_support() {
+        let dir = std::env::temp_dir().join(format!("torchic-wgsl-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let relu = KernelKey::Op(OpType::UnopEwizeType(UnopEwizeType::Relu));
+        let sqrt = KernelKey::Op(OpType::UnopEwizeType(UnopEwizeType::Sqrt));
+
+        let config = RuntimeConfig::new().kernel_cache_dir(&dir);
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+        let ctx = device.rt().backend.as_gpu().unwrap().ctx.clone();
+        let support = ShaderSupport::of(&ctx.device);
+        let cache = KernelCache {
+            dir: dir.clone(),
+            pipelines: None,
+        };
+        // Relu kernel computing a square root, to see which source is compiled
+        let header = KernelCache::header(&relu, support);
+        cache
+            .store_wgsl(&header, &known_source(&sqrt, support))
+            .unwrap();
+
+        let backend = &device.rt().backend;
+        let x = backend.upload(&[4.0, 9.0]);
+        let y = backend.unop_ewize(&UnopEwizeType::Relu, &x);
+        assert_eq!(backend.download(&y), vec![2.0, 3.0]);
+
+        // Other support generates its own kernel next to it
+        let mut registry = KernelRegistry::with_cache_dir(ctx, &dir, None)
+            .unwrap()
+            .with_writable_bindings();
+        registry.get(&relu);
+        assert_eq!(fs::read_dir(dir.join("wgsl")).unwrap().count(), 2);
+        let writable = KernelCache::header(&relu, registry.support);
+        assert_eq!(
+            cache.load_wgsl(&writable),
+            Some(known_source(&relu, registry.support))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The test adapter has no `SHADER_F16`, so the f16 kernel is validated here
+    #[test]
+    fn f16_matmul_is_valid_wgsl() {
+        use wgpu::naga::{front::wgsl, valid};
+
+        for params_mode in [ParamsMode::Immediates, ParamsMode::Uniform] {
+            let support = ShaderSupport {
+                params_mode,
+                subgroups: false,
+                writable_bindings: false,
+            };
+            let src = known_source(&KernelKey::F16Matmul, support);
+            let module =
+                wgsl::parse_str(&src).unwrap_or_else(|e| panic!("{}", e.emit_to_string(&src)));
+            valid::Validator::new(
+                valid::ValidationFlags::all(),
+                valid::Capabilities::SHADER_FLOAT16 | valid::Capabilities::IMMEDIATES,
+            )
+            .validate(&module)
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn int8_kernels_are_valid_wgsl() {
+        use wgpu::naga::{front::wgsl, valid};
+
+        let keys = [
+            KernelKey::QuantizeInt8,
+            KernelKey::Int8Matmul { packed_dot4: true },
+            KernelKey::Int8Matmul { packed_dot4: false },
+        ];
+        for key in keys {
+            for mode in [ParamsMode::Immediates, ParamsMode::Uniform] {
+                let support = ShaderSupport {
+                    params_mode: mode,
+                    subgroups: false,
+                    writable_bindings: false,
+                };
+                let src = known_source(&key, support);
+                let module =
+                    wgsl::parse_str(&src).unwrap_or_else(|e| panic!("{}", e.emit_to_string(&src)));
+                valid::Validator::new(
+                    valid::ValidationFlags::all(),
+                    valid::Capabilities::IMMEDIATES,
+                )
+                .validate(&module)
+                .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn subgroup_reductions_are_valid_wgsl() {
+        use wgpu::naga::{front::wgsl, valid};
+
+        for typ in [ReduceOpType::Sum, ReduceOpType::Max] {
+            let src = reduce_source(&typ, true);
+            let module =
+                wgsl::parse_str(&src).unwrap_or_else(|e| panic!("{}", e.emit_to_string(&src)));
+            valid::Validator::new(valid::ValidationFlags::all(), valid::Capabilities::SUBGROUP)
+                .validate(&module)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an adapter with subgroups"]
+    fn subgroup_reductions_match_the_fallback() {
+        let config = RuntimeConfig::new().subgroups(true);
+        let subgroups = Device::with_config(test_adapter(), &config)
+            .expect("No SUBGROUP support on the test adapter");
+        let config = RuntimeConfig::new().subgroups(false);
+        let fallback = Device::with_config(test_adapter(), &config).unwrap();
+
+        // Several passes, and sizes that leave workgroups and subgroups partly empty
+        for len in [1, 37, 1000, 300_000] {
+            let data: Vec<f32> = (0..len).map(|i| ((i * 13) % 101) as f32 - 60.0).collect();
+            let reduce = |device: &Device| {
+                let t = crate::tensor::Tensor::new_on(device, &[len], &data, false);
+                (t.sum().unwrap().to_vec()[0], t.max().unwrap().to_vec()[0])
+            };
+
+            let (sum, max) = reduce(&subgroups);
+            let (expected_sum, expected_max) = reduce(&fallback);
+            assert_eq!(sum, expected_sum);
+            assert_eq!(max, expected_max);
+            assert_eq!(max, data.iter().copied().fold(f32::MIN, f32::max));
+        }
+    }
 }
@@ -1,11 +1,23 @@
+pub mod adapter;
+pub mod amp;
+pub mod anomaly;
 pub mod autograd;
+pub mod backend;
 pub mod buffer_alloc;
+pub mod capture;
+pub mod fusion;
+pub mod graph;
 pub mod kernel_registry;
+pub mod memory_report;
 pub mod metadata_arena;
 pub mod nn;
 pub mod ops;
+pub mod profiler;
+pub mod quant;
+pub mod readback;
 pub mod runtime;
 pub mod tensor;
+pub mod trace;
 
 pub(crate) trait AsBindingResource {
     fn as_binding_resource(&self) -> wgpu::BindingResource<'_>;
@@ -0,0 +1,423 @@
+use std::{fmt::Write as _, io, path::Path};
+
+use crate::buffer_alloc::{AllocatorEvent, AllocatorEventKind, AllocatorEvents};
+
+const WIDTH: f64 = 960.0;
+const MARGIN_LEFT: f64 = 80.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const LEGEND_HEIGHT: f64 = 30.0;
+// Title above and time axis below each plot
+const PANEL_TOP: f64 = 28.0;
+const PANEL_BOTTOM: f64 = 36.0;
+const PLOT_HEIGHT: f64 = 200.0;
+// Events sharing a column are drawn as the one with the most bytes
+const MAX_COLUMNS: usize = 1000;
+
+const STATES: [(&str, &str); 3] = [
+    ("InUse", "#2563eb"),
+    ("Pending", "#f59e0b"),
+    ("Free", "#16a34a"),
+];
+const STEP_COLOR: &str = "#6b7280";
+const EVICTION_COLOR: &str = "#dc2626";
+
+/// Timeline of the bytes each allocator holds in use, pending and free, stacked, with the step
+/// boundaries and evictions of the event log. Rendered as SVG, or HTML with a summary.
+///
+/// ```ignore
+/// record_allocator_events(true);
+/// for epoch in 0..epochs {
+///     train(&model);
+///     mark_step();
+/// }
+/// memory_report().save("memory.html")?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemoryReport {
+    events: AllocatorEvents,
+}
+
+struct Panel<'a> {
+    title: &'static str,
+    events: &'a [AllocatorEvent],
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+fn states(e: &AllocatorEvent) -> [u64; 3] {
+    [e.in_use_bytes, e.pending_bytes, e.free_bytes]
+}
+
+// Draws a step function through `points`, holding each value until the next point
+fn step_path(points: &[(f64, u64)], y: impl Fn(u64) -> f64) -> Vec<(f64, f64)> {
+    let mut path = Vec::with_capacity(points.len() * 2);
+    for (i, &(x, value)) in points.iter().enumerate() {
+        if i > 0 {
+            path.push((x, y(points[i - 1].1)));
+        }
+        path.push((x, y(value)));
+    }
+
+    path
+}
+
+impl MemoryReport {
+    pub fn new(events: AllocatorEvents) -> Self {
+        Self { events }
+    }
+
+    pub fn events(&self) -> &AllocatorEvents {
+        &self.events
+    }
+
+    fn panels(&self) -> [Panel<'_>; 2] {
+        [
+            Panel {
+                title: "Storage buffers",
+                events: &self.events.storage,
+            },
+            Panel {
+                title: "Readback buffers",
+                events: &self.events.readback,
+            },
+        ]
+    }
+
+    /// The timeline as a standalone SVG image. Both allocators share the time axis.
+    pub fn to_svg(&self) -> String {
+        let panels = self.panels();
+        let times = panels.iter().flat_map(|p| p.events).map(|e| e.time_us);
+        let start = times.clone().min().unwrap_or(0);
+        let span = (times.max().unwrap_or(0) - start).max(1);
+
+        let panel_height = PANEL_TOP + PLOT_HEIGHT + PANEL_BOTTOM;
+        let height = LEGEND_HEIGHT + panels.len() as f64 * panel_height;
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{height}" font-family="sans-serif" font-size="12">"#
+        );
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{WIDTH}" height="{height}" fill="white"/>"#
+        );
+
+        let mut x = MARGIN_LEFT;
+        for (name, color) in STATES
+            .iter()
+            .copied()
+            .chain([("Step", STEP_COLOR), ("Eviction", EVICTION_COLOR)])
+        {
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{x}" y="10" width="12" height="12" fill="{color}"/><text x="{}" y="20">{name}</text>"#,
+                x + 16.0
+            );
+            x += 100.0;
+        }
+
+        for (i, panel) in panels.iter().enumerate() {
+            let top = LEGEND_HEIGHT + i as f64 * panel_height + PANEL_TOP;
+            render_panel(&mut svg, panel, top, start, span);
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// The SVG timeline in an HTML page, after the peak bytes and evictions of each allocator.
+    pub fn to_html(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Memory timeline</title>\n</head>\n<body style=\"font-family: sans-serif\">\n\
+             <h1>Memory timeline</h1>\n<ul>\n",
+        );
+
+        for panel in self.panels() {
+            let peak_in_use = panel.events.iter().map(|e| e.in_use_bytes).max();
+            let peak_total = panel.events.iter().map(|e| states(e).iter().sum()).max();
+            let evictions = panel
+                .events
+                .iter()
+                .filter(|e| matches!(e.kind, AllocatorEventKind::Evict { .. }))
+                .count();
+            let _ = writeln!(
+                html,
+                "<li>{}: {} events, peak {} in use, {} held, {} evictions</li>",
+                panel.title,
+                panel.events.len(),
+                format_bytes(peak_in_use.unwrap_or(0)),
+                format_bytes(peak_total.unwrap_or(0)),
+                evictions
+            );
+        }
+
+        html.push_str("</ul>\n");
+        html.push_str(&self.to_svg());
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    /// Writes the SVG image if `path` ends with `.svg`, the HTML page otherwise.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let contents = if path.extension().is_some_and(|ext| ext == "svg") {
+            self.to_svg()
+        } else {
+            self.to_html()
+        };
+
+        std::fs::write(path, contents)
+    }
+}
+
+fn render_panel(svg: &mut String, panel: &Panel, top: f64, start: u64, span: u64) {
+    let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let bottom = top + PLOT_HEIGHT;
+    let column = |t: u64| ((t - start) as usize * MAX_COLUMNS / span as usize).min(MAX_COLUMNS - 1);
+    let x = |t: u64| MARGIN_LEFT + (t - start) as f64 / span as f64 * plot_width;
+
+    let _ = writeln!(
+        svg,
+        r#"<text x="{MARGIN_LEFT}" y="{}" font-weight="bold">{}</text>"#,
+        top - 8.0,
+        panel.title
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect x="{MARGIN_LEFT}" y="{top}" width="{plot_width}" height="{PLOT_HEIGHT}" fill="none" stroke="{STEP_COLOR}"/>"#
+    );
+    if panel.events.is_empty() {
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle" fill="{STEP_COLOR}">No events recorded</text>"#,
+            MARGIN_LEFT + plot_width / 2.0,
+            top + PLOT_HEIGHT / 2.0
+        );
+        return;
+    }
+
+    // The fullest state of every column, so peaks survive downsampling
+    let mut samples: Vec<(u64, [u64; 3])> = vec![];
+    let mut last_column = None;
+    for e in panel.events {
+        let (c, s) = (column(e.time_us), states(e));
+        if last_column != Some(c) {
+            samples.push((e.time_us, s));
+            last_column = Some(c);
+        } else {
+            let last = samples.last_mut().unwrap();
+            if s.iter().sum::<u64>() > last.1.iter().sum() {
+                *last = (last.0, s);
+            }
+        }
+    }
+
+    let max = samples
+        .iter()
+        .map(|(_, s)| s.iter().sum::<u64>())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let y = |bytes: u64| bottom - bytes as f64 / max as f64 * PLOT_HEIGHT;
+
+    for i in 0..=4 {
+        let bytes = max * i / 4;
+        let _ = writeln!(
+            svg,
+            r##"<line x1="{MARGIN_LEFT}" y1="{0}" x2="{1}" y2="{0}" stroke="#e5e7eb"/><text x="{2}" y="{3}" text-anchor="end">{4}</text>"##,
+            y(bytes),
+            MARGIN_LEFT + plot_width,
+            MARGIN_LEFT - 6.0,
+            y(bytes) + 4.0,
+            format_bytes(bytes)
+        );
+    }
+    for i in 0..=4 {
+        let t = start + span * i / 4;
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">{:.1} ms</text>"#,
+            x(t),
+            bottom + 16.0,
+            t as f64 / 1000.0
+        );
+    }
+
+    // Layers stacked from the bottom, each between its total and the one below
+    let mut below: Vec<(f64, u64)> = samples.iter().map(|(t, _)| (x(*t), 0)).collect();
+    for (layer, (_, color)) in STATES.iter().enumerate() {
+        let above: Vec<(f64, u64)> = samples
+            .iter()
+            .map(|(t, s)| (x(*t), s[..=layer].iter().sum()))
+            .collect();
+
+        let mut outline = step_path(&above, y);
+        outline.extend(step_path(&below, y).into_iter().rev());
+        let points: Vec<String> = outline
+            .iter()
+            .map(|(px, py)| format!("{px:.1},{py:.1}"))
+            .collect();
+        let _ = writeln!(
+            svg,
+            r#"<polygon points="{}" fill="{color}" fill-opacity="0.85"/>"#,
+            points.join(" ")
+        );
+
+        below = above;
+    }
+
+    // Labels closer than this to the previous one are skipped
+    let mut next_label = f64::MIN;
+    for e in panel.events {
+        if let AllocatorEventKind::Step { step } = e.kind {
+            let px = x(e.time_us);
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{px:.1}" y1="{top}" x2="{px:.1}" y2="{bottom}" stroke="{STEP_COLOR}" stroke-dasharray="4 3"/>"#
+            );
+            if px >= next_label {
+                let _ = writeln!(
+                    svg,
+                    r#"<text x="{:.1}" y="{}" fill="{STEP_COLOR}">step {step}</text>"#,
+                    px + 3.0,
+                    top + 12.0
+                );
+                next_label = px + 48.0;
+            }
+        }
+    }
+
+    // One marker per column, summing its evictions
+    let mut evictions: Vec<(u64, usize, u64)> = vec![];
+    for e in panel.events {
+        if let AllocatorEventKind::Evict { capacity, .. } = e.kind {
+            match evictions.last_mut() {
+                Some((t, count, bytes)) if column(*t) == column(e.time_us) => {
+                    *count += 1;
+                    *bytes += capacity;
+                }
+                _ => evictions.push((e.time_us, 1, capacity)),
+            }
+        }
+    }
+    for (t, count, bytes) in evictions {
+        let px = x(t);
+        let _ = writeln!(
+            svg,
+            r#"<path d="M{:.1},{} l-5,-8 h10 z" fill="{EVICTION_COLOR}"><title>{count} evicted, {}</title></path>"#,
+            px,
+            bottom,
+            format_bytes(bytes)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_alloc::EvictionCause;
+
+    fn event(time_us: u64, kind: AllocatorEventKind, states: [u64; 3]) -> AllocatorEvent {
+        AllocatorEvent {
+            time_us,
+            clock: 0,
+            kind,
+            in_use_bytes: states[0],
+            pending_bytes: states[1],
+            free_bytes: states[2],
+        }
+    }
+
+    #[test]
+    fn formats_bytes_with_binary_units() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn renders_stacked_states_steps_and_evictions() {
+        let evict = AllocatorEventKind::Evict {
+            capacity: 2048,
+            cause: EvictionCause::CacheLimit,
+        };
+        let storage = vec![
+            event(
+                0,
+                AllocatorEventKind::Allocate {
+                    size: 4096,
+                    capacity: 4096,
+                },
+                [4096, 0, 0],
+            ),
+            event(
+                10,
+                AllocatorEventKind::Free { capacity: 4096 },
+                [0, 4096, 0],
+            ),
+            event(20, AllocatorEventKind::Step { step: 0 }, [0, 4096, 0]),
+            event(
+                30,
+                AllocatorEventKind::Reclaim { bytes: 4096 },
+                [0, 0, 4096],
+            ),
+            // Same column, one marker
+            event(40, evict, [0, 0, 2048]),
+            event(40, evict, [0, 0, 0]),
+        ];
+        let report = MemoryReport::new(AllocatorEvents {
+            storage,
+            readback: vec![],
+        });
+
+        let svg = report.to_svg();
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<polygon").count(), 3);
+        assert_eq!(svg.matches("step 0").count(), 1);
+        assert_eq!(svg.matches("<title>2 evicted, 4.0 KiB</title>").count(), 1);
+        assert!(svg.contains("No events recorded"));
+
+        let html = report.to_html();
+        assert!(
+            html.contains(
+                "Storage buffers: 6 events, peak 4.0 KiB in use, 4.0 KiB held, 2 evictions"
+            )
+        );
+        assert!(html.contains(&svg));
+    }
+
+    #[test]
+    fn long_logs_are_downsampled_keeping_peaks() {
+        let storage: Vec<_> = (0..100_000)
+            .map(|i| {
+                let bytes = if i == 50_001 { 1 << 20 } else { 1024 };
+                event(i, AllocatorEventKind::Free { capacity: 4 }, [bytes, 0, 0])
+            })
+            .collect();
+        let report = MemoryReport::new(AllocatorEvents {
+            storage,
+            readback: vec![],
+        });
+
+        let svg = report.to_svg();
+        let polygon = svg.lines().find(|l| l.starts_with("<polygon")).unwrap();
+        assert!(polygon.matches(',').count() <= 4 * MAX_COLUMNS);
+        // The peak sets the scale of the axis
+        assert!(svg.contains(">1.0 MiB</text>"));
+    }
+}
@@ -1,5 +1,6 @@
 use std::num::NonZeroU64;
 
+use serde::Serialize;
 use wgpu::{BufferUsages, wgt::BufferDescriptor};
 
 use crate::{AsBindingResource, runtime::WGPUContext};
@@ -17,18 +18,30 @@ pub struct MetadataArena {
     cursor: ArenaCursor,
     capacity: u64,
     alignment: u64,
+    // Most bytes used between two resets
+    peak_used: u64,
 }
 
-pub struct MetdataHandle<'a> {
+/// Page usage of the arena holding op metadata, rewound after every step.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MetadataArenaStats {
+    pub pages: usize,
+    pub page_size: u64,
+    /// Bytes used since the last rewind, counting the unused ends of filled pages.
+    pub used_bytes: u64,
+    pub peak_used_bytes: u64,
+}
+
+pub struct MetdataHandle {
     pub(crate) offset: u64,
     pub(crate) size: u64,
-    pub(crate) buf: &'a wgpu::Buffer,
+    pub(crate) buf: wgpu::Buffer,
 }
 
-impl<'a> AsBindingResource for MetdataHandle<'a> {
+impl AsBindingResource for MetdataHandle {
     fn as_binding_resource(&self) -> wgpu::BindingResource<'_> {
         wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-            buffer: self.buf,
+            buffer: &self.buf,
             offset: self.offset,
             size: Some(NonZeroU64::new(self.size).unwrap()),
         })
@@ -46,14 +59,18 @@ fn align_up(x: u64, a: u64) -> u64 {
 
 impl MetadataArena {
     pub fn new(ctx: WGPUContext, capacity: u64) -> Self {
-        let alignment = ctx.device.limits().min_storage_buffer_offset_alignment as u64;
+        // Slices are bound as storage or uniform buffers
+        let limits = ctx.device.limits();
+        let alignment = limits
+            .min_storage_buffer_offset_alignment
+            .max(limits.min_uniform_buffer_offset_alignment) as u64;
         let capacity = align_up(capacity, alignment);
 
         let buf = ctx.device.create_buffer(&BufferDescriptor {
             label: Some("metadata_arena 0"),
             mapped_at_creation: false,
             size: capacity,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            usage: BufferUsages::STORAGE | BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
 
         Self {
@@ -62,10 +79,11 @@ impl MetadataArena {
             cursor: ArenaCursor { page: 0, offset: 0 },
             capacity,
             alignment,
+            peak_used: 0,
         }
     }
 
-    pub fn allocate<'a>(&'a mut self, bytes: &[u8]) -> Result<MetdataHandle<'a>, AllocError> {
+    pub fn allocate(&mut self, bytes: &[u8]) -> Result<MetdataHandle, AllocError> {
         if align_up(bytes.len() as u64, self.alignment) > self.capacity {
             return Err(AllocError::RequestedSizeBiggerThanPageSize);
         }
@@ -86,7 +104,9 @@ impl MetadataArena {
                         label: Some(label.as_str()),
                         mapped_at_creation: false,
                         size: self.capacity,
-                        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                        usage: BufferUsages::STORAGE
+                            | BufferUsages::UNIFORM
+                            | BufferUsages::COPY_DST,
                     }));
             }
 
@@ -98,17 +118,31 @@ impl MetadataArena {
             .queue
             .write_buffer(&self.pages[self.cursor.page], start, bytes);
         self.cursor.offset = end;
+        self.peak_used = self.peak_used.max(self.used());
 
         Ok(MetdataHandle {
             offset: start,
             size: bytes.len() as u64,
-            buf: &self.pages[self.cursor.page],
+            buf: self.pages[self.cursor.page].clone(),
         })
     }
 
     pub fn reset(&mut self) {
         self.cursor = ArenaCursor { page: 0, offset: 0 };
     }
+
+    fn used(&self) -> u64 {
+        self.cursor.page as u64 * self.capacity + self.cursor.offset
+    }
+
+    pub fn stats(&self) -> MetadataArenaStats {
+        MetadataArenaStats {
+            pages: self.pages.len(),
+            page_size: self.capacity,
+            used_bytes: self.used(),
+            peak_used_bytes: self.peak_used,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -222,6 +256,10 @@ mod tests {
         assert_eq!(arena.pages.len(), 2);
         assert_eq!(arena.cursor.page, 0);
         assert_eq!(arena.cursor.offset, 0);
+
+        let stats = arena.stats();
+        assert_eq!((stats.pages, stats.used_bytes), (2, 0));
+        assert_eq!(stats.peak_used_bytes, arena.capacity + arena.alignment);
     }
 
     #[test]
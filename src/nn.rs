@@ -1,8 +1,6 @@
-use std::sync::atomic::AtomicU32;
-
 use crate::{
     ops::{self, TensorOpError},
-    runtime::{no_grad, rt},
+    runtime::{Device, default_device, no_grad},
     tensor::Tensor,
 };
 
@@ -10,29 +8,43 @@ pub trait Model {
     fn params(&self) -> Vec<Tensor>;
 }
 
-static LAYER_COUNTER: AtomicU32 = AtomicU32::new(1);
-
-pub(crate) fn get_layer_count() -> u32 {
-    LAYER_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
-}
-
 pub struct Linear {
     pub(crate) weights: Tensor,
     pub(crate) bias: Option<Tensor>,
 }
 
 impl Linear {
+    /// Creates a layer on the default device.
     pub fn new(in_dim: usize, out_dim: usize, bias: bool) -> Self {
-        let (seed, _) = rt().seed.overflowing_mul(get_layer_count());
-        let weights = ops::he_init(seed, in_dim as u32, &[in_dim, out_dim], true);
+        Self::new_on(&default_device(), in_dim, out_dim, bias)
+    }
+
+    /// Panics when the weights don't fit in a buffer of `device`, see `try_new_on()`.
+    pub fn new_on(device: &Device, in_dim: usize, out_dim: usize, bias: bool) -> Self {
+        Self::try_new_on(device, in_dim, out_dim, bias).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new_on(
+        device: &Device,
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+    ) -> Result<Self, TensorOpError> {
+        let seed = device.rt().next_seed();
+        let weights = ops::he_init(device, seed, in_dim as u32, &[in_dim, out_dim], true)?;
 
         let bias = if bias {
-            Some(Tensor::new(&[out_dim], &vec![0.0; out_dim], true))
+            Some(Tensor::try_new_on(
+                device,
+                &[out_dim],
+                &vec![0.0; out_dim],
+                true,
+            )?)
         } else {
             None
         };
 
-        Self { weights, bias }
+        Ok(Self { weights, bias })
     }
 
     pub fn forward(&self, input: &Tensor) -> Result<Tensor, TensorOpError> {
@@ -42,7 +54,7 @@ impl Linear {
             (None, _) => Ok(out),
             (Some(b), [_]) => out.add(b),
             (Some(b), [batch, _]) => {
-                let ones = Tensor::ones(&[*batch], false);
+                let ones = Tensor::ones_on(input.device(), &[*batch], false);
                 let bias_rows = ones.outer(b)?;
                 out.add(&bias_rows)
             }
@@ -64,18 +76,32 @@ impl Model for Linear {
 }
 
 pub struct MLP {
-    layers: Vec<Linear>,
+    pub(crate) layers: Vec<Linear>,
 }
 
 impl MLP {
+    /// Creates a model on the default device.
     pub fn new(features: &[usize], bias: bool) -> Self {
+        Self::new_on(&default_device(), features, bias)
+    }
+
+    /// Panics when a layer doesn't fit in a buffer of `device`, see `try_new_on()`.
+    pub fn new_on(device: &Device, features: &[usize], bias: bool) -> Self {
+        Self::try_new_on(device, features, bias).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new_on(
+        device: &Device,
+        features: &[usize],
+        bias: bool,
+    ) -> Result<Self, TensorOpError> {
         let mut layers = vec![];
 
         for pair in features.windows(2) {
-            layers.push(Linear::new(pair[0], pair[1], bias));
+            layers.push(Linear::try_new_on(device, pair[0], pair[1], bias)?);
         }
 
-        Self { layers }
+        Ok(Self { layers })
     }
 
     pub fn forward(&self, input: &Tensor) -> Result<Tensor, TensorOpError> {
@@ -120,12 +146,12 @@ impl Adam {
 
         let m: Vec<Tensor> = params
             .iter()
-            .map(|p| Tensor::new(p.shape(), &vec![0.0; p.numel()], false))
+            .map(|p| Tensor::new_on(p.device(), p.shape(), &vec![0.0; p.numel()], false))
             .collect();
 
         let v: Vec<Tensor> = params
             .iter()
-            .map(|p| Tensor::new(p.shape(), &vec![0.0; p.numel()], false))
+            .map(|p| Tensor::new_on(p.device(), p.shape(), &vec![0.0; p.numel()], false))
             .collect();
 
         Self {
@@ -147,7 +173,26 @@ impl Adam {
     }
 
     pub fn step(&mut self) {
-        let _ng = no_grad().unwrap();
+        self.step_with_grad_scale(1.0);
+    }
+
+    // Whether no gradient holds an infinite or NaN value, checked by `GradScaler` before stepping
+    pub(crate) fn grads_finite(&self) -> bool {
+        let grads: Vec<Tensor> = self.params.iter().filter_map(|p| p.grad()).collect();
+        let Some(first) = grads.first() else {
+            return true;
+        };
+
+        let storages: Vec<_> = grads.iter().map(|g| g.buf()).collect();
+        let flags = first.rt().backend.non_finite_flags(&storages);
+        first.rt().cleanup();
+        flags == 0
+    }
+
+    // Steps with the gradients multiplied by `grad_scale`, which undoes the loss scaling of
+    // `GradScaler` inside the fused kernels
+    pub(crate) fn step_with_grad_scale(&mut self, grad_scale: f32) {
+        let _ng = no_grad();
 
         self.t += 1;
 
@@ -162,30 +207,28 @@ impl Adam {
                 continue;
             }
             let grad = grad.unwrap();
-
-            *m = m
-                .mul_s(self.b1)
-                .unwrap()
-                .add(&grad.mul_s(1.0 - self.b1).unwrap())
+            let g = || {
+                if grad_scale == 1.0 {
+                    grad.fuse()
+                } else {
+                    grad.fuse().mul_s(grad_scale)
+                }
+            };
+
+            // One fused kernel per moment and one for the parameter
+            *m = (m.fuse().mul_s(self.b1) + g().mul_s(1.0 - self.b1))
+                .eval()
                 .unwrap();
 
-            let m_hat = m.mul_s(1.0 / (1.0 - self.b1.powi(self.t))).unwrap();
-
-            *v = v
-                .mul_s(self.b2)
-                .unwrap()
-                .add(&grad.mul(&grad).unwrap().mul_s(1.0 - self.b2).unwrap())
+            *v = (v.fuse().mul_s(self.b2) + (g() * g()).mul_s(1.0 - self.b2))
+                .eval()
                 .unwrap();
 
-            let v_hat = v.mul_s(1.0 / (1.0 - self.b2.powi(self.t))).unwrap();
-
-            let update = m_hat
-                .div(&v_hat.sqrt().unwrap().add_s(self.eps).unwrap())
-                .unwrap()
-                .mul_s(-1.0 * self.lr)
-                .unwrap();
+            let m_hat = m.fuse().mul_s(1.0 / (1.0 - self.b1.powi(self.t)));
+            let v_hat = v.fuse().mul_s(1.0 / (1.0 - self.b2.powi(self.t)));
+            let update = (m_hat / v_hat.sqrt().add_s(self.eps)).mul_s(-self.lr);
 
-            p.assign(&p.add(&update).unwrap());
+            p.assign(&(p.fuse() + update).eval().unwrap());
         }
     }
 }
@@ -1,13 +1,15 @@
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
 use crate::{
-    AsBindingResource,
+    amp,
+    anomaly::{self, AnomalyReport},
     autograd::{GradNode, GradNodeMeta},
-    kernel_registry::KernelKey,
-    runtime::{do_grad, rt},
-    tensor::{DTYPE_SIZE, Tensor, TensorInner, get_tensor_id},
+    backend::TensorStorage,
+    fusion, profiler,
+    runtime::{Device, is_grad_enabled},
+    tensor::{DTYPE_SIZE, Tensor, TensorInner, get_tensor_id, is_inference_tensor},
+    trace::{self, TracedOp},
 };
-use bytemuck::{Pod, Zeroable};
 use strum_macros::AsRefStr;
 
 #[derive(Debug, Eq, Hash, PartialEq, Clone, AsRefStr)]
@@ -20,6 +22,9 @@ pub enum OpType {
     ScalarEwize(ScalarEwizeType),
     Outer,
     CrossEntropyLoss,
+    Checkpoint,
+    FusedEwize,
+    Int8Matmul,
 }
 
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
@@ -54,65 +59,115 @@ pub enum TensorOpError {
     MismatchedShapes,
     NonMatrixTensor,
     EmptyTensor,
+    DeviceMismatch,
+    Anomaly(Box<AnomalyReport>),
+    /// The op was called inside `trace()` but can't be recorded.
+    NotTraceable(OpType),
+    /// A tensor of the op is larger than the device can store, or than it can bind when the
+    /// kernel needs the tensor at once.
+    TooLarge {
+        bytes: u64,
+        max: u64,
+    },
+}
+
+impl fmt::Display for TensorOpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TensorOpError::MismatchedShapes => write!(f, "mismatched tensor shapes"),
+            TensorOpError::NonMatrixTensor => write!(f, "expected a matrix tensor"),
+            TensorOpError::EmptyTensor => write!(f, "empty tensor"),
+            TensorOpError::DeviceMismatch => write!(f, "tensors are on different devices"),
+            TensorOpError::Anomaly(report) => write!(f, "anomaly detected: {}", report),
+            TensorOpError::NotTraceable(op) => write!(f, "{:?} can't be traced", op),
+            TensorOpError::TooLarge { bytes, max } => write!(
+                f,
+                "tensor of {} bytes is larger than the {} bytes the device supports",
+                bytes, max
+            ),
+        }
+    }
 }
 
-fn should_grad(grads: &[bool]) -> bool {
-    grads.iter().any(|&v| v) && do_grad()
-}
+impl std::error::Error for TensorOpError {}
 
-pub fn mul_scalar(t: &Tensor, s: f32) -> Result<Tensor, TensorOpError> {
-    dispatch_scalar_ewize(t, ScalarEwizeType::Mul, s)
-}
+// Device shared by all inputs of an op
+pub(crate) fn same_device(inputs: &[&Tensor]) -> Result<Device, TensorOpError> {
+    let device = inputs[0].device();
+    if inputs.iter().any(|t| t.device() != device) {
+        return Err(TensorOpError::DeviceMismatch);
+    }
 
-pub fn add_scalar(t: &Tensor, s: f32) -> Result<Tensor, TensorOpError> {
-    dispatch_scalar_ewize(t, ScalarEwizeType::Add, s)
+    Ok(device.clone())
+}
+
+// Fails before anything runs when the output of an op can't be allocated, when one of the
+// `whole` tensors, with these numbers of elements, can't be bound at once, or when the `rows`
+// rows of the matrices the kernel walks in windows, with rows of `widths` elements, can't be
+// split into windows that fit a binding
+pub(crate) fn check_size(
+    device: &Device,
+    out_numel: usize,
+    whole: &[usize],
+    rows: usize,
+    widths: &[usize],
+) -> Result<(), TensorOpError> {
+    let backend = &device.rt().backend;
+    let bytes = |numel: usize| (numel * DTYPE_SIZE) as u64;
+
+    let max = backend.max_buffer_bytes();
+    if bytes(out_numel) > max {
+        return Err(TensorOpError::TooLarge {
+            bytes: bytes(out_numel),
+            max,
+        });
+    }
+
+    let max = backend.max_binding_bytes();
+    if let Some(&numel) = whole.iter().find(|&&numel| bytes(numel) > max) {
+        return Err(TensorOpError::TooLarge {
+            bytes: bytes(numel),
+            max,
+        });
+    }
+    if rows > 0 && backend.window_rows(rows, widths) == 0 {
+        let widest = widths.iter().max().copied().unwrap_or(0);
+        return Err(TensorOpError::TooLarge {
+            bytes: bytes(rows * widest),
+            max,
+        });
+    }
+
+    Ok(())
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable)]
-struct ScalarMeta {
-    s: f32,
+pub(crate) fn should_grad(inputs: &[&Tensor]) -> bool {
+    inputs.iter().any(|t| t.requires_grad()) && is_grad_enabled()
 }
 
-pub fn dispatch_scalar_ewize(
-    t: &Tensor,
-    typ: ScalarEwizeType,
-    s: f32,
+// Wraps the storage computed by `op` into a tensor, checking it for anomalies and recording the
+// graph node if needed
+pub(crate) fn op_output(
+    device: Device,
+    op: OpType,
+    inputs: &[&Tensor],
+    meta: Option<GradNodeMeta>,
+    buf: TensorStorage,
+    shape: Vec<usize>,
 ) -> Result<Tensor, TensorOpError> {
-    let op = OpType::ScalarEwize(typ);
-    let rt = rt();
-
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let out_buf = rt.storage_buffer_alloc.request(t.bsize() as u64);
-
-    let mut ma = rt.metadata_arena.lock().unwrap();
-    let meta = ma.allocate(&bytemuck::bytes_of(&ScalarMeta { s })).unwrap();
-
-    let bg = create_bg(
-        op.as_ref(),
-        &[t, &out_buf, &meta],
-        kernel.bind_group_layout(),
-    );
-
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        ((t.numel().div_ceil(64) as u32).min(65535), 1, 1),
-    );
-
-    let requires_grad = should_grad(&[t.requires_grad()]);
+    anomaly::check_output(device.rt(), &op, inputs, &buf)?;
+    if profiler::is_profiling() {
+        let input_shapes: Vec<&[usize]> = inputs.iter().map(|t| t.shape()).collect();
+        profiler::set_shapes(&input_shapes, &shape);
+    }
+
+    let requires_grad = should_grad(inputs);
     let grad_node = if requires_grad {
-        Some(GradNode {
-            op: op.clone(),
-            parents: vec![t.clone()],
-            meta: Some(GradNodeMeta::Scalar(s)),
-        })
+        Some(GradNode::new(
+            op,
+            inputs.iter().map(|&t| t.clone()).collect(),
+            meta,
+        ))
     } else {
         None
     };
@@ -120,14 +175,53 @@ pub fn dispatch_scalar_ewize(
     Ok(Tensor {
         inner: Arc::new(TensorInner {
             id: get_tensor_id(),
-            buf: out_buf,
-            shape: t.shape().to_vec(),
+            device,
+            inference: is_inference_tensor(false),
+            buf: Arc::new(buf).into(),
+            pending: Default::default(),
+            shape,
             requires_grad,
             grad_node,
         }),
     })
 }
 
+pub fn mul_scalar(t: &Tensor, s: f32) -> Result<Tensor, TensorOpError> {
+    dispatch_scalar_ewize(t, ScalarEwizeType::Mul, s)
+}
+
+pub fn add_scalar(t: &Tensor, s: f32) -> Result<Tensor, TensorOpError> {
+    dispatch_scalar_ewize(t, ScalarEwizeType::Add, s)
+}
+
+pub fn dispatch_scalar_ewize(
+    t: &Tensor,
+    typ: ScalarEwizeType,
+    s: f32,
+) -> Result<Tensor, TensorOpError> {
+    let device = same_device(&[t])?;
+    if trace::is_tracing() {
+        return Ok(trace::record(
+            TracedOp::ScalarEwize(typ, s),
+            &[t],
+            t.shape(),
+        ));
+    }
+    if fusion::can_defer(&device, &[t]) {
+        return Ok(fusion::defer_scalar(t, typ, s));
+    }
+    let buf = device.rt().backend.scalar_ewize(&typ, t.buf(), s);
+
+    op_output(
+        device,
+        OpType::ScalarEwize(typ),
+        &[t],
+        Some(GradNodeMeta::Scalar(s)),
+        buf,
+        t.shape().to_vec(),
+    )
+}
+
 pub fn relu(t: &Tensor) -> Result<Tensor, TensorOpError> {
     dispatch_unop_ewize(t, UnopEwizeType::Relu)
 }
@@ -137,46 +231,23 @@ pub fn sqrt(t: &Tensor) -> Result<Tensor, TensorOpError> {
 }
 
 pub fn dispatch_unop_ewize(t: &Tensor, typ: UnopEwizeType) -> Result<Tensor, TensorOpError> {
-    let op = OpType::UnopEwizeType(typ);
-    let rt = rt();
-
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let out_buf = rt.storage_buffer_alloc.request(t.bsize() as u64);
-
-    let bg = create_bg(op.as_ref(), &[t, &out_buf], kernel.bind_group_layout());
-
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        ((t.numel().div_ceil(64) as u32).min(65535), 1, 1),
-    );
-
-    let requires_grad = should_grad(&[t.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op: op.clone(),
-            parents: vec![t.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
+    let device = same_device(&[t])?;
+    if trace::is_tracing() {
+        return Ok(trace::record(TracedOp::UnopEwize(typ), &[t], t.shape()));
+    }
+    if fusion::can_defer(&device, &[t]) {
+        return Ok(fusion::defer_unop(t, typ));
+    }
+    let buf = device.rt().backend.unop_ewize(&typ, t.buf());
 
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: out_buf,
-            shape: t.shape().to_vec(),
-            requires_grad,
-            grad_node,
-        }),
-    })
+    op_output(
+        device,
+        OpType::UnopEwizeType(typ),
+        &[t],
+        None,
+        buf,
+        t.shape().to_vec(),
+    )
 }
 
 pub fn add(lhs: &Tensor, rhs: &Tensor) -> Result<Tensor, TensorOpError> {
@@ -305,36 +376,26 @@ pub(crate) fn cross_entropy_loss_backward_targets(
 
 pub fn cross_entropy_loss(logits: &Tensor, targets: &Tensor) -> Result<Tensor, TensorOpError> {
     let (batch, classes) = validate_cross_entropy_shapes(logits, targets)?;
+    let device = same_device(&[logits, targets])?;
+    // The loss is computed on the host
+    if trace::is_tracing() {
+        return Err(TensorOpError::NotTraceable(OpType::CrossEntropyLoss));
+    }
 
     let logits_data = logits.readback();
     let targets_data = targets.readback();
     let loss = cross_entropy_loss_forward(&logits_data, &targets_data, batch, classes);
 
-    let rt = rt();
-    let out_buf = rt.storage_buffer_alloc.request(DTYPE_SIZE as u64);
-    out_buf.set(bytemuck::cast_slice(&[loss]));
-
-    let op = OpType::CrossEntropyLoss;
-    let requires_grad = should_grad(&[logits.requires_grad(), targets.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op,
-            parents: vec![logits.clone(), targets.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
+    let buf = device.rt().backend.upload(&[loss]);
 
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: out_buf,
-            shape: vec![1],
-            requires_grad,
-            grad_node,
-        }),
-    })
+    op_output(
+        device,
+        OpType::CrossEntropyLoss,
+        &[logits, targets],
+        None,
+        buf,
+        vec![1],
+    )
 }
 
 pub fn dispatch_binop_ewize(
@@ -345,51 +406,28 @@ pub fn dispatch_binop_ewize(
     if lhs.shape() != rhs.shape() {
         return Err(TensorOpError::MismatchedShapes);
     }
-    let op = OpType::BinopEwizeType(typ);
-
-    let rt = rt();
-
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let out_buf = rt.storage_buffer_alloc.request(lhs.bsize() as u64);
-
-    let bg = create_bg(
-        op.as_ref(),
-        &[lhs, rhs, &out_buf],
-        kernel.bind_group_layout(),
-    );
 
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        ((lhs.numel().div_ceil(64) as u32).min(65535), 1, 1),
-    );
-
-    let requires_grad = should_grad(&[lhs.requires_grad(), rhs.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op,
-            parents: vec![lhs.clone(), rhs.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
+    let device = same_device(&[lhs, rhs])?;
+    if trace::is_tracing() {
+        return Ok(trace::record(
+            TracedOp::BinopEwize(typ),
+            &[lhs, rhs],
+            lhs.shape(),
+        ));
+    }
+    if fusion::can_defer(&device, &[lhs, rhs]) {
+        return Ok(fusion::defer_binop(lhs, rhs, typ));
+    }
+    let buf = device.rt().backend.binop_ewize(&typ, lhs.buf(), rhs.buf());
 
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: out_buf,
-            shape: lhs.shape().to_vec(),
-            requires_grad: requires_grad,
-            grad_node: grad_node,
-        }),
-    })
+    op_output(
+        device,
+        OpType::BinopEwizeType(typ),
+        &[lhs, rhs],
+        None,
+        buf,
+        lhs.shape().to_vec(),
+    )
 }
 
 pub fn sum(t: &Tensor) -> Result<Tensor, TensorOpError> {
@@ -405,81 +443,13 @@ pub fn reduce(t: &Tensor, typ: ReduceOpType) -> Result<Tensor, TensorOpError> {
         return Err(TensorOpError::EmptyTensor);
     }
 
-    let op = OpType::Reduce(typ);
-
-    let rt = rt();
-
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let mut output_size = t.numel().div_ceil(256).min(65535);
-    let mut inp_buf = rt
-        .storage_buffer_alloc
-        .request((output_size * DTYPE_SIZE) as u64);
-    let mut out_buf;
-
-    let bg = create_bg(op.as_ref(), &[t, &inp_buf], kernel.bind_group_layout());
-
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        (output_size as u32, 1, 1),
-    );
-
-    while output_size > 1 {
-        output_size = output_size.div_ceil(256).min(65535);
-        out_buf = rt
-            .storage_buffer_alloc
-            .request((output_size * DTYPE_SIZE) as u64);
-
-        let bg = create_bg(
-            op.as_ref(),
-            &[&inp_buf, &out_buf],
-            kernel.bind_group_layout(),
-        );
-
-        dispatch_pass(
-            op.as_ref(),
-            kernel.pipeline(),
-            &bg,
-            (output_size as u32, 1, 1),
-        );
-
-        inp_buf = out_buf;
+    let device = same_device(&[t])?;
+    if trace::is_tracing() {
+        return Ok(trace::record(TracedOp::Reduce(typ), &[t], &[1]));
     }
+    let buf = device.rt().backend.reduce(&typ, t.buf());
 
-    let requires_grad = should_grad(&[t.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op,
-            parents: vec![t.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
-
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: inp_buf,
-            shape: vec![1],
-            requires_grad: requires_grad,
-            grad_node: grad_node,
-        }),
-    })
-}
-
-#[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable)]
-struct MatmulMeta {
-    m: u32,
-    n: u32,
-    k: u32,
+    op_output(device, OpType::Reduce(typ), &[t], None, buf, vec![1])
 }
 
 enum CollapseDim {
@@ -492,8 +462,8 @@ enum CollapseDim {
 fn to_matrix_shape(
     shape1: &[usize],
     shape2: &[usize],
-) -> Result<(u32, u32, u32, CollapseDim), TensorOpError> {
-    if shape1.len() == 0 || shape2.len() == 0 {
+) -> Result<(usize, usize, usize, CollapseDim), TensorOpError> {
+    if shape1.is_empty() || shape2.is_empty() {
         return Err(TensorOpError::EmptyTensor);
     }
 
@@ -502,10 +472,10 @@ fn to_matrix_shape(
     }
 
     match (shape1, shape2) {
-        ([m, k1], [k2, n]) if k1 == k2 => Ok((*m as u32, *n as u32, *k1 as u32, CollapseDim::None)),
-        ([m, k1], [k2]) if k1 == k2 => Ok((*m as u32, 1, *k1 as u32, CollapseDim::N)),
-        ([k1], [k2, n]) if k1 == k2 => Ok((1, *n as u32, *k1 as u32, CollapseDim::M)),
-        ([k1], [k2]) if k1 == k2 => Ok((1, 1, *k1 as u32, CollapseDim::Both)),
+        ([m, k1], [k2, n]) if k1 == k2 => Ok((*m, *n, *k1, CollapseDim::None)),
+        ([m, k1], [k2]) if k1 == k2 => Ok((*m, 1, *k1, CollapseDim::N)),
+        ([k1], [k2, n]) if k1 == k2 => Ok((1, *n, *k1, CollapseDim::M)),
+        ([k1], [k2]) if k1 == k2 => Ok((1, 1, *k1, CollapseDim::Both)),
         _ => Err(TensorOpError::MismatchedShapes),
     }
 }
@@ -514,305 +484,104 @@ pub fn matmul(lhs: &Tensor, rhs: &Tensor) -> Result<Tensor, TensorOpError> {
     let (m, n, k, col) = to_matrix_shape(lhs.shape(), rhs.shape())?;
 
     let out_shape = match col {
-        CollapseDim::None => vec![m as usize, n as usize],
-        CollapseDim::N => vec![m as usize],
-        CollapseDim::M => vec![n as usize],
+        CollapseDim::None => vec![m, n],
+        CollapseDim::N => vec![m],
+        CollapseDim::M => vec![n],
         CollapseDim::Both => vec![1],
     };
-    let bsize = m * n * (DTYPE_SIZE as u32);
-
-    let op = OpType::Matmul;
-
-    let rt = rt();
-
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let out_buf = rt.storage_buffer_alloc.request(bsize as u64);
-
-    let mut ma = rt.metadata_arena.lock().unwrap();
-    let meta = ma
-        .allocate(bytemuck::bytes_of(&MatmulMeta { m, n, k }))
-        .unwrap();
-
-    let bg = create_bg(
-        op.as_ref(),
-        &[lhs, rhs, &out_buf, &meta],
-        kernel.bind_group_layout(),
-    );
-
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        (n.div_ceil(32), m.div_ceil(32), 1),
-    );
-
-    let requires_grad = should_grad(&[lhs.requires_grad(), rhs.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op: op,
-            parents: vec![lhs.clone(), rhs.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
 
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: out_buf,
-            shape: out_shape,
-            requires_grad,
-            grad_node,
-        }),
-    })
-}
+    let device = same_device(&[lhs, rhs])?;
+    // Rows of the lhs and output are bound in windows
+    check_size(&device, m * n, &[k * n], m, &[k, n])?;
+    let precision = amp::matmul_precision(&device);
+    if trace::is_tracing() {
+        return Ok(trace::record(
+            TracedOp::Matmul { m, n, k, precision },
+            &[lhs, rhs],
+            &out_shape,
+        ));
+    }
+    let buf = device
+        .rt()
+        .backend
+        .matmul(lhs.buf(), rhs.buf(), m, n, k, precision);
 
-#[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable)]
-struct MatrixMeta {
-    m: u32,
-    n: u32,
+    op_output(device, OpType::Matmul, &[lhs, rhs], None, buf, out_shape)
 }
 
 pub fn transposed(t: &Tensor) -> Result<Tensor, TensorOpError> {
     if t.shape().len() != 2 {
         return Err(TensorOpError::NonMatrixTensor);
     }
-    let (m, n) = (t.shape()[0] as u32, t.shape()[1] as u32);
-
-    let op = OpType::Transpose;
-
-    let rt = rt();
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let out_buf = rt.storage_buffer_alloc.request(t.bsize() as u64);
-
-    let mut ma = rt.metadata_arena.lock().unwrap();
-    let meta = ma
-        .allocate(bytemuck::bytes_of(&MatrixMeta { m, n }))
-        .unwrap();
+    let (m, n) = (t.shape()[0], t.shape()[1]);
 
-    let bg = create_bg(
-        op.as_ref(),
-        &[t, &out_buf, &meta],
-        kernel.bind_group_layout(),
-    );
-
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        (t.numel().div_ceil(64) as u32, 1, 1),
-    );
-
-    let requires_grad = should_grad(&[t.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op: op,
-            parents: vec![t.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
+    let device = same_device(&[t])?;
+    check_size(&device, m * n, &[m * n], 0, &[])?;
+    if trace::is_tracing() {
+        return Ok(trace::record(TracedOp::Transpose { m, n }, &[t], &[n, m]));
+    }
+    let buf = device.rt().backend.transpose(t.buf(), m, n);
 
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: out_buf,
-            shape: vec![n as usize, m as usize],
-            requires_grad,
-            grad_node,
-        }),
-    })
+    op_output(device, OpType::Transpose, &[t], None, buf, vec![n, m])
 }
 
 pub fn outer(lhs: &Tensor, rhs: &Tensor) -> Result<Tensor, TensorOpError> {
     if lhs.shape().len() != 1 || rhs.shape().len() != 1 {
         return Err(TensorOpError::MismatchedShapes);
     }
-    let (m, n) = (lhs.shape()[0] as u32, rhs.shape()[0] as u32);
-
-    let op = OpType::Outer;
-
-    let rt = rt();
-    let kernel = rt
-        .kernel_registry
-        .lock()
-        .unwrap()
-        .get(&KernelKey::Op(op.clone()));
-
-    let out_buf = rt
-        .storage_buffer_alloc
-        .request((m * n * DTYPE_SIZE as u32) as u64);
-
-    let mut ma = rt.metadata_arena.lock().unwrap();
-    let meta = ma
-        .allocate(&bytemuck::bytes_of(&MatrixMeta { m, n }))
-        .unwrap();
-
-    let bg = create_bg(
-        op.as_ref(),
-        &[lhs, rhs, &out_buf, &meta],
-        kernel.bind_group_layout(),
-    );
-
-    dispatch_pass(
-        op.as_ref(),
-        kernel.pipeline(),
-        &bg,
-        (n.div_ceil(8), m.div_ceil(8), 1),
-    );
-
-    let requires_grad = should_grad(&[lhs.requires_grad(), rhs.requires_grad()]);
-    let grad_node = if requires_grad {
-        Some(GradNode {
-            op,
-            parents: vec![lhs.clone(), rhs.clone()],
-            meta: None,
-        })
-    } else {
-        None
-    };
+    let (m, n) = (lhs.shape()[0], rhs.shape()[0]);
+
+    let device = same_device(&[lhs, rhs])?;
+    check_size(&device, m * n, &[n], m, &[1, n])?;
+    if trace::is_tracing() {
+        return Ok(trace::record(
+            TracedOp::Outer { m, n },
+            &[lhs, rhs],
+            &[m, n],
+        ));
+    }
+    let buf = device.rt().backend.outer(lhs.buf(), rhs.buf(), m, n);
 
-    Ok(Tensor {
-        inner: Arc::new(TensorInner {
-            id: get_tensor_id(),
-            buf: out_buf,
-            shape: vec![m as usize, n as usize],
-            requires_grad,
-            grad_node,
-        }),
-    })
+    op_output(device, OpType::Outer, &[lhs, rhs], None, buf, vec![m, n])
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable)]
-struct HeMeta {
-    fact: f32,
+pub fn he_init(
+    device: &Device,
     seed: u32,
-}
-
-pub fn he_init(seed: u32, fan_in: u32, shape: &[usize], requires_grad: bool) -> Tensor {
-    let rt = rt();
+    fan_in: u32,
+    shape: &[usize],
+    requires_grad: bool,
+) -> Result<Tensor, TensorOpError> {
     let numel = shape.iter().product::<usize>();
-    let out_buf = rt.storage_buffer_alloc.request((numel * DTYPE_SIZE) as u64);
-
-    let kernel = rt.kernel_registry.lock().unwrap().get(&KernelKey::HeInit);
-
+    check_size(device, numel, &[], 0, &[])?;
     let fact = (2.0 / (fan_in as f32)).sqrt();
+    let buf = device.rt().backend.he_init(numel, fact, seed);
+    if profiler::is_profiling() {
+        profiler::set_shapes(&[], shape);
+    }
 
-    let mut ma = rt.metadata_arena.lock().unwrap();
-    let meta = ma
-        .allocate(&bytemuck::bytes_of(&HeMeta { fact, seed }))
-        .unwrap();
-
-    let bg = create_bg("he init", &[&meta, &out_buf], kernel.bind_group_layout());
-
-    dispatch_pass(
-        "he init",
-        kernel.pipeline(),
-        &bg,
-        (numel.div_ceil(64).min(65535) as u32, 1, 1),
-    );
-
-    Tensor {
+    Ok(Tensor {
         inner: Arc::new(TensorInner {
             id: get_tensor_id(),
-            buf: out_buf,
+            device: device.clone(),
+            inference: is_inference_tensor(requires_grad),
+            buf: Arc::new(buf).into(),
+            pending: Default::default(),
             shape: shape.to_vec(),
             requires_grad,
             grad_node: None,
         }),
-    }
-}
-
-pub(crate) fn dispatch_pass(
-    label_prefix: &str,
-    pipeline: &wgpu::ComputePipeline,
-    bg: &wgpu::BindGroup,
-    wgs: (u32, u32, u32),
-) {
-    let rt = rt();
-    let encoder_label = format!("{} encoder", label_prefix);
-    let mut encoder = rt
-        .ctx
-        .device
-        .create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor {
-            label: Some(&encoder_label),
-        });
-
-    let pass_label = format!("{} pass", label_prefix);
-    let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-        label: Some(&pass_label),
-        timestamp_writes: None,
-    });
-
-    compute_pass.set_pipeline(pipeline);
-    compute_pass.set_bind_group(0, bg, &[]);
-    compute_pass.dispatch_workgroups(wgs.0, wgs.1, wgs.2);
-
-    drop(compute_pass);
-
-    rt.ctx.queue.submit(Some(encoder.finish()));
-}
-
-pub(crate) fn create_bg(
-    prefix: &str,
-    entries: &[&dyn AsBindingResource],
-    bgl: &wgpu::BindGroupLayout,
-) -> wgpu::BindGroup {
-    let rt = rt();
-    let label = format!("{} bg", prefix);
-
-    let bge: Vec<wgpu::BindGroupEntry<'_>> = entries
-        .iter()
-        .enumerate()
-        .map(|(i, e)| wgpu::BindGroupEntry {
-            binding: i as u32,
-            resource: e.as_binding_resource(),
-        })
-        .collect();
-
-    rt.ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: Some(&label),
-        layout: bgl,
-        entries: &bge,
     })
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Once;
-
     use super::*;
-    use crate::runtime::{WGPUContext, init_runtime};
-
-    fn init_test_runtime() {
-        static ONCE: Once = Once::new();
-
-        ONCE.call_once(|| {
-            let adapter = WGPUContext::list_adapters()
-                .into_iter()
-                .next()
-                .expect("No WGPU adapter available for tests");
-            init_runtime(adapter, 42);
-        });
-    }
+    use crate::runtime::init_test_runtime;
 
     #[test]
     fn cross_entropy_loss_matches_uniform_logits_and_backward() {
-        init_test_runtime();
+        let _rt = init_test_runtime();
 
         let logits = Tensor::new(&[2, 3], &[0.0, 0.0, 0.0, 0.0, 0.0, 0.0], true);
         let targets = Tensor::new(&[2, 3], &[1.0, 0.0, 0.0, 0.0, 0.0, 1.0], false);
@@ -0,0 +1,469 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io,
+    marker::PhantomData,
+    path::Path,
+    sync::{Weak, mpsc::channel},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::backend::GpuBackend;
+
+/// How the GPU time of a dispatch was measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum GpuTiming {
+    /// Timestamp queries written by the GPU at the start and end of the compute pass.
+    TimestampQuery,
+    /// The dispatch was submitted on its own and the host waited for it. Includes the submit
+    /// and synchronization overhead.
+    HostWait,
+}
+
+/// A single kernel dispatch recorded by the profiler. Times are in microseconds, relative to the
+/// start of the profiler.
+#[derive(Debug, Clone, Serialize)]
+pub struct DispatchEvent {
+    pub label: String,
+    pub input_shapes: Vec<Vec<usize>>,
+    pub output_shape: Vec<usize>,
+    pub workgroups: [u32; 3],
+    pub start_us: f64,
+    /// Time spent on the CPU to record the dispatch.
+    pub cpu_us: f64,
+    /// Start of the dispatch on the GPU. Timestamps are aligned to the CPU clock at the first
+    /// dispatch of each device.
+    pub gpu_start_us: Option<f64>,
+    /// `None` if the device was dropped before the timestamps were read.
+    pub gpu_us: Option<f64>,
+    pub gpu_timing: GpuTiming,
+}
+
+/// Times of all dispatches of one op label.
+#[derive(Debug, Clone, Serialize)]
+pub struct OpSummary {
+    pub label: String,
+    pub count: usize,
+    pub cpu_total_us: f64,
+    pub gpu_total_us: f64,
+    pub gpu_mean_us: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub events: Vec<DispatchEvent>,
+}
+
+pub(crate) enum GpuTime {
+    Timestamps(GpuTimestamps),
+    HostWait { start: Instant, duration: Duration },
+}
+
+// Query set and buffers the GPU writes the pass timestamps to
+pub(crate) struct GpuTimestamps {
+    backend: Weak<GpuBackend>,
+    pub(crate) query_set: wgpu::QuerySet,
+    pub(crate) resolve: wgpu::Buffer,
+    pub(crate) readback: wgpu::Buffer,
+}
+
+impl GpuTimestamps {
+    pub(crate) fn new(backend: Weak<GpuBackend>, device: &wgpu::Device) -> Self {
+        let size = 2 * std::mem::size_of::<u64>() as u64;
+
+        Self {
+            backend,
+            query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("profiler query set"),
+                ty: wgpu::QueryType::Timestamp,
+                count: 2,
+            }),
+            resolve: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("profiler resolve"),
+                size,
+                usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("profiler readback"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+        }
+    }
+
+    // Begin tick, end tick and nanoseconds per tick
+    fn read(&self) -> Option<(u64, u64, f32)> {
+        let backend = self.backend.upgrade()?;
+        backend.flush();
+
+        let (tx, rx) = channel();
+        self.readback
+            .map_async(wgpu::MapMode::Read, .., move |r| tx.send(r).unwrap());
+        let _ = backend.ctx.device.poll(wgpu::PollType::wait_indefinitely());
+        rx.recv().unwrap().ok()?;
+
+        let ticks = {
+            let bytes = self.readback.get_mapped_range(..);
+            bytemuck::cast_slice::<u8, u64>(&bytes).to_vec()
+        };
+        self.readback.unmap();
+
+        Some((ticks[0], ticks[1], backend.ctx.queue.get_timestamp_period()))
+    }
+}
+
+struct RecordedDispatch {
+    label: String,
+    shapes: Option<(Vec<Vec<usize>>, Vec<usize>)>,
+    workgroups: [u32; 3],
+    start: Instant,
+    cpu: Duration,
+    gpu: GpuTime,
+}
+
+struct Session {
+    start: Instant,
+    dispatches: Vec<RecordedDispatch>,
+}
+
+thread_local! {
+    static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+/// Records the dispatches of this thread until `finish()` is called. A nested guard hides the
+/// dispatches from the outer one while it is alive.
+pub struct ProfilerGuard {
+    previous: Option<Session>,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Starts profiling every kernel dispatched from this thread. With the `TIMESTAMP_QUERY` feature
+/// each dispatch is recorded into the shared batch as usual, with timestamp queries around its
+/// pass that are read back in `finish()`. Without it each dispatch is submitted on its own and
+/// waited for, which slows everything down.
+pub fn profile() -> ProfilerGuard {
+    let session = Session {
+        start: Instant::now(),
+        dispatches: vec![],
+    };
+
+    ProfilerGuard {
+        previous: SESSION.replace(Some(session)),
+        _not_send: PhantomData,
+    }
+}
+
+impl ProfilerGuard {
+    /// Stops profiling and reads back the GPU times.
+    pub fn finish(self) -> Profile {
+        let session = SESSION.take().expect("Profiler session is always set");
+        drop(self);
+
+        Profile::from_session(session)
+    }
+}
+
+impl Drop for ProfilerGuard {
+    fn drop(&mut self) {
+        SESSION.set(self.previous.take());
+    }
+}
+
+pub(crate) fn is_profiling() -> bool {
+    SESSION.with_borrow(|s| s.is_some())
+}
+
+pub(crate) fn record(
+    label: String,
+    workgroups: (u32, u32, u32),
+    start: Instant,
+    cpu: Duration,
+    gpu: GpuTime,
+) {
+    SESSION.with_borrow_mut(|s| {
+        if let Some(s) = s {
+            s.dispatches.push(RecordedDispatch {
+                label,
+                shapes: None,
+                workgroups: [workgroups.0, workgroups.1, workgroups.2],
+                start,
+                cpu,
+                gpu,
+            });
+        }
+    });
+}
+
+/// Attaches the shapes of an op to the dispatches it recorded.
+pub(crate) fn set_shapes(inputs: &[&[usize]], output: &[usize]) {
+    SESSION.with_borrow_mut(|s| {
+        let Some(s) = s else {
+            return;
+        };
+
+        for d in s.dispatches.iter_mut().rev() {
+            if d.shapes.is_some() {
+                break;
+            }
+            d.shapes = Some((inputs.iter().map(|s| s.to_vec()).collect(), output.to_vec()));
+        }
+    });
+}
+
+fn micros(d: Duration) -> f64 {
+    d.as_secs_f64() * 1e6
+}
+
+impl Profile {
+    fn from_session(session: Session) -> Self {
+        // First begin tick and its CPU time for every device
+        let mut anchors: HashMap<*const GpuBackend, (u64, f64)> = HashMap::new();
+
+        let events = session
+            .dispatches
+            .into_iter()
+            .map(|d| {
+                let start_us = micros(d.start - session.start);
+                let cpu_us = micros(d.cpu);
+
+                let (gpu_start_us, gpu_us, gpu_timing) = match &d.gpu {
+                    GpuTime::HostWait { start, duration } => (
+                        Some(micros(*start - session.start)),
+                        Some(micros(*duration)),
+                        GpuTiming::HostWait,
+                    ),
+                    GpuTime::Timestamps(ts) => match ts.read() {
+                        Some((begin, end, period)) => {
+                            let (anchor_tick, anchor_us) = *anchors
+                                .entry(ts.backend.as_ptr())
+                                .or_insert((begin, start_us + cpu_us));
+                            let to_us = |ticks: u64| ticks as f64 * period as f64 / 1000.0;
+
+                            (
+                                Some(anchor_us + to_us(begin.saturating_sub(anchor_tick))),
+                                Some(to_us(end.saturating_sub(begin))),
+                                GpuTiming::TimestampQuery,
+                            )
+                        }
+                        None => (None, None, GpuTiming::TimestampQuery),
+                    },
+                };
+
+                let (input_shapes, output_shape) = d.shapes.unwrap_or_default();
+                DispatchEvent {
+                    label: d.label,
+                    input_shapes,
+                    output_shape,
+                    workgroups: d.workgroups,
+                    start_us,
+                    cpu_us,
+                    gpu_start_us,
+                    gpu_us,
+                    gpu_timing,
+                }
+            })
+            .collect();
+
+        Self { events }
+    }
+
+    /// Times aggregated per op label, slowest on the GPU first.
+    pub fn summary(&self) -> Vec<OpSummary> {
+        let mut ops: BTreeMap<&str, OpSummary> = BTreeMap::new();
+
+        for e in &self.events {
+            let op = ops.entry(&e.label).or_insert_with(|| OpSummary {
+                label: e.label.clone(),
+                count: 0,
+                cpu_total_us: 0.0,
+                gpu_total_us: 0.0,
+                gpu_mean_us: 0.0,
+            });
+            op.count += 1;
+            op.cpu_total_us += e.cpu_us;
+            op.gpu_total_us += e.gpu_us.unwrap_or(0.0);
+        }
+
+        let mut ops: Vec<OpSummary> = ops.into_values().collect();
+        for op in &mut ops {
+            op.gpu_mean_us = op.gpu_total_us / op.count as f64;
+        }
+        ops.sort_by(|a, b| b.gpu_total_us.total_cmp(&a.gpu_total_us));
+
+        ops
+    }
+
+    /// `summary()` formatted as a text table.
+    pub fn table(&self) -> String {
+        let summary = self.summary();
+        let width = summary
+            .iter()
+            .map(|op| op.label.len())
+            .max()
+            .unwrap_or(0)
+            .max("op".len());
+
+        let mut out = format!(
+            "{:<width$}  {:>7}  {:>12}  {:>12}  {:>12}\n",
+            "op", "count", "cpu total us", "gpu total us", "gpu mean us"
+        );
+        for op in summary {
+            let _ = writeln!(
+                out,
+                "{:<width$}  {:>7}  {:>12.1}  {:>12.1}  {:>12.1}",
+                op.label, op.count, op.cpu_total_us, op.gpu_total_us, op.gpu_mean_us
+            );
+        }
+
+        out
+    }
+
+    /// Events in the Chrome trace event format, which can be opened in `chrome://tracing` or
+    /// Perfetto. CPU recording and GPU execution are shown as two threads.
+    pub fn to_chrome_trace(&self) -> String {
+        let mut events = vec![
+            json!({"name": "thread_name", "ph": "M", "pid": 0, "tid": 0, "args": {"name": "CPU"}}),
+            json!({"name": "thread_name", "ph": "M", "pid": 0, "tid": 1, "args": {"name": "GPU"}}),
+        ];
+
+        for e in &self.events {
+            let args = json!({
+                "input_shapes": e.input_shapes,
+                "output_shape": e.output_shape,
+                "workgroups": e.workgroups,
+            });
+
+            events.push(json!({
+                "name": e.label,
+                "cat": "cpu",
+                "ph": "X",
+                "ts": e.start_us,
+                "dur": e.cpu_us,
+                "pid": 0,
+                "tid": 0,
+                "args": args,
+            }));
+
+            if let (Some(ts), Some(dur)) = (e.gpu_start_us, e.gpu_us) {
+                events.push(json!({
+                    "name": e.label,
+                    "cat": "gpu",
+                    "ph": "X",
+                    "ts": ts,
+                    "dur": dur,
+                    "pid": 0,
+                    "tid": 1,
+                    "args": args,
+                }));
+            }
+        }
+
+        serde_json::to_string(&json!({ "traceEvents": events }))
+            .expect("Trace is always serializable")
+    }
+
+    pub fn save_chrome_trace(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_chrome_trace())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::{Device, RuntimeConfig, test_adapter},
+        tensor::Tensor,
+    };
+
+    fn run_ops(device: &Device) {
+        let a = Tensor::new_on(device, &[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], false);
+        let b = Tensor::ones_on(device, &[3, 4], false);
+        let c = a.matmul(&b).unwrap().relu().unwrap();
+        assert_eq!(c.sum().unwrap().to_vec(), vec![84.0]);
+    }
+
+    #[test]
+    fn dispatches_are_timed_with_timestamp_queries() {
+        let config = RuntimeConfig::new().timestamp_queries(true);
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+
+        let guard = profile();
+        run_ops(&device);
+        let profile = guard.finish();
+
+        let matmul = profile
+            .events
+            .iter()
+            .find(|e| e.label == "Matmul")
+            .expect("matmul was profiled");
+        assert_eq!(matmul.input_shapes, vec![vec![2, 3], vec![3, 4]]);
+        assert_eq!(matmul.output_shape, vec![2, 4]);
+        assert!(matmul.workgroups.iter().all(|&w| w > 0));
+
+        for e in &profile.events {
+            assert_eq!(e.gpu_timing, GpuTiming::TimestampQuery);
+            assert!(e.gpu_us.is_some_and(|us| us >= 0.0));
+            assert!(e.gpu_start_us.is_some());
+        }
+        assert!(!is_profiling());
+    }
+
+    #[test]
+    fn host_wait_fallback_and_aggregation() {
+        let device = Device::new(test_adapter(), 0);
+        run_ops(&device);
+
+        let guard = profile();
+        run_ops(&device);
+        run_ops(&device);
+        let profile = guard.finish();
+
+        assert!(
+            profile
+                .events
+                .iter()
+                .all(|e| e.gpu_timing == GpuTiming::HostWait && e.gpu_us.is_some())
+        );
+        assert!(profile.events.iter().all(|e| !e.label.is_empty()));
+
+        let summary = profile.summary();
+        let relu = summary.iter().find(|op| op.label == "Fused(Relu(x0))");
+        assert_eq!(relu.map(|op| op.count), Some(2));
+        let count: usize = summary.iter().map(|op| op.count).sum();
+        assert_eq!(count, profile.events.len());
+        assert!(profile.table().contains("Fused(Relu(x0))"));
+
+        let trace: serde_json::Value = serde_json::from_str(&profile.to_chrome_trace()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let complete = events.iter().filter(|e| e["ph"] == "X").count();
+        assert_eq!(complete, 2 * profile.events.len());
+        let matmul = events
+            .iter()
+            .find(|e| e["name"] == "Matmul" && e["tid"] == 1)
+            .unwrap();
+        assert_eq!(matmul["args"]["output_shape"], serde_json::json!([2, 4]));
+    }
+
+    #[test]
+    fn nested_profilers_restore_the_outer_session() {
+        let device = Device::new(test_adapter(), 0);
+        let outer = profile();
+        {
+            let _inner = profile();
+            run_ops(&device);
+        }
+        let a = Tensor::new_on(&device, &[2], &[1.0, 2.0], false);
+        a.mul_s(2.0).unwrap().to_vec();
+
+        let profile = outer.finish();
+        assert_eq!(profile.events.len(), 1);
+        assert_eq!(profile.events[0].label, "Fused(Mul(x0, s0))");
+        assert_eq!(profile.events[0].input_shapes, vec![vec![2]]);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    backend::TensorStorage,
+    nn::{Linear, MLP},
+    ops::{self, OpType, TensorOpError},
+    runtime::{Device, no_grad},
+    tensor::Tensor,
+    trace,
+};
+
+// Packs `round(x / scale)`, clamped to ±127, four values per word with the first one in the low
+// byte. Words are carried by f32 tensors as their bits
+pub(crate) fn pack_int8(row: &[f32], scale: f32) -> impl Iterator<Item = f32> + '_ {
+    row.chunks(4).map(move |values| {
+        let word = values.iter().enumerate().fold(0u32, |word, (b, &x)| {
+            let q = (x / scale).round_ties_even().clamp(-127.0, 127.0) as i8;
+            word | (q as u8 as u32) << (8 * b)
+        });
+        f32::from_bits(word)
+    })
+}
+
+pub(crate) fn unpack_int8(word: f32) -> [i8; 4] {
+    word.to_bits().to_le_bytes().map(|b| b as i8)
+}
+
+// Symmetric scale mapping [-max_abs, max_abs] onto [-127, 127]
+fn int8_scale(max_abs: f32) -> f32 {
+    if max_abs > 0.0 { max_abs / 127.0 } else { 1.0 }
+}
+
+/// Largest absolute value seen at a layer input, collected over calibration batches to pick the
+/// scale its activations are quantized with.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ActivationRange {
+    max_abs: f32,
+}
+
+impl ActivationRange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Widens the range to the values of `t`, reading them back to the host.
+    pub fn observe(&mut self, t: &Tensor) {
+        self.max_abs = t
+            .to_vec()
+            .iter()
+            .fold(self.max_abs, |acc, x| acc.max(x.abs()));
+    }
+
+    pub fn max_abs(&self) -> f32 {
+        self.max_abs
+    }
+
+    /// Step between two int8 values. Larger activations are clamped.
+    pub fn scale(&self) -> f32 {
+        int8_scale(self.max_abs)
+    }
+}
+
+/// `Linear` layer with int8 weights, quantized per output channel, for inference. Inputs are
+/// quantized with the scale of a calibrated `ActivationRange` and multiplied with a packed int8
+/// kernel accumulating in f32.
+///
+/// Outputs never require grad, and the layer keeps its own copy of the parameters.
+#[derive(Debug)]
+pub struct QuantizedLinear {
+    device: Device,
+    // `[out_dim, in_dim]` packed by rows
+    weights: TensorStorage,
+    // Input scale times the scale of each output channel
+    scales: TensorStorage,
+    input_scale: f32,
+    bias: Option<Tensor>,
+    in_dim: usize,
+    out_dim: usize,
+}
+
+impl QuantizedLinear {
+    pub fn new(layer: &Linear, input_range: &ActivationRange) -> Self {
+        let device = layer.weights.device().clone();
+        let (in_dim, out_dim) = (layer.weights.shape()[0], layer.weights.shape()[1]);
+        let w = layer.weights.to_vec();
+        let input_scale = input_range.scale();
+
+        let mut packed = Vec::with_capacity(out_dim * in_dim.div_ceil(4));
+        let mut scales = Vec::with_capacity(out_dim);
+        for j in 0..out_dim {
+            let channel: Vec<f32> = (0..in_dim).map(|i| w[i * out_dim + j]).collect();
+            let scale = int8_scale(channel.iter().fold(0.0, |acc, x| acc.max(x.abs())));
+            packed.extend(pack_int8(&channel, scale));
+            scales.push(input_scale * scale);
+        }
+
+        let backend = &device.rt().backend;
+        let (weights, scales) = (backend.upload(&packed), backend.upload(&scales));
+        let bias = layer
+            .bias
+            .as_ref()
+            .map(|b| Tensor::new_on(&device, b.shape(), &b.to_vec(), false));
+
+        Self {
+            device,
+            weights,
+            scales,
+            input_scale,
+            bias,
+            in_dim,
+            out_dim,
+        }
+    }
+
+    pub fn forward(&self, input: &Tensor) -> Result<Tensor, TensorOpError> {
+        let (m, out_shape) = match input.shape() {
+            [k] if *k == self.in_dim => (1, vec![self.out_dim]),
+            [batch, k] if *k == self.in_dim => (*batch, vec![*batch, self.out_dim]),
+            [_] | [_, _] => return Err(TensorOpError::MismatchedShapes),
+            _ => return Err(TensorOpError::NonMatrixTensor),
+        };
+        if input.device() != &self.device {
+            return Err(TensorOpError::DeviceMismatch);
+        }
+        // Int8 kernels bind every tensor at once
+        let words = self.in_dim.div_ceil(4);
+        ops::check_size(
+            &self.device,
+            m * self.out_dim,
+            &[
+                m * self.in_dim,
+                m * words,
+                self.out_dim * words,
+                m * self.out_dim,
+            ],
+            0,
+            &[],
+        )?;
+        if trace::is_tracing() {
+            return Err(TensorOpError::NotTraceable(OpType::Int8Matmul));
+        }
+
+        let _ng = no_grad();
+        let backend = &self.device.rt().backend;
+        let packed = backend.quantize_int8(input.buf(), m, self.in_dim, self.input_scale);
+        let buf = backend.int8_matmul(
+            &packed,
+            &self.weights,
+            &self.scales,
+            m,
+            self.out_dim,
+            self.in_dim,
+        );
+        let out = ops::op_output(
+            self.device.clone(),
+            OpType::Int8Matmul,
+            &[input],
+            None,
+            buf,
+            out_shape,
+        )?;
+
+        match (&self.bias, input.shape()) {
+            (None, _) => Ok(out),
+            (Some(b), [_]) => out.add(b),
+            (Some(b), _) => {
+                let ones = Tensor::ones_on(&self.device, &[m], false);
+                out.add(&ones.outer(b)?)
+            }
+        }
+    }
+}
+
+/// `MLP` with every layer quantized by `QuantizedLinear`.
+#[derive(Debug)]
+pub struct QuantizedMLP {
+    layers: Vec<QuantizedLinear>,
+}
+
+impl QuantizedMLP {
+    /// Runs `model` on the calibration `inputs` to collect the input range of every layer, then
+    /// quantizes the layers with them.
+    pub fn calibrate(model: &MLP, inputs: &[Tensor]) -> Result<Self, TensorOpError> {
+        let _ng = no_grad();
+        let mut ranges = vec![ActivationRange::new(); model.layers.len()];
+
+        for input in inputs {
+            let mut out = input.clone();
+            for (i, (layer, range)) in model.layers.iter().zip(&mut ranges).enumerate() {
+                range.observe(&out);
+                out = layer.forward(&out)?;
+                if i < model.layers.len() - 1 {
+                    out = out.relu()?;
+                }
+            }
+        }
+
+        let layers = model
+            .layers
+            .iter()
+            .zip(&ranges)
+            .map(|(layer, range)| QuantizedLinear::new(layer, range))
+            .collect();
+
+        Ok(Self { layers })
+    }
+
+    pub fn forward(&self, input: &Tensor) -> Result<Tensor, TensorOpError> {
+        let mut out = input.clone();
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            out = layer.forward(&out)?;
+
+            if i < self.layers.len() - 1 {
+                out = out.relu()?;
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Runs both models on `inputs` and measures how far the quantized outputs are from the f32
+    /// ones.
+    pub fn compare(&self, model: &MLP, inputs: &[Tensor]) -> Result<AccuracyReport, TensorOpError> {
+        let _ng = no_grad();
+        let mut report = AccuracyReport::default();
+        let (mut values, mut rows, mut agreeing) = (0, 0, 0);
+
+        for input in inputs {
+            let expected = model.forward(input)?;
+            let actual = self.forward(input)?;
+            let width = *expected.shape().last().unwrap();
+            let (expected, actual) = (expected.to_vec(), actual.to_vec());
+
+            for (e, a) in expected.iter().zip(&actual) {
+                let error = (e - a).abs();
+                report.max_abs_error = report.max_abs_error.max(error);
+                report.mean_abs_error += error;
+            }
+            values += expected.len();
+
+            for (e, a) in expected.chunks(width).zip(actual.chunks(width)) {
+                agreeing += (argmax(e) == argmax(a)) as usize;
+                rows += 1;
+            }
+        }
+
+        report.mean_abs_error /= values.max(1) as f32;
+        report.argmax_agreement = agreeing as f32 / rows.max(1) as f32;
+
+        Ok(report)
+    }
+}
+
+fn argmax(row: &[f32]) -> usize {
+    row.iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, &x)| {
+            if x > best.1 { (i, x) } else { best }
+        })
+        .0
+}
+
+/// Error of a quantized model against the f32 one on the same inputs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AccuracyReport {
+    pub max_abs_error: f32,
+    pub mean_abs_error: f32,
+    /// Fraction of the output rows whose largest value is at the same index, the prediction of
+    /// a classifier.
+    pub argmax_agreement: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::test_adapter;
+
+    fn batch(device: &Device, step: usize) -> Tensor {
+        let data: Vec<f32> = (0..4 * 10)
+            .map(|i| (((i * 7 + step * 3) % 19) as f32 - 9.0) * 0.1)
+            .collect();
+        Tensor::new_on(device, &[4, 10], &data, false)
+    }
+
+    #[test]
+    fn packs_four_values_per_word() {
+        let words: Vec<f32> = pack_int8(&[1.0, -1.0, 300.0, -0.49, 2.5], 1.0).collect();
+        assert_eq!(words.len(), 2);
+        assert_eq!(unpack_int8(words[0]), [1, -1, 127, 0]);
+        // Ties round to even, the padding is zero
+        assert_eq!(unpack_int8(words[1]), [2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn quantized_mlp_stays_close_to_f32() {
+        let cpu_output = {
+            let device = Device::cpu(3);
+            let model = MLP::new_on(&device, &[10, 16, 3], true);
+            let inputs: Vec<Tensor> = (0..4).map(|i| batch(&device, i)).collect();
+            let quantized = QuantizedMLP::calibrate(&model, &inputs[..2]).unwrap();
+
+            let report = quantized.compare(&model, &inputs).unwrap();
+            assert!(report.max_abs_error < 0.1, "{report:?}");
+            assert!(report.mean_abs_error < 0.02, "{report:?}");
+            assert!(report.argmax_agreement >= 0.75, "{report:?}");
+
+            let out = quantized.forward(&inputs[3]).unwrap();
+            assert!(!out.requires_grad());
+            out.to_vec()
+        };
+
+        // Same seed, same weights and the same integer products
+        let device = Device::new(test_adapter(), 3);
+        let model = MLP::new_on(&device, &[10, 16, 3], true);
+        let inputs: Vec<Tensor> = (0..4).map(|i| batch(&device, i)).collect();
+        let quantized = QuantizedMLP::calibrate(&model, &inputs[..2]).unwrap();
+        let out = quantized.forward(&inputs[3]).unwrap().to_vec();
+        for (g, c) in out.iter().zip(&cpu_output) {
+            assert!((g - c).abs() < 1e-3, "{out:?} != {cpu_output:?}");
+        }
+
+        // A single vector gives the first row of the batch
+        let row = Tensor::new_on(&device, &[10], &inputs[3].to_vec()[..10], false);
+        let single = quantized.forward(&row).unwrap();
+        assert_eq!(single.shape(), &[3]);
+        assert_eq!(single.to_vec(), out[..3]);
+    }
+}
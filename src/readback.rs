@@ -0,0 +1,353 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    buffer_alloc::{BufferLease, usage_marker::Readback},
+    runtime::WGPUContext,
+};
+
+/// Copy of a tensor to host memory that was started with `Tensor::copy_to_host()`. The copy runs
+/// in the background with the ops queued after it, so the handle can be checked later without
+/// stalling the device. It is also a future resolving to the data.
+#[derive(Debug)]
+pub struct HostCopy {
+    state: HostCopyState,
+}
+
+#[derive(Debug)]
+enum HostCopyState {
+    Ready(Vec<f32>),
+    Mapping(PendingMap),
+    Taken,
+}
+
+#[derive(Debug)]
+struct PendingMap {
+    ctx: WGPUContext,
+    staging: BufferLease<Readback>,
+    // Submission holding the copy, later ones don't have to finish
+    submission: wgpu::SubmissionIndex,
+    state: Arc<Mutex<MapState>>,
+    // Whether the poller thread already waits for the submission on behalf of the future
+    waiting: bool,
+}
+
+#[derive(Debug, Default)]
+struct MapState {
+    status: MapStatus,
+    // Task awaiting the copy, woken by the map callback
+    waker: Option<Waker>,
+}
+
+#[derive(Debug, Default)]
+enum MapStatus {
+    #[default]
+    Pending,
+    Mapped,
+    Failed(wgpu::BufferAsyncError),
+    // Data was read and the staging buffer unmapped
+    Done,
+}
+
+impl HostCopy {
+    pub(crate) fn ready(data: Vec<f32>) -> Self {
+        Self {
+            state: HostCopyState::Ready(data),
+        }
+    }
+
+    // `staging` already has a copy of the tensor, submitted in `submission`
+    pub(crate) fn mapping(
+        ctx: WGPUContext,
+        staging: BufferLease<Readback>,
+        submission: wgpu::SubmissionIndex,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(MapState::default()));
+
+        let callback_state = state.clone();
+        staging.map_async(move |result| {
+            let mut state = callback_state.lock().unwrap();
+            state.status = match result {
+                Ok(()) => MapStatus::Mapped,
+                Err(e) => MapStatus::Failed(e),
+            };
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        Self {
+            state: HostCopyState::Mapping(PendingMap {
+                ctx,
+                staging,
+                submission,
+                state,
+                waiting: false,
+            }),
+        }
+    }
+
+    /// Whether the data arrived, checking the device without blocking.
+    pub fn is_ready(&self) -> bool {
+        match &self.state {
+            HostCopyState::Ready(_) => true,
+            HostCopyState::Mapping(pending) => {
+                let _ = pending.ctx.device.poll(wgpu::PollType::Poll);
+                pending.is_mapped()
+            }
+            HostCopyState::Taken => panic!("HostCopy data was already taken"),
+        }
+    }
+
+    /// Returns the data if it arrived, without blocking.
+    pub fn try_take(&mut self) -> Option<Vec<f32>> {
+        if !self.is_ready() {
+            return None;
+        }
+
+        Some(self.take())
+    }
+
+    /// Blocks until the data arrives, without waiting for the work submitted after the copy.
+    pub fn wait(mut self) -> Vec<f32> {
+        if let HostCopyState::Mapping(pending) = &self.state {
+            pending.wait();
+        }
+
+        self.take()
+    }
+
+    // Only called once the map callback ran
+    fn take(&mut self) -> Vec<f32> {
+        match std::mem::replace(&mut self.state, HostCopyState::Taken) {
+            HostCopyState::Ready(data) => data,
+            HostCopyState::Mapping(pending) => pending.read(),
+            HostCopyState::Taken => panic!("HostCopy data was already taken"),
+        }
+    }
+}
+
+impl PendingMap {
+    fn is_mapped(&self) -> bool {
+        !matches!(self.state.lock().unwrap().status, MapStatus::Pending)
+    }
+
+    // Blocks until the submission with the copy finished and the map callback ran
+    fn wait(&self) {
+        let _ = self.ctx.device.poll(wgpu::PollType::Wait {
+            submission_index: Some(self.submission.clone()),
+            timeout: None,
+        });
+    }
+
+    fn read(&self) -> Vec<f32> {
+        let mut state = self.state.lock().unwrap();
+        match std::mem::replace(&mut state.status, MapStatus::Done) {
+            MapStatus::Mapped => self.staging.read_mapped(),
+            MapStatus::Failed(e) => panic!("Readback failed: {}", e),
+            MapStatus::Pending | MapStatus::Done => unreachable!("Buffer is not mapped"),
+        }
+    }
+}
+
+impl Drop for PendingMap {
+    // The staging buffer goes back to the allocator, so it must not stay mapped
+    fn drop(&mut self) {
+        if !self.is_mapped() {
+            self.wait();
+        }
+
+        if let MapStatus::Mapped = self.state.lock().unwrap().status {
+            self.staging.raw().unmap();
+        }
+    }
+}
+
+impl Future for HostCopy {
+    type Output = Vec<f32>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let HostCopyState::Mapping(pending) = &mut this.state else {
+            return Poll::Ready(this.take());
+        };
+
+        {
+            // Registered under the lock the callback takes, so its wake can't be missed
+            let mut state = pending.state.lock().unwrap();
+            if matches!(state.status, MapStatus::Pending) {
+                state.waker = Some(cx.waker().clone());
+            } else {
+                drop(state);
+                return Poll::Ready(this.take());
+            }
+        }
+
+        // Native wgpu runs map callbacks only inside `Device::poll`. If the copy is done, this
+        // one wakes the task, otherwise the poller thread of the device waits for the submission
+        // while the executor sleeps until the callback wakes it
+        let _ = pending.ctx.device.poll(wgpu::PollType::Poll);
+        if !pending.waiting && !pending.is_mapped() {
+            pending.waiting = true;
+            pending.ctx.poll_in_background(pending.submission.clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Wake,
+        thread::Thread,
+    };
+
+    use super::*;
+    use crate::{
+        runtime::{Device, test_adapter},
+        tensor::Tensor,
+    };
+
+    struct CountingWaker {
+        wakes: AtomicUsize,
+        thread: Thread,
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    #[test]
+    fn copy_sees_the_data_at_the_time_it_was_started() {
+        let device = Device::new(test_adapter(), 0);
+        let mut x = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+
+        let copy = x.copy_to_host();
+        let y = x.mul_s(2.0).unwrap();
+        x.assign(&y);
+
+        assert_eq!(copy.wait(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(pollster::block_on(x.to_vec_async()), vec![2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn wait_leaves_the_work_queued_after_the_copy() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+
+        let copy = x.copy_to_host();
+        let y = x.sum().unwrap();
+        assert_eq!(copy.wait(), vec![1.0, 2.0, 3.0]);
+
+        // Still recorded, neither submitted nor waited for
+        let gpu = device.rt().backend.as_gpu().unwrap();
+        assert!(gpu.batch.lock().unwrap().ops > 0);
+        assert_eq!(y.to_vec(), vec![6.0]);
+    }
+
+    #[test]
+    fn awaiting_a_copy_sleeps_until_the_map_callback() {
+        let device = Device::new(test_adapter(), 0);
+        let a = Tensor::ones_on(&device, &[256, 256], false);
+        let mut copy = a.matmul(&a).unwrap().copy_to_host();
+
+        let waker = Arc::new(CountingWaker {
+            wakes: AtomicUsize::new(0),
+            thread: std::thread::current(),
+        });
+        let task_waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&task_waker);
+        // The first poll registers the waker before the map callback can run
+        assert!(Pin::new(&mut copy).poll(&mut cx).is_pending());
+        let data = loop {
+            match Pin::new(&mut copy).poll(&mut cx) {
+                Poll::Ready(data) => break data,
+                Poll::Pending => std::thread::park(),
+            }
+        };
+
+        assert_eq!(data.len(), 256 * 256);
+        assert!(data.iter().all(|&x| x == 256.0));
+        // Woken once by the map callback, not by the pending polls
+        assert_eq!(waker.wakes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn copies_awaited_together_all_complete() {
+        let device = Device::new(test_adapter(), 0);
+        let a = Tensor::ones_on(&device, &[128, 128], false);
+        let mut copies: Vec<_> = (1..=3)
+            .map(|i| {
+                a.mul_s(i as f32)
+                    .unwrap()
+                    .matmul(&a)
+                    .unwrap()
+                    .copy_to_host()
+            })
+            .collect();
+
+        let waker = Arc::new(CountingWaker {
+            wakes: AtomicUsize::new(0),
+            thread: std::thread::current(),
+        });
+        let task_waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&task_waker);
+        // Every copy waits on the same poller thread, in submission order
+        let mut data = vec![None, None, None];
+        while data.iter().any(Option::is_none) {
+            for (copy, data) in copies.iter_mut().zip(&mut data) {
+                if data.is_none()
+                    && let Poll::Ready(ready) = Pin::new(copy).poll(&mut cx)
+                {
+                    *data = Some(ready);
+                }
+            }
+            if data.iter().any(Option::is_none) {
+                std::thread::park();
+            }
+        }
+
+        for (i, data) in data.into_iter().enumerate() {
+            let expected = 128.0 * (i + 1) as f32;
+            assert!(data.unwrap().iter().all(|&x| x == expected));
+        }
+    }
+
+    #[test]
+    fn copy_can_be_polled_and_dropped_unread() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[2], &[1.0, 2.0], false);
+
+        let mut copy = x.add(&x).unwrap().copy_to_host();
+        device.synchronize();
+        assert!(copy.is_ready());
+        assert_eq!(copy.try_take(), Some(vec![2.0, 4.0]));
+
+        // Unread copies must give back their staging buffers unmapped
+        for _ in 0..3 {
+            drop(x.copy_to_host());
+        }
+        assert_eq!(x.to_vec(), vec![1.0, 2.0]);
+        assert_eq!(x.copy_to_host().wait(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn cpu_copies_are_ready_immediately() {
+        let device = Device::cpu(0);
+        let x = Tensor::new_on(&device, &[2], &[1.0, 2.0], false);
+
+        let mut copy = x.copy_to_host();
+        assert!(copy.is_ready());
+        assert_eq!(copy.try_take(), Some(vec![1.0, 2.0]));
+        assert_eq!(pollster::block_on(x.to_vec_async()), vec![1.0, 2.0]);
+    }
+}
@@ -1,38 +1,137 @@
-use std::sync::{Arc, Mutex, OnceLock};
+use std::{
+    cell::Cell,
+    fmt, io,
+    marker::PhantomData,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, OnceLock, Weak,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Instant,
+};
 
 use serde::Serialize;
 
 use crate::{
+    adapter::{AdapterPreference, AdapterSelectionError},
     autograd::GradStore,
-    buffer_alloc::{
-        BufferAllocStats, BufferAllocatorRef,
-        usage_marker::{Readback, Storage},
-    },
-    kernel_registry::KernelRegistry,
-    metadata_arena::MetadataArena,
+    backend::{Backend, BackendKind, CpuBackend, GpuBackend},
+    buffer_alloc::{AllocationStrategy, AllocatorEvents, BufferAllocStats, CachePolicy},
+    kernel_registry::{KernelKey, KernelRegistry},
+    memory_report::MemoryReport,
+    metadata_arena::MetadataArenaStats,
+    tensor::TensorInner,
 };
 
 #[derive(Debug, Clone)]
 pub struct WGPUContext {
     pub(crate) device: Arc<wgpu::Device>,
     pub(crate) queue: Arc<wgpu::Queue>,
+    pub(crate) submissions: Arc<Submissions>,
+    poller: Arc<Poller>,
+}
+
+// Numbers the submissions of a queue, from 1, and tracks the last one the GPU finished
+#[derive(Debug, Default)]
+pub(crate) struct Submissions {
+    // Held while submitting, so that the numbers follow the queue order
+    lock: Mutex<()>,
+    submitted: AtomicU64,
+    done: Arc<AtomicU64>,
+}
+
+impl Submissions {
+    // Submission that runs the commands recorded until now, at the latest
+    pub(crate) fn next(&self) -> u64 {
+        self.submitted.load(Ordering::Acquire) + 1
+    }
+}
+
+// Thread blocking on submissions for the futures awaiting them, started on first use. Native
+// wgpu runs callbacks only inside `Device::poll`, so something has to wait for the device
+#[derive(Debug, Default)]
+struct Poller {
+    sender: OnceLock<mpsc::Sender<wgpu::SubmissionIndex>>,
 }
 
 impl WGPUContext {
     pub fn new(adapter: wgpu::Adapter) -> Self {
-        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
-            label: Some("Torchic device"),
-            required_limits: adapter.limits(),
-            required_features: wgpu::Features::empty(),
-            memory_hints: wgpu::MemoryHints::MemoryUsage,
-            ..Default::default()
-        }))
-        .expect("This operation should be successfull, maybe there is a problem with your adapter");
+        let limits = adapter.limits();
+        Self::request(&adapter, wgpu::Features::empty(), limits).expect(
+            "This operation should be successfull, maybe there is a problem with your adapter",
+        )
+    }
 
-        Self {
+    pub(crate) fn request(
+        adapter: &wgpu::Adapter,
+        features: wgpu::Features,
+        limits: wgpu::Limits,
+    ) -> Result<Self, wgpu::RequestDeviceError> {
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+                label: Some("Torchic device"),
+                required_limits: limits,
+                required_features: features,
+                memory_hints: wgpu::MemoryHints::MemoryUsage,
+                ..Default::default()
+            }))?;
+
+        Ok(Self {
             device: Arc::new(device),
             queue: Arc::new(queue),
-        }
+            submissions: Default::default(),
+            poller: Default::default(),
+        })
+    }
+
+    pub(crate) fn submit(
+        &self,
+        commands: impl IntoIterator<Item = wgpu::CommandBuffer>,
+    ) -> wgpu::SubmissionIndex {
+        let submissions = &self.submissions;
+        let _lock = submissions.lock.lock().unwrap();
+        let index = self.queue.submit(commands);
+        let number = submissions.submitted.fetch_add(1, Ordering::AcqRel) + 1;
+
+        let done = submissions.done.clone();
+        self.queue.on_submitted_work_done(move || {
+            done.fetch_max(number, Ordering::AcqRel);
+        });
+
+        index
+    }
+
+    // Last submission known to be finished, without waiting for the others
+    pub(crate) fn finished_submission(&self) -> u64 {
+        let _ = self.device.poll(wgpu::PollType::Poll);
+        self.submissions.done.load(Ordering::Acquire)
+    }
+
+    // Runs the callbacks of `submission` once it finished, without blocking the caller. The
+    // submissions are waited for in order on one thread, which exits with the context
+    pub(crate) fn poll_in_background(&self, submission: wgpu::SubmissionIndex) {
+        let sender = self.poller.sender.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<wgpu::SubmissionIndex>();
+            let device = Arc::downgrade(&self.device);
+            thread::Builder::new()
+                .name("torchic-poller".to_string())
+                .spawn(move || {
+                    for submission in receiver {
+                        let Some(device) = device.upgrade() else {
+                            return;
+                        };
+                        let _ = device.poll(wgpu::PollType::Wait {
+                            submission_index: Some(submission),
+                            timeout: None,
+                        });
+                    }
+                })
+                .expect("Failed to start the device poller thread");
+            sender
+        });
+        let _ = sender.send(submission);
     }
 
     pub fn list_adapters() -> Vec<wgpu::Adapter> {
@@ -41,24 +140,405 @@ impl WGPUContext {
     }
 }
 
+/// Settings used to create a `Device`. Everything not set explicitly keeps its default: seed 0,
+/// 1MB metadata pages, the default cache policies, batches of up to 64 ops or 256MB, no optional
+/// features and all the limits the adapter supports.
+///
+/// Op parameters are passed as immediates when the adapter supports `IMMEDIATES`, and in uniform
+/// buffers otherwise or when the `max_immediate_size` limit is too small for them. Reductions
+/// use subgroup operations when the adapter supports `SUBGROUP`, unless `subgroups(false)`.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    seed: u32,
+    pub(crate) metadata_page_size: u64,
+    pub(crate) storage_cache_policy: CachePolicy,
+    pub(crate) readback_cache_policy: CachePolicy,
+    pub(crate) max_batch_ops: usize,
+    pub(crate) max_batch_bytes: u64,
+    features: wgpu::Features,
+    // Features turned off that are otherwise requested wherever the adapter has them
+    disabled_features: wgpu::Features,
+    limits: Option<wgpu::Limits>,
+    pub(crate) kernel_cache_dir: Option<PathBuf>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            metadata_page_size: 1024 * 1024, /* 1MB */
+            storage_cache_policy: CachePolicy::default(),
+            readback_cache_policy: CachePolicy::default(),
+            max_batch_ops: 64,
+            max_batch_bytes: 256 * 1024 * 1024, /* 256MB */
+            features: wgpu::Features::empty(),
+            disabled_features: wgpu::Features::empty(),
+            limits: None,
+            kernel_cache_dir: None,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Size of a metadata arena page, which bounds the size of op metadata like shapes.
+    pub fn metadata_page_size(mut self, size: u64) -> Self {
+        self.metadata_page_size = size;
+        self
+    }
+
+    /// Policy of the tensor buffers. With `AllocationStrategy::Slab`, kernels bind all their
+    /// buffers read-write since several of them can be ranges of the same buffer.
+    pub fn storage_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.storage_cache_policy = policy;
+        self
+    }
+
+    pub fn readback_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.readback_cache_policy = policy;
+        self
+    }
+
+    /// Number of commands recorded before they are submitted to the GPU. Commands are also
+    /// submitted on readback and `synchronize()`. 1 submits every op on its own.
+    pub fn max_batch_ops(mut self, ops: usize) -> Self {
+        self.max_batch_ops = ops;
+        self
+    }
+
+    /// Submits the recorded commands once the buffers they use add up to `bytes`.
+    pub fn max_batch_bytes(mut self, bytes: u64) -> Self {
+        self.max_batch_bytes = bytes;
+        self
+    }
+
+    pub fn shader_f16(self, enabled: bool) -> Self {
+        self.feature(wgpu::Features::SHADER_F16, enabled)
+    }
+
+    pub fn timestamp_queries(self, enabled: bool) -> Self {
+        self.feature(wgpu::Features::TIMESTAMP_QUERY, enabled)
+    }
+
+    /// Subgroup operations, which reductions then use instead of workgroup memory alone. They're
+    /// on by default where the adapter supports them, `true` makes them required.
+    pub fn subgroups(mut self, enabled: bool) -> Self {
+        self.disabled_features
+            .set(wgpu::Features::SUBGROUP, !enabled);
+        self.feature(wgpu::Features::SUBGROUP, enabled)
+    }
+
+    /// Limits requested from the adapter instead of everything it supports.
+    pub fn limits(mut self, limits: wgpu::Limits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Directory keeping the generated WGSL, loaded instead of generating it again, and, on
+    /// backends that support it, the compiled pipelines across processes. It's created if missing.
+    pub fn kernel_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.kernel_cache_dir = Some(dir.into());
+        self
+    }
+
+    fn feature(mut self, feature: wgpu::Features, enabled: bool) -> Self {
+        self.features.set(feature, enabled);
+        self
+    }
+
+    // Checks the config against the adapter and returns the limits to request
+    fn validate(&self, adapter: &wgpu::Adapter) -> Result<wgpu::Limits, RuntimeConfigError> {
+        let missing = self.features - adapter.features();
+        if !missing.is_empty() {
+            return Err(RuntimeConfigError::UnsupportedFeatures(missing));
+        }
+
+        let allowed = adapter.limits();
+        let limits = match &self.limits {
+            Some(limits) => {
+                let mut failed = None;
+                limits.check_limits_with_fail_fn(&allowed, true, |name, requested, allowed| {
+                    failed = Some(RuntimeConfigError::UnsupportedLimit {
+                        name,
+                        requested,
+                        allowed,
+                    });
+                });
+                if let Some(e) = failed {
+                    return Err(e);
+                }
+
+                limits.clone()
+            }
+            None => allowed,
+        };
+
+        let max_page_size = limits
+            .max_storage_buffer_binding_size
+            .min(limits.max_buffer_size);
+        if self.metadata_page_size == 0 || self.metadata_page_size > max_page_size {
+            return Err(RuntimeConfigError::InvalidMetadataPageSize(
+                self.metadata_page_size,
+            ));
+        }
+
+        for policy in [&self.storage_cache_policy, &self.readback_cache_policy] {
+            policy
+                .validate()
+                .map_err(RuntimeConfigError::InvalidCachePolicy)?;
+        }
+
+        if self.max_batch_ops == 0 || self.max_batch_bytes == 0 {
+            return Err(RuntimeConfigError::InvalidBatchLimit);
+        }
+
+        Ok(limits)
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeConfigError {
+    /// Requested features the adapter doesn't support.
+    UnsupportedFeatures(wgpu::Features),
+    UnsupportedLimit {
+        name: &'static str,
+        requested: u64,
+        allowed: u64,
+    },
+    InvalidMetadataPageSize(u64),
+    InvalidCachePolicy(&'static str),
+    /// `max_batch_ops` or `max_batch_bytes` is zero.
+    InvalidBatchLimit,
+    RequestDevice(wgpu::RequestDeviceError),
+    /// The kernel cache directory can't be created.
+    KernelCacheDir(std::io::Error),
+}
+
+impl fmt::Display for RuntimeConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeConfigError::UnsupportedFeatures(features) => {
+                write!(f, "adapter doesn't support features {:?}", features)
+            }
+            RuntimeConfigError::UnsupportedLimit {
+                name,
+                requested,
+                allowed,
+            } => write!(
+                f,
+                "limit {} = {} is out of the adapter's range, which allows {}",
+                name, requested, allowed
+            ),
+            RuntimeConfigError::InvalidMetadataPageSize(size) => {
+                write!(f, "invalid metadata page size {}", size)
+            }
+            RuntimeConfigError::InvalidCachePolicy(reason) => {
+                write!(f, "invalid cache policy: {}", reason)
+            }
+            RuntimeConfigError::InvalidBatchLimit => {
+                write!(f, "batch limits must be greater than zero")
+            }
+            RuntimeConfigError::RequestDevice(e) => write!(f, "failed to request device: {}", e),
+            RuntimeConfigError::KernelCacheDir(e) => {
+                write!(f, "failed to create the kernel cache directory: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeConfigError {}
+
 #[derive(Debug)]
 pub(crate) struct Runtime {
-    pub(crate) ctx: WGPUContext,
-    pub(crate) storage_buffer_alloc: BufferAllocatorRef<Storage>,
-    pub(crate) readback_buffer_alloc: BufferAllocatorRef<Readback>,
-    pub(crate) metadata_arena: Mutex<MetadataArena>,
+    pub(crate) backend: Arc<dyn Backend>,
     pub(crate) grad_store: GradStore,
-    pub(crate) kernel_registry: Mutex<KernelRegistry>,
-    pub(crate) do_grad: Mutex<bool>,
     pub(crate) seed: u32,
+    pub(crate) rng_counter: AtomicU32,
+    // Tensors whose deferred elementwise ops haven't run yet
+    pub(crate) pending: Mutex<Vec<Weak<TensorInner>>>,
 }
 
 impl Runtime {
-    pub(crate) fn hard_evict(&self, target: Option<u64>) {
-        self.storage_buffer_alloc.reclaim();
-        self.readback_buffer_alloc.reclaim();
-        self.storage_buffer_alloc.hard_evict(target);
-        self.readback_buffer_alloc.hard_evict(target);
+    // Seed for the next random op. Every call advances the RNG state
+    pub(crate) fn next_seed(&self) -> u32 {
+        let counter = self.rng_counter.fetch_add(1, Ordering::Relaxed);
+        self.seed.overflowing_mul(counter).0
+    }
+
+    pub(crate) fn cleanup(&self) {
+        self.grad_store.cleanup();
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|t| t.upgrade().is_some_and(|t| t.buf.get().is_none()));
+        self.backend.cleanup();
+    }
+}
+
+/// Handle to a backend with its own memory, compiled kernels and autograd state, either a GPU
+/// or the CPU. Tensors keep the device they were created on alive and ops only accept tensors
+/// from a single device. The resources are released once the last handle and tensor are dropped.
+#[derive(Debug, Clone)]
+pub struct Device(Arc<Runtime>);
+
+impl PartialEq for Device {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Device {}
+
+impl Device {
+    pub fn new(adapter: wgpu::Adapter, seed: u32) -> Self {
+        Self::with_config(adapter, &RuntimeConfig::new().seed(seed)).expect(
+            "This operation should be successfull, maybe there is a problem with your adapter",
+        )
+    }
+
+    pub fn with_config(
+        adapter: wgpu::Adapter,
+        config: &RuntimeConfig,
+    ) -> Result<Self, RuntimeConfigError> {
+        let limits = config.validate(&adapter)?;
+
+        // Op parameters are passed as immediates and reductions use subgroups wherever the
+        // adapter has them
+        let available =
+            adapter.features() & (wgpu::Features::IMMEDIATES | wgpu::Features::SUBGROUP);
+        let mut features = config.features | (available - config.disabled_features);
+        // Pipelines are only cached when asked to and where the adapter can
+        if config.kernel_cache_dir.is_some()
+            && adapter.features().contains(wgpu::Features::PIPELINE_CACHE)
+        {
+            features |= wgpu::Features::PIPELINE_CACHE;
+        }
+
+        let ctx = WGPUContext::request(&adapter, features, limits)
+            .map_err(RuntimeConfigError::RequestDevice)?;
+
+        let kernel_registry = match &config.kernel_cache_dir {
+            Some(dir) => {
+                let key = wgpu::util::pipeline_cache_key(&adapter.get_info());
+                KernelRegistry::with_cache_dir(ctx.clone(), dir, key)
+                    .map_err(RuntimeConfigError::KernelCacheDir)?
+            }
+            None => KernelRegistry::new(ctx.clone()),
+        };
+        // Slab blocks of the same buffer can be bound together
+        let kernel_registry = match config.storage_cache_policy.strategy {
+            AllocationStrategy::Slab { .. } => kernel_registry.with_writable_bindings(),
+            _ => kernel_registry,
+        };
+
+        let backend = Arc::new_cyclic(|weak| GpuBackend::new(ctx, config, kernel_registry, weak));
+
+        Ok(Self::with_backend(backend, config.seed))
+    }
+
+    /// Device that runs all ops on the CPU threads, without any adapter. Random ops give the
+    /// same results as on a GPU with the same seed.
+    pub fn cpu(seed: u32) -> Self {
+        Self::with_backend(Arc::new(CpuBackend::new()), seed)
+    }
+
+    fn with_backend(backend: Arc<dyn Backend>, seed: u32) -> Self {
+        Self(Arc::new(Runtime {
+            backend,
+            grad_store: GradStore::new(),
+            seed,
+            rng_counter: AtomicU32::new(1),
+            pending: Mutex::new(vec![]),
+        }))
+    }
+
+    pub fn backend(&self) -> BackendKind {
+        self.0.backend.kind()
+    }
+
+    /// Optional wgpu features enabled on this device, always empty for the CPU backend.
+    pub fn features(&self) -> wgpu::Features {
+        self.0
+            .backend
+            .as_gpu()
+            .map_or(wgpu::Features::empty(), |gpu| gpu.ctx.device.features())
+    }
+
+    pub(crate) fn rt(&self) -> &Arc<Runtime> {
+        &self.0
+    }
+
+    pub fn stats(&self) -> RuntimeStats {
+        self.0.backend.stats()
+    }
+
+    /// Starts logging every request, free, reclaim and eviction of the buffer allocators,
+    /// dropping the events logged before, or stops when `enabled` is false. The log grows until
+    /// then. Does nothing on the CPU backend.
+    pub fn record_allocator_events(&self, enabled: bool) {
+        if let Some(gpu) = self.0.backend.as_gpu() {
+            let start = enabled.then(Instant::now);
+            gpu.storage_buffer_alloc.record_events(start);
+            gpu.readback_buffer_alloc.record_events(start);
+        }
+    }
+
+    /// Marks the end of a step, like an optimizer step or an epoch, in the allocator event log.
+    /// Does nothing unless recording.
+    pub fn mark_step(&self) {
+        if let Some(gpu) = self.0.backend.as_gpu() {
+            gpu.storage_buffer_alloc.mark_step();
+            gpu.readback_buffer_alloc.mark_step();
+        }
+    }
+
+    /// Events logged since `record_allocator_events(true)`.
+    pub fn allocator_events(&self) -> AllocatorEvents {
+        self.0
+            .backend
+            .as_gpu()
+            .map_or(AllocatorEvents::default(), |gpu| AllocatorEvents {
+                storage: gpu.storage_buffer_alloc.events(),
+                readback: gpu.readback_buffer_alloc.events(),
+            })
+    }
+
+    /// Timeline of the allocator events logged so far, see `MemoryReport`.
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport::new(self.allocator_events())
+    }
+
+    /// Compiles the kernels of `keys` ahead of their first use, see `KernelRegistry::warmup`.
+    /// Does nothing on the CPU backend.
+    pub fn warmup_kernels(&self, keys: &[KernelKey]) -> io::Result<()> {
+        match self.0.backend.as_gpu() {
+            Some(gpu) => gpu.kernel_registry.lock().unwrap().warmup(keys),
+            None => Ok(()),
+        }
+    }
+
+    /// Submits the recorded ops and blocks until the device finished them, e.g. before timing.
+    pub fn synchronize(&self) {
+        self.0.backend.synchronize();
+    }
+
+    /// Current state of the random number generator used by random ops on this device.
+    pub fn rng_state(&self) -> u32 {
+        self.0.rng_counter.load(Ordering::Relaxed)
+    }
+
+    /// Restores a state previously returned by `rng_state()`, so random ops repeat their
+    /// results.
+    pub fn set_rng_state(&self, state: u32) {
+        self.0.rng_counter.store(state, Ordering::Relaxed);
     }
 }
 
@@ -66,77 +546,441 @@ impl Runtime {
 pub struct RuntimeStats {
     pub storage_buffer_stats: BufferAllocStats,
     pub readback_buffer_stats: BufferAllocStats,
+    pub metadata_arena_stats: MetadataArenaStats,
 }
 
 pub fn stats() -> RuntimeStats {
-    let rt = rt();
-    RuntimeStats {
-        storage_buffer_stats: rt.storage_buffer_alloc.stats(),
-        readback_buffer_stats: rt.readback_buffer_alloc.stats(),
-    }
+    default_device().stats()
+}
+
+/// Logs the allocator events of the default device, see `Device::record_allocator_events()`.
+pub fn record_allocator_events(enabled: bool) {
+    default_device().record_allocator_events(enabled);
+}
+
+pub fn allocator_events() -> AllocatorEvents {
+    default_device().allocator_events()
 }
 
-static RUNTIME: OnceLock<Arc<Runtime>> = OnceLock::new();
+/// Marks the end of a step on the default device, see `Device::mark_step()`.
+pub fn mark_step() {
+    default_device().mark_step();
+}
+
+/// Timeline of the allocator events of the default device, see `Device::memory_report()`.
+pub fn memory_report() -> MemoryReport {
+    default_device().memory_report()
+}
 
+/// Waits for the default device, see `Device::synchronize()`.
+pub fn synchronize() {
+    default_device().synchronize();
+}
+
+static DEFAULT_DEVICE: OnceLock<Device> = OnceLock::new();
+
+/// Creates the default device, used by everything that doesn't take a device explicitly.
+/// Can be called only once.
 pub fn init_runtime(adapter: wgpu::Adapter, seed: u32) {
-    let ctx = WGPUContext::new(adapter);
-
-    let runtime = Runtime {
-        ctx: ctx.clone(),
-        storage_buffer_alloc: BufferAllocatorRef::<Storage>::new(ctx.clone()),
-        readback_buffer_alloc: BufferAllocatorRef::<Readback>::new(ctx.clone()),
-        metadata_arena: Mutex::new(MetadataArena::new(ctx.clone(), 1024 * 1024 /* 1MB */)),
-        grad_store: GradStore::new(),
-        kernel_registry: Mutex::new(KernelRegistry::new(ctx)),
-        do_grad: Mutex::new(true),
-        seed,
-    };
+    set_default_device(Device::new(adapter, seed));
+}
 
-    RUNTIME
-        .set(Arc::new(runtime))
-        .expect("This should generally be ok");
+/// Same as `init_runtime()`, but creates the default device from a `RuntimeConfig`.
+pub fn init_runtime_with_config(
+    adapter: wgpu::Adapter,
+    config: &RuntimeConfig,
+) -> Result<(), RuntimeConfigError> {
+    set_default_device(Device::with_config(adapter, config)?);
+    Ok(())
 }
 
-pub(crate) fn rt() -> Arc<Runtime> {
-    RUNTIME.get().expect("Runtime is not initialized! Initialize runtime with torchic::runtime::init_runtime(adapter)").clone()
+/// Makes the CPU device the default device, see `Device::cpu()`.
+pub fn init_runtime_cpu(seed: u32) {
+    set_default_device(Device::cpu(seed));
 }
 
-pub struct NoGradGuard(bool);
+/// Creates the default device on the adapter that best matches `pref`, without user
+/// interaction. Set `TORCHIC_ADAPTER=fallback` to run on machines without a GPU.
+pub fn init_runtime_auto(pref: AdapterPreference) -> Result<(), AdapterSelectionError> {
+    let adapter = pref.select()?;
+    init_runtime_with_config(adapter, &RuntimeConfig::new()).map_err(AdapterSelectionError::Config)
+}
 
-#[derive(Debug)]
-pub enum GradGuardError {
-    AlreadyDisabled,
+fn set_default_device(device: Device) {
+    DEFAULT_DEVICE
+        .set(device)
+        .expect("Runtime is already initialized");
+}
+
+pub fn default_device() -> Device {
+    DEFAULT_DEVICE
+        .get()
+        .expect("Runtime is not initialized! Initialize runtime with torchic::runtime::init_runtime(adapter)")
+        .clone()
+}
+
+thread_local! {
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+    static INFERENCE_MODE: Cell<bool> = const { Cell::new(false) };
+    static ANOMALY_MODE: Cell<bool> = const { Cell::new(false) };
 }
 
-impl Drop for NoGradGuard {
+/// Restores the grad mode of the current thread that was active before the guard was created.
+/// Guards can be nested freely and are not `Send`, as grad modes are per thread.
+pub struct GradModeGuard {
+    grad_enabled: bool,
+    inference_mode: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl GradModeGuard {
+    fn new(grad_enabled: bool, inference_mode: bool) -> Self {
+        let guard = Self {
+            grad_enabled: GRAD_ENABLED.get(),
+            inference_mode: INFERENCE_MODE.get(),
+            _not_send: PhantomData,
+        };
+        GRAD_ENABLED.set(grad_enabled);
+        INFERENCE_MODE.set(inference_mode);
+
+        guard
+    }
+}
+
+impl Drop for GradModeGuard {
     fn drop(&mut self) {
-        *rt().do_grad.lock().unwrap() = self.0;
+        GRAD_ENABLED.set(self.grad_enabled);
+        INFERENCE_MODE.set(self.inference_mode);
     }
 }
 
-pub fn no_grad() -> Result<NoGradGuard, GradGuardError> {
-    let rt = rt();
-    let mut do_grad = rt.do_grad.lock().unwrap();
+/// Disables graph recording on this thread until the guard is dropped, like `torch.no_grad()`.
+pub fn no_grad() -> GradModeGuard {
+    set_grad_enabled(false)
+}
+
+/// Re-enables graph recording on this thread inside a `no_grad` block. Has no effect inside
+/// `inference_mode`.
+pub fn enable_grad() -> GradModeGuard {
+    set_grad_enabled(true)
+}
+
+pub fn set_grad_enabled(enabled: bool) -> GradModeGuard {
+    GradModeGuard::new(enabled, INFERENCE_MODE.get())
+}
+
+/// Stricter `no_grad`: no graph is recorded and the tensors created on this thread skip all
+/// autograd bookkeeping, which makes them cheaper to create and drop. Such tensors never get
+/// gradients. Cannot be overridden by `enable_grad` while the guard is alive.
+pub fn inference_mode() -> GradModeGuard {
+    GradModeGuard::new(false, true)
+}
+
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.get() && !INFERENCE_MODE.get()
+}
+
+pub fn is_inference_mode_enabled() -> bool {
+    INFERENCE_MODE.get()
+}
 
-    if !*do_grad {
-        return Err(GradGuardError::AlreadyDisabled);
+pub struct AnomalyModeGuard {
+    enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for AnomalyModeGuard {
+    fn drop(&mut self) {
+        ANOMALY_MODE.set(self.enabled);
     }
+}
 
-    let gl = NoGradGuard(*do_grad);
-    *do_grad = false;
+/// Enables anomaly detection on this thread until the guard is dropped. Every op then checks
+/// its output for NaN/Inf on the GPU and returns `TensorOpError::Anomaly` describing the op that
+/// produced it. Graph nodes created in this mode remember their creation site. This is slow,
+/// use it only for debugging.
+pub fn detect_anomaly() -> AnomalyModeGuard {
+    let guard = AnomalyModeGuard {
+        enabled: ANOMALY_MODE.get(),
+        _not_send: PhantomData,
+    };
+    ANOMALY_MODE.set(true);
+
+    guard
+}
+
+pub(crate) fn is_anomaly_enabled() -> bool {
+    ANOMALY_MODE.get()
+}
+
+/// RNG state of the default device, see `Device::rng_state()`.
+pub fn rng_state() -> u32 {
+    default_device().rng_state()
+}
 
-    Ok(gl)
+/// Restores the RNG state of the default device, see `Device::set_rng_state()`.
+pub fn set_rng_state(state: u32) {
+    default_device().set_rng_state(state);
 }
 
-pub(crate) fn do_grad() -> bool {
-    *rt().do_grad.lock().unwrap()
+/// Adapter used by unit tests, which can be chosen with the `TORCHIC_ADAPTER` and
+/// `TORCHIC_BACKEND` environment variables.
+#[cfg(test)]
+pub(crate) fn test_adapter() -> wgpu::Adapter {
+    AdapterPreference::new()
+        .select()
+        .unwrap_or_else(|e| panic!("No WGPU adapter available for tests: {}", e))
 }
 
-pub(crate) fn cleanup() {
-    rt().grad_store.cleanup();
-    rt().storage_buffer_alloc.reclaim();
-    rt().readback_buffer_alloc.reclaim();
-    rt().storage_buffer_alloc.try_evict();
-    rt().readback_buffer_alloc.try_evict();
-    rt().metadata_arena.lock().unwrap().reset();
+/// Initializes the default device for unit tests. The returned guard serializes tests that
+/// touch its shared state, like the RNG state.
+#[cfg(test)]
+pub(crate) fn init_test_runtime() -> std::sync::MutexGuard<'static, ()> {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    ONCE.call_once(|| init_runtime(test_adapter(), 42));
+
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_alloc::AllocatorEventKind,
+        ops::{self, TensorOpError},
+        tensor::Tensor,
+    };
+
+    fn new_device() -> Device {
+        Device::new(test_adapter(), 7)
+    }
+
+    #[test]
+    fn ops_reject_tensors_from_different_devices() {
+        let _rt = init_test_runtime();
+        let device = new_device();
+
+        let a = Tensor::new(&[2], &[1.0, 2.0], false);
+        let b = Tensor::new_on(&device, &[2], &[3.0, 4.0], false);
+
+        assert_eq!(b.device(), &device);
+        assert_ne!(a.device(), &device);
+        assert!(matches!(a.add(&b), Err(TensorOpError::DeviceMismatch)));
+    }
+
+    #[test]
+    fn device_keeps_its_own_autograd_state_and_is_freed_on_drop() {
+        let device = new_device();
+        let runtime = Arc::downgrade(device.rt());
+
+        let x = Tensor::new_on(&device, &[2], &[1.0, 2.0], true);
+        let y = x.mul_s(3.0).unwrap().sum().unwrap();
+        assert_eq!(y.device(), &device);
+
+        y.backward();
+        assert_eq!(x.grad().unwrap().to_vec(), vec![3.0, 3.0]);
+        assert!(
+            device
+                .rt()
+                .grad_store
+                .map
+                .lock()
+                .unwrap()
+                .contains_key(&x.id())
+        );
+
+        drop((x, y, device));
+        assert!(runtime.upgrade().is_none());
+    }
+
+    #[test]
+    fn devices_can_be_used_from_several_threads() {
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let device = new_device();
+                    let x = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+                    x.mul_s(i as f32).unwrap().sum().unwrap().to_vec()[0]
+                })
+            })
+            .collect();
+
+        let sums: Vec<f32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(sums, vec![0.0, 6.0]);
+    }
+
+    #[test]
+    fn config_builds_a_working_device() {
+        let policy = CachePolicy {
+            soft_cache_limit: 0,
+            hard_cache_limit: 1024 * 1024,
+            ..Default::default()
+        };
+        let config = RuntimeConfig::new()
+            .seed(3)
+            .metadata_page_size(4096)
+            .storage_cache_policy(policy)
+            .readback_cache_policy(policy);
+
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+        assert_eq!(device.rt().seed, 3);
+
+        let x = Tensor::new_on(&device, &[2, 2], &[1.0, 2.0, 3.0, 4.0], false);
+        let y = x.matmul(&x).unwrap();
+        assert_eq!(y.to_vec(), vec![7.0, 10.0, 15.0, 22.0]);
+    }
+
+    #[test]
+    fn subgroups_are_on_wherever_the_adapter_has_them() {
+        let adapter = test_adapter().features();
+        let device = Device::new(test_adapter(), 0);
+        assert_eq!(
+            device.features().contains(wgpu::Features::SUBGROUP),
+            adapter.contains(wgpu::Features::SUBGROUP)
+        );
+
+        let config = RuntimeConfig::new().subgroups(false);
+        let device = Device::with_config(test_adapter(), &config).unwrap();
+        assert!(!device.features().contains(wgpu::Features::SUBGROUP));
+        assert_eq!(
+            device.features().contains(wgpu::Features::IMMEDIATES),
+            adapter.contains(wgpu::Features::IMMEDIATES)
+        );
+    }
+
+    #[test]
+    fn allocation_strategies_compute_the_same_results() {
+        let run = |strategy: AllocationStrategy| {
+            let policy = CachePolicy {
+                strategy,
+                ..Default::default()
+            };
+            let config = RuntimeConfig::new().seed(1).storage_cache_policy(policy);
+            let device = Device::with_config(test_adapter(), &config).unwrap();
+
+            let mut res = vec![];
+            let mut x = Tensor::new_on(&device, &[3, 2], &[1.0, -2.0, 3.0, 0.5, 4.0, -1.0], false);
+            for i in 0..3 {
+                let w = ops::he_init(&device, i, 2, &[2, 2], false).unwrap();
+                let y = x.matmul(&w).unwrap().relu().unwrap();
+                res.extend(y.sum().unwrap().to_vec());
+                x.assign(&y.add(&x).unwrap());
+                res.extend(x.transposed().unwrap().to_vec());
+            }
+            res
+        };
+
+        let expected = run(AllocationStrategy::default());
+        let strategies = [
+            AllocationStrategy::PowerOfTwo,
+            // Every tensor of the run is a slab block
+            AllocationStrategy::Slab {
+                slab_size: 64 * 1024,
+                max_block_size: 4096,
+            },
+        ];
+        for strategy in strategies {
+            assert_eq!(run(strategy), expected, "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn stats_count_allocations_and_log_events_on_request() {
+        let device = new_device();
+        let x = Tensor::new_on(&device, &[2, 2], &[1.0, 2.0, 3.0, 4.0], false);
+        assert!(device.allocator_events().storage.is_empty());
+
+        device.record_allocator_events(true);
+        for _ in 0..3 {
+            x.matmul(&x).unwrap().to_vec();
+            device.mark_step();
+        }
+        let events = device.allocator_events();
+        assert!(!events.storage.is_empty());
+        assert!(!events.readback.is_empty());
+        let last = events.storage.last().unwrap();
+        assert_eq!(last.kind, AllocatorEventKind::Step { step: 2 });
+        // Later labels are skipped when the steps are close
+        assert!(device.memory_report().to_svg().contains("step 0"));
+
+        let stats = device.stats();
+        let counters = stats.storage_buffer_stats.counters;
+        assert!(counters.pool_hits > 0);
+        assert!(counters.peak_in_use_bytes >= counters.in_use_bytes);
+        assert!(counters.in_use_bytes >= 16);
+        // Op parameters only use the arena without immediates
+        let arena = stats.metadata_arena_stats;
+        assert!(arena.pages >= 1 && arena.page_size > 0);
+        assert!(arena.used_bytes <= arena.peak_used_bytes);
+
+        device.record_allocator_events(false);
+        assert!(device.allocator_events().storage.is_empty());
+        assert!(Device::cpu(0).allocator_events().storage.is_empty());
+    }
+
+    #[test]
+    fn config_rejects_what_the_adapter_cannot_satisfy() {
+        let adapter = test_adapter();
+
+        let limits = wgpu::Limits {
+            max_bind_groups: u32::MAX,
+            ..adapter.limits()
+        };
+        let config = RuntimeConfig::new().limits(limits);
+        assert!(matches!(
+            Device::with_config(adapter.clone(), &config),
+            Err(RuntimeConfigError::UnsupportedLimit {
+                name: "max_bind_groups",
+                ..
+            })
+        ));
+
+        let config = RuntimeConfig::new().metadata_page_size(0);
+        assert!(matches!(
+            Device::with_config(adapter.clone(), &config),
+            Err(RuntimeConfigError::InvalidMetadataPageSize(0))
+        ));
+
+        let policy = CachePolicy {
+            soft_cache_limit: 2,
+            hard_cache_limit: 1,
+            ..Default::default()
+        };
+        let config = RuntimeConfig::new().storage_cache_policy(policy);
+        assert!(matches!(
+            Device::with_config(adapter.clone(), &config),
+            Err(RuntimeConfigError::InvalidCachePolicy(_))
+        ));
+
+        let policy = CachePolicy {
+            strategy: AllocationStrategy::Slab {
+                slab_size: 1024,
+                max_block_size: 4096,
+            },
+            ..Default::default()
+        };
+        let config = RuntimeConfig::new().storage_cache_policy(policy);
+        assert!(matches!(
+            Device::with_config(adapter.clone(), &config),
+            Err(RuntimeConfigError::InvalidCachePolicy(_))
+        ));
+
+        let config = RuntimeConfig::new().max_batch_ops(0);
+        assert!(matches!(
+            Device::with_config(adapter.clone(), &config),
+            Err(RuntimeConfigError::InvalidBatchLimit)
+        ));
+
+        let config = RuntimeConfig::new().shader_f16(true);
+        match Device::with_config(adapter.clone(), &config) {
+            Ok(device) => assert!(device.features().contains(wgpu::Features::SHADER_F16)),
+            Err(RuntimeConfigError::UnsupportedFeatures(missing)) => {
+                assert_eq!(missing, wgpu::Features::SHADER_F16);
+                assert!(!adapter.features().contains(wgpu::Features::SHADER_F16));
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
 }
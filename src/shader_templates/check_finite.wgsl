@@ -0,0 +1,26 @@
+@group(0) @binding(0) var<storage, read> input: array<f32>;
+@group(0) @binding(1) var<storage, read_write> flags: atomic<u32>;
+
+const EXP_MASK: u32 = 0x7f800000u;
+const MANTISSA_MASK: u32 = 0x007fffffu;
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let total_threads = num_workgroups.x * 64u;
+    var idx = global_id.x;
+
+    loop {
+        if (idx >= arrayLength(&input)) { return; }
+
+        let bits = bitcast<u32>(input[idx]);
+        if ((bits & EXP_MASK) == EXP_MASK) {
+            // NaN sets bit 0, Inf sets bit 1
+            atomicOr(&flags, select(2u, 1u, (bits & MANTISSA_MASK) != 0u));
+        }
+
+        idx += total_threads;
+    }
+}
@@ -0,0 +1,19 @@
+${inputs}@group(0) @binding(${output_binding}) var<storage, read_write> output: array<f32>;
+@group(0) @binding(${scalars_binding}) var<storage, read> scalars: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let total_threads = num_workgroups.x * 64u;
+    var idx = global_id.x;
+
+    loop {
+        if (idx >= arrayLength(&output)) { return; }
+
+${body}
+
+        idx += total_threads;
+    }
+}
@@ -0,0 +1,30 @@
+struct Params {
+  M: u32,
+  N: u32,
+  words: u32,
+};
+
+@group(0) @binding(0) var<storage, read> A: array<u32>; // Mx(K/4)
+@group(0) @binding(1) var<storage, read> B: array<u32>; // Nx(K/4)
+@group(0) @binding(2) var<storage, read> scales: array<f32>; // N
+@group(0) @binding(3) var<storage, read_write> C: array<f32>; // MxN
+${params} p: Params;
+
+// Dot product of the four signed bytes packed in `a` and `b`
+fn dot4(a: u32, b: u32) -> i32 {
+  ${dot4}
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let row = gid.y;
+  let col = gid.x;
+  if (row >= p.M || col >= p.N) { return; }
+
+  var acc = 0.0;
+  for (var w = 0u; w < p.words; w++) {
+    acc += f32(dot4(A[row * p.words + w], B[col * p.words + w]));
+  }
+
+  C[row * p.N + col] = acc * scales[col];
+}
@@ -1,4 +1,4 @@
-struct Params {
+${enable}struct Params {
   M: u32,
   N: u32,
   K: u32,
@@ -7,7 +7,7 @@ struct Params {
 @group(0) @binding(0) var<storage, read> A: array<f32>; // MxK
 @group(0) @binding(1) var<storage, read> B: array<f32>; // KxN
 @group(0) @binding(2) var<storage, read_write> C: array<f32>; // MxN
-@group(0) @binding(3) var<storage, read> p: Params;
+${params} p: Params;
 
 const WG_X: u32 = 8u;
 const WG_Y: u32 = 8u;
@@ -19,8 +19,8 @@ const BM: u32 = WG_Y * TM;
 const BN: u32 = WG_X * TN;
 const BK: u32 = 16u;
 
-var<workgroup> As: array<f32, BM * BK>;
-var<workgroup> Bs: array<f32, BK * BN>;
+var<workgroup> As: array<${tile}, BM * BK>;
+var<workgroup> Bs: array<${tile}, BK * BN>;
 
 fn a_index(r: u32, c: u32) -> u32 {
   return r * p.K + c;
@@ -66,7 +66,7 @@ fn main(
       let gr = block_row + r;
       let gc = k0 + c;
 
-      As[idx] = select(0.0, A[a_index(gr, gc)], gr < p.M && gc < p.K);
+      As[idx] = select(0.0, ${tile}(A[a_index(gr, gc)]), gr < p.M && gc < p.K);
     }
 
     for (var t = 0u; t < num_b_loads; t++) {
@@ -77,14 +77,14 @@ fn main(
       let gr = k0 + r;
       let gc = block_col + c;
 
-      Bs[idx] = select(0.0, B[b_index(gr, gc)], gr < p.K && gc < p.N);
+      Bs[idx] = select(0.0, ${tile}(B[b_index(gr, gc)]), gr < p.K && gc < p.N);
     }
 
     workgroupBarrier();
 
     for (var kk = 0u; kk < BK; kk++) {
-      var a_frag: array<f32, TM>;
-      var b_frag: array<f32, TN>;
+      var a_frag: array<${tile}, TM>;
+      var b_frag: array<${tile}, TN>;
 
       for (var i = 0u; i < TM; i++) {
         let r = thread_row_base + i;
@@ -98,7 +98,7 @@ fn main(
 
       for (var i = 0u; i < TM; i++) {
         for (var j = 0u; j < TN; j++) {
-          acc[i][j] = fma(a_frag[i], b_frag[j], acc[i][j]);
+          acc[i][j] = fma(f32(a_frag[i]), f32(b_frag[j]), acc[i][j]);
         }
       }
     }
@@ -113,7 +113,7 @@ fn main(
     for (var j = 0u; j < TN; j++) {
       let gc = block_col + thread_col_base + j;
       if (gc >= p.N) { continue; }
-      C[c_index(gr, gc)] = acc[i][j];
+      C[c_index(gr, gc)] = ${store};
     }
   }
 }
\ No newline at end of file
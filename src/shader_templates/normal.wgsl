@@ -1,10 +1,11 @@
 struct Params {
     fact: f32,
     seed: u32,
+    offset: u32,
 }
 
-@group(0) @binding(0) var<storage, read> p: Params;
-@group(0) @binding(1) var<storage, read_write> output: array<f32>;
+@group(0) @binding(0) var<storage, read_write> output: array<f32>;
+${params} p: Params;
 
 fn mix(x_in: u32) -> u32 {
     var x = x_in;
@@ -42,7 +43,7 @@ fn main(
     loop {
         if (idx >= arrayLength(&output)) { return; }
 
-        output[idx] = randn(idx ^ p.seed) * p.fact;
+        output[idx] = randn((p.offset + idx) ^ p.seed) * p.fact;
         idx += total_threads;
     }
 }
\ No newline at end of file
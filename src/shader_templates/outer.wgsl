@@ -6,7 +6,7 @@ struct Params {
 @group(0) @binding(0) var<storage, read> input1: array<f32>;
 @group(0) @binding(1) var<storage, read> input2: array<f32>;
 @group(0) @binding(2) var<storage, read_write> output: array<f32>;
-@group(0) @binding(3) var<storage, read> p: Params;
+${params} p: Params;
 
 @compute @workgroup_size(8, 8)
 fn main(
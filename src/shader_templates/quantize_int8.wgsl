@@ -0,0 +1,38 @@
+struct Params {
+  k: u32,
+  words: u32,
+  total: u32,
+  scale: f32,
+};
+
+@group(0) @binding(0) var<storage, read> input: array<f32>; // MxK
+@group(0) @binding(1) var<storage, read_write> output: array<u32>; // Mx(K/4)
+${params} p: Params;
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>
+) {
+    let total_threads = num_workgroups.x * 64u;
+    var idx = global_id.x;
+
+    loop {
+        if (idx >= p.total) { return; }
+
+        let row = idx / p.words;
+        let col = (idx % p.words) * 4u;
+
+        // Byte b holds the value of column col + b, the padding past K stays zero
+        var word = 0u;
+        for (var b = 0u; b < 4u; b++) {
+            if (col + b < p.k) {
+                let q = clamp(round(input[row * p.k + col + b] / p.scale), -127.0, 127.0);
+                word |= (bitcast<u32>(i32(q)) & 0xffu) << (8u * b);
+            }
+        }
+        output[idx] = word;
+
+        idx += total_threads;
+    }
+}
@@ -23,7 +23,7 @@ fn main(
 
     loop {
         if (i >= arrayLength(&input)) { break; }
-        acc = map(acc, input[i])
+        acc = map(acc, input[i]);
         i += step_size;
     }
 
@@ -0,0 +1,51 @@
+const threads : u32 = 256;
+
+@group(0) @binding(0) var<storage, read> input: array<f32>;
+@group(0) @binding(1) var<storage, read_write> output: array<f32>;
+
+// One value per subgroup, sized for subgroups of a single invocation
+var<workgroup> wgm: array<f32, threads>;
+
+fn map(acc: f32, x: f32) -> f32 {
+    return ${map};
+}
+
+@compute @workgroup_size(threads)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) nw: vec3<u32>,
+    @builtin(local_invocation_id) lid : vec3<u32>,
+    @builtin(workgroup_id) wid : vec3<u32>,
+    @builtin(subgroup_id) sg_id: u32,
+    @builtin(num_subgroups) num_sg: u32,
+    @builtin(subgroup_invocation_id) sg_lane: u32,
+    @builtin(subgroup_size) sg_size: u32
+) {
+    let step_size = nw.x * threads;
+    var i = gid.x;
+    var acc = ${identity};
+
+    loop {
+        if (i >= arrayLength(&input)) { break; }
+        acc = map(acc, input[i]);
+        i += step_size;
+    }
+
+    acc = ${subgroup_op}(acc);
+    if (sg_lane == 0u) {
+        wgm[sg_id] = acc;
+    }
+    workgroupBarrier();
+
+    // Every subgroup combines the partial results, so that all invocations reach the
+    // subgroup op, and the first invocation writes them out
+    acc = ${identity};
+    for (var j = sg_lane; j < num_sg; j += sg_size) {
+        acc = map(acc, wgm[j]);
+    }
+    acc = ${subgroup_op}(acc);
+
+    if (lid.x == 0u) {
+        output[wid.x] = acc;
+    }
+}
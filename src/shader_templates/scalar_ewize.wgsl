@@ -1,6 +1,6 @@
 @group(0) @binding(0) var<storage, read> input: array<f32>;
 @group(0) @binding(1) var<storage, read_write> output: array<f32>;
-@group(0) @binding(2) var<storage, read> s: f32;
+${params} s: f32;
 
 @compute @workgroup_size(64)
 fn main(
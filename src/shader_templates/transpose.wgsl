@@ -5,7 +5,7 @@ struct Params {
 
 @group(0) @binding(0) var<storage, read> input: array<f32>;
 @group(0) @binding(1) var<storage, read_write> output: array<f32>;
-@group(0) @binding(2) var<storage, read> p: Params;
+${params} p: Params;
 
 @compute @workgroup_size(64)
 fn main(
@@ -1,11 +1,13 @@
-use std::sync::{Arc, atomic::AtomicU64};
+use std::sync::{Arc, Mutex, OnceLock, atomic::AtomicU64};
 
 use crate::{
-    AsBindingResource,
-    autograd::{self, GradNode},
-    buffer_alloc::{BufferLease, usage_marker::Storage},
+    autograd::{self, GradNode, HookError, HookHandle},
+    backend::TensorStorage,
+    fusion::{self, FusedBody, FusedExpr},
+    graph::ComputationGraph,
     ops::{self, TensorOpError},
-    runtime::{cleanup, rt},
+    readback::HostCopy,
+    runtime::{Device, Runtime, default_device, is_inference_mode_enabled},
 };
 
 static TENSOR_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
@@ -17,19 +19,31 @@ pub(crate) fn get_tensor_id() -> u64 {
 #[derive(Debug)]
 pub(crate) struct TensorInner {
     pub(crate) id: u64,
-    pub(crate) buf: BufferLease<Storage>,
+    pub(crate) device: Device,
+    // Unset until the deferred elementwise ops in `pending` run, see `fusion::defer_unop`
+    pub(crate) buf: OnceLock<Arc<TensorStorage>>,
+    pub(crate) pending: Mutex<Option<FusedBody<Tensor>>>,
     pub(crate) shape: Vec<usize>,
 
     pub(crate) requires_grad: bool,
     pub(crate) grad_node: Option<GradNode>,
+    // Created in inference mode, never has anything in the grad store
+    pub(crate) inference: bool,
 }
 
 impl Drop for TensorInner {
     fn drop(&mut self) {
-        rt().grad_store.add_orphan(self.id);
+        if !self.inference {
+            self.device.rt().grad_store.release(self.id);
+        }
     }
 }
 
+// Tensors that require grad are always tracked, even when created in inference mode
+pub(crate) fn is_inference_tensor(requires_grad: bool) -> bool {
+    !requires_grad && is_inference_mode_enabled()
+}
+
 #[derive(Clone, Debug)]
 pub struct Tensor {
     pub(crate) inner: Arc<TensorInner>,
@@ -38,25 +52,36 @@ pub struct Tensor {
 pub const DTYPE_SIZE: usize = 4;
 
 impl Tensor {
-    pub(crate) fn id(&self) -> u64 {
+    pub fn id(&self) -> u64 {
         self.inner.id
     }
 
-    pub(crate) fn bsize(&self) -> usize {
-        self.numel() * DTYPE_SIZE
+    pub fn device(&self) -> &Device {
+        &self.inner.device
+    }
+
+    pub(crate) fn rt(&self) -> &Arc<Runtime> {
+        self.inner.device.rt()
     }
 
     pub(crate) fn readback(&self) -> Vec<f32> {
-        let staging = rt().readback_buffer_alloc.request(self.bsize() as u64);
-        staging.download(&self.inner.buf).unwrap()
+        self.rt().backend.download(self.buf())
     }
 
     pub(crate) fn zero_grad(&self) {
-        if !rt().grad_store.map.lock().unwrap().contains_key(&self.id()) {
+        if !self
+            .rt()
+            .grad_store
+            .map
+            .lock()
+            .unwrap()
+            .contains_key(&self.id())
+        {
             return;
         }
 
-        rt().grad_store
+        self.rt()
+            .grad_store
             .map
             .lock()
             .unwrap()
@@ -64,36 +89,41 @@ impl Tensor {
             .unwrap();
     }
 
-    pub(crate) fn buf(&self) -> &BufferLease<Storage> {
-        &self.inner.buf
+    pub(crate) fn buf(&self) -> &TensorStorage {
+        self.storage()
+    }
+
+    // Runs the deferred elementwise ops computing the tensor on first use
+    pub(crate) fn storage(&self) -> &Arc<TensorStorage> {
+        self.inner
+            .buf
+            .get_or_init(|| Arc::new(fusion::materialize(self)))
+    }
+
+    // New graph leaf that shares storage with this tensor
+    pub(crate) fn detached(&self, requires_grad: bool) -> Tensor {
+        Tensor {
+            inner: Arc::new(TensorInner {
+                id: get_tensor_id(),
+                device: self.inner.device.clone(),
+                inference: is_inference_tensor(requires_grad),
+                buf: self.storage().clone().into(),
+                pending: Default::default(),
+                shape: self.inner.shape.clone(),
+                requires_grad,
+                grad_node: None,
+            }),
+        }
     }
 
     // The only mutating operation. TO BE USED ONLY IN ADAM STEP FOR NOW
     pub(crate) fn assign(&mut self, other: &Tensor) {
         assert!(self.shape() == other.shape());
-        let rt = rt();
-
-        let mut encoder =
-            rt.ctx
-                .device
-                .create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor {
-                    label: Some("Tensor copy encoder"),
-                });
-        encoder.copy_buffer_to_buffer(
-            other.buf().raw(),
-            0,
-            self.inner.buf.raw(),
-            0,
-            Some(self.bsize() as u64),
-        );
-
-        rt.ctx.queue.submit(Some(encoder.finish()));
-    }
-}
+        assert!(self.device() == other.device());
 
-impl AsBindingResource for Tensor {
-    fn as_binding_resource(&self) -> wgpu::BindingResource<'_> {
-        self.inner.buf.as_binding_resource()
+        // Deferred ops reading the old data must see it
+        fusion::materialize_all(self.rt());
+        self.rt().backend.copy(other.buf(), self.buf());
     }
 }
 
@@ -106,56 +136,158 @@ impl Tensor {
         self.inner.requires_grad
     }
 
+    /// Whether the tensor was created in `inference_mode`, skipping autograd bookkeeping.
+    pub fn is_inference(&self) -> bool {
+        self.inner.inference
+    }
+
     pub fn numel(&self) -> usize {
         self.inner.shape.iter().product::<usize>()
     }
+
+    /// Returns a tensor that shares storage with this one but is cut off from the graph.
+    pub fn detach(&self) -> Tensor {
+        self.detached(false)
+    }
+
+    /// Snapshot of the autograd graph that produced this tensor.
+    pub fn graph(&self) -> ComputationGraph {
+        ComputationGraph::from_tensor(self)
+    }
 }
 
 impl Tensor {
+    /// Creates a tensor on the default device.
     pub fn new(shape: &[usize], data: &[f32], requires_grad: bool) -> Self {
-        let bsize = shape.iter().product::<usize>() * DTYPE_SIZE;
+        Self::new_on(&default_device(), shape, data, requires_grad)
+    }
 
-        let rt = rt();
-        let buf = rt.storage_buffer_alloc.request(bsize as u64);
-        buf.set(bytemuck::cast_slice(data));
+    /// Panics when the tensor doesn't fit in a buffer of `device`, see `try_new_on()`.
+    pub fn new_on(device: &Device, shape: &[usize], data: &[f32], requires_grad: bool) -> Self {
+        Self::try_new_on(device, shape, data, requires_grad).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        Self {
+    /// Like `new_on()`, but fails with `TooLarge` when the tensor doesn't fit in a buffer.
+    pub fn try_new_on(
+        device: &Device,
+        shape: &[usize],
+        data: &[f32],
+        requires_grad: bool,
+    ) -> Result<Self, TensorOpError> {
+        assert_eq!(shape.iter().product::<usize>(), data.len());
+        ops::check_size(device, data.len(), &[], 0, &[])?;
+        let buf = device.rt().backend.upload(data);
+
+        Ok(Self {
             inner: Arc::new(TensorInner {
                 id: get_tensor_id(),
+                device: device.clone(),
+                inference: is_inference_tensor(requires_grad),
                 shape: shape.to_vec(),
                 requires_grad,
-                buf,
+                buf: Arc::new(buf).into(),
+                pending: Default::default(),
                 grad_node: None,
             }),
-        }
+        })
+    }
+
+    /// Copies the data to `device`. The copy is a new graph leaf.
+    pub fn to_device(&self, device: &Device) -> Self {
+        Self::new_on(device, self.shape(), &self.to_vec(), self.requires_grad())
     }
 
     pub fn ones(shape: &[usize], requires_grad: bool) -> Self {
+        Self::ones_on(&default_device(), shape, requires_grad)
+    }
+
+    pub fn ones_on(device: &Device, shape: &[usize], requires_grad: bool) -> Self {
         let size = shape.iter().product();
         let data = vec![1.0; size];
 
-        Self::new(shape, &data, requires_grad)
+        Self::new_on(device, shape, &data, requires_grad)
     }
 
     pub fn grad(&self) -> Option<Self> {
-        rt().grad_store
+        self.rt()
+            .grad_store
             .map
             .lock()
             .unwrap()
             .get(&self.inner.id)
-            .map(|t| t.clone())
+            .cloned()
+    }
+
+    /// Registers a hook that is called with the gradient of this tensor once it is computed
+    /// during backward. Returning `Some` replaces the gradient, e.g. for clipping.
+    pub fn register_hook<F>(&self, hook: F) -> Result<HookHandle, HookError>
+    where
+        F: Fn(&Tensor) -> Option<Tensor> + Send + Sync + 'static,
+    {
+        autograd::register_hook(self, Arc::new(hook))
+    }
+
+    /// Registers a hook that is called with this leaf tensor after its gradient was
+    /// accumulated, so `hook` can read the updated `grad()`.
+    pub fn register_post_accumulate_grad_hook<F>(&self, hook: F) -> Result<HookHandle, HookError>
+    where
+        F: Fn(&Tensor) + Send + Sync + 'static,
+    {
+        autograd::register_post_accumulate_grad_hook(self, Arc::new(hook))
     }
 
+    /// Makes this non-leaf tensor keep its gradient after backward, so it can be read with
+    /// `grad()`. Leaf tensors always keep their gradients.
+    pub fn retain_grad(&self) {
+        if self.inner.grad_node.is_some() {
+            self.rt().grad_store.retain(self.id());
+        }
+    }
+
+    /// Computes gradients of this tensor with respect to the graph leaves. Only leaves and
+    /// tensors marked with `retain_grad()` keep their gradients, and the tensors saved by the
+    /// graph are freed, so the same graph can't be backpropagated twice.
     pub fn backward(&self) {
-        autograd::backward(self);
-        cleanup();
+        if let Err(e) = self.try_backward() {
+            panic!("Backward pass failed: {}", e);
+        }
+    }
+
+    /// Same as `backward()`, but keeps the saved tensors so the graph can be backpropagated
+    /// again.
+    pub fn backward_retain_graph(&self) {
+        let res = autograd::backward(self, true);
+        self.rt().cleanup();
+
+        if let Err(e) = res {
+            panic!("Backward pass failed: {}", e);
+        }
+    }
+
+    /// Same as `backward()`, but returns errors of the ops dispatched during backward, like
+    /// anomalies found by `detect_anomaly()`.
+    pub fn try_backward(&self) -> Result<(), TensorOpError> {
+        let res = autograd::backward(self, false);
+        self.rt().cleanup();
+        res
     }
 
     pub fn to_vec(&self) -> Vec<f32> {
         let res = self.readback();
-        cleanup();
+        self.rt().cleanup();
         res
     }
+
+    /// Starts copying the data to the host and returns right away. Ops queued afterwards keep
+    /// running while the copy is in flight.
+    pub fn copy_to_host(&self) -> HostCopy {
+        self.rt().backend.copy_to_host(self.buf())
+    }
+
+    /// Same as `to_vec()`, but doesn't block the thread while the device catches up.
+    pub fn to_vec_async(&self) -> impl Future<Output = Vec<f32>> + use<> {
+        self.copy_to_host()
+    }
 }
 
 impl Tensor {
@@ -167,11 +299,11 @@ impl Tensor {
         ops::mul(self, other)
     }
 
-    pub(crate) fn div(&self, other: &Tensor) -> Result<Tensor, ops::TensorOpError> {
+    pub fn div(&self, other: &Tensor) -> Result<Tensor, ops::TensorOpError> {
         ops::div(self, other)
     }
 
-    pub(crate) fn sub(&self, other: &Tensor) -> Result<Tensor, ops::TensorOpError> {
+    pub fn sub(&self, other: &Tensor) -> Result<Tensor, ops::TensorOpError> {
         ops::sub(self, other)
     }
 
@@ -195,7 +327,7 @@ impl Tensor {
         ops::relu(self)
     }
 
-    pub(crate) fn sqrt(&self) -> Result<Tensor, ops::TensorOpError> {
+    pub fn sqrt(&self) -> Result<Tensor, ops::TensorOpError> {
         ops::sqrt(self)
     }
 
@@ -203,10 +335,16 @@ impl Tensor {
         ops::mul_scalar(self, s)
     }
 
-    pub(crate) fn add_s(&self, s: f32) -> Result<Tensor, TensorOpError> {
+    pub fn add_s(&self, s: f32) -> Result<Tensor, TensorOpError> {
         ops::add_scalar(self, s)
     }
 
+    /// Starts a chain of elementwise ops evaluated by a single kernel. Eager elementwise ops are
+    /// fused as well when they don't need grad; the chain only makes it explicit.
+    pub fn fuse(&self) -> FusedExpr {
+        FusedExpr::new(self)
+    }
+
     pub fn outer(&self, other: &Tensor) -> Result<Tensor, TensorOpError> {
         ops::outer(self, other)
     }
@@ -0,0 +1,591 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{
+    amp::Precision,
+    backend::{Backend, TensorStorage, gpu::Capture},
+    fusion::{FusedBody, FusedProgram},
+    ops::{BinopEwizeType, ReduceOpType, ScalarEwizeType, TensorOpError, UnopEwizeType},
+    runtime::{Device, no_grad},
+    tensor::{Tensor, TensorInner, get_tensor_id, is_inference_tensor},
+};
+
+static TRACE_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Result of a traced op, standing in for its data.
+#[derive(Debug)]
+pub(crate) struct TracedValue {
+    trace: u64,
+    node: usize,
+    pub(crate) numel: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum TracedOp {
+    Input(usize),
+    // Tensor captured by the traced function, like a parameter. Runs read its current data
+    Constant(Tensor),
+    ScalarEwize(ScalarEwizeType, f32),
+    UnopEwize(UnopEwizeType),
+    BinopEwize(BinopEwizeType),
+    Reduce(ReduceOpType),
+    Matmul {
+        m: usize,
+        n: usize,
+        k: usize,
+        precision: Precision,
+    },
+    Transpose {
+        m: usize,
+        n: usize,
+    },
+    Outer {
+        m: usize,
+        n: usize,
+    },
+    Fused(FusedProgram, Vec<f32>),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    op: TracedOp,
+    args: Vec<usize>,
+    shape: Vec<usize>,
+}
+
+// Nodes are in recording order, so every node comes after its arguments
+#[derive(Debug)]
+struct Graph {
+    nodes: Vec<Node>,
+    output: usize,
+}
+
+struct TraceSession {
+    id: u64,
+    device: Device,
+    nodes: Vec<Node>,
+    // Node of each captured tensor, by tensor id
+    constants: HashMap<u64, usize>,
+}
+
+thread_local! {
+    static TRACE: RefCell<Option<TraceSession>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn is_tracing() -> bool {
+    TRACE.with_borrow(|t| t.is_some())
+}
+
+/// Records `op` over `inputs` into the trace of this thread and returns a placeholder for its
+/// result.
+pub(crate) fn record(op: TracedOp, inputs: &[&Tensor], shape: &[usize]) -> Tensor {
+    TRACE.with_borrow_mut(|session| {
+        let session = session.as_mut().expect("Not tracing");
+        let args = inputs.iter().map(|t| session.node_of(t)).collect();
+        session.push(op, args, shape.to_vec())
+    })
+}
+
+impl TraceSession {
+    fn node_of(&mut self, t: &Tensor) -> usize {
+        if let TensorStorage::Traced(value) = t.buf() {
+            assert_eq!(value.trace, self.id, "Tensor comes from another trace");
+            return value.node;
+        }
+
+        if let Some(&node) = self.constants.get(&t.id()) {
+            return node;
+        }
+        self.push(TracedOp::Constant(t.clone()), vec![], t.shape().to_vec());
+        let node = self.nodes.len() - 1;
+        self.constants.insert(t.id(), node);
+
+        node
+    }
+
+    fn push(&mut self, op: TracedOp, args: Vec<usize>, shape: Vec<usize>) -> Tensor {
+        let node = self.nodes.len();
+        let numel = shape.iter().product();
+        self.nodes.push(Node {
+            op,
+            args,
+            shape: shape.clone(),
+        });
+
+        Tensor {
+            inner: Arc::new(TensorInner {
+                id: get_tensor_id(),
+                device: self.device.clone(),
+                inference: is_inference_tensor(false),
+                buf: Arc::new(TensorStorage::Traced(TracedValue {
+                    trace: self.id,
+                    node,
+                    numel,
+                }))
+                .into(),
+                pending: Default::default(),
+                shape,
+                requires_grad: false,
+                grad_node: None,
+            }),
+        }
+    }
+}
+
+// Ends the trace of this thread, even if the traced function panics
+struct TraceGuard;
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        TRACE.set(None);
+    }
+}
+
+/// Runs `f` on placeholders shaped like `inputs`, recording its ops instead of dispatching them,
+/// and compiles the recorded graph. Tensors captured by `f`, like model parameters, are read
+/// again on every run, so they can be updated in place between runs.
+///
+/// The graph records no gradients and can't contain ops computed on the host, like
+/// `cross_entropy_loss`. Reading a placeholder inside `f` panics.
+pub fn trace<F>(inputs: &[Tensor], f: F) -> Result<CompiledGraph, TensorOpError>
+where
+    F: FnOnce(&[Tensor]) -> Result<Tensor, TensorOpError>,
+{
+    assert!(!inputs.is_empty(), "trace() needs at least one input");
+    assert!(!is_tracing(), "trace() can't be nested");
+
+    let device = inputs[0].device().clone();
+    if inputs.iter().any(|t| t.device() != &device) {
+        return Err(TensorOpError::DeviceMismatch);
+    }
+
+    let mut session = TraceSession {
+        id: TRACE_ID_COUNTER.fetch_add(1, Ordering::Relaxed),
+        device: device.clone(),
+        nodes: vec![],
+        constants: HashMap::new(),
+    };
+    let placeholders: Vec<Tensor> = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, t)| session.push(TracedOp::Input(i), vec![], t.shape().to_vec()))
+        .collect();
+
+    TRACE.set(Some(session));
+    let guard = TraceGuard;
+    let result = {
+        let _ng = no_grad();
+        f(&placeholders)
+    };
+    let mut session = TRACE.take().expect("Trace session is set");
+    drop(guard);
+
+    let output = session.node_of(&result?);
+    let graph = Graph {
+        nodes: session.nodes,
+        output,
+    };
+    let input_shapes = inputs.iter().map(|t| t.shape().to_vec()).collect();
+
+    Ok(CompiledGraph::compile(device, graph, input_shapes))
+}
+
+impl Graph {
+    fn op_count(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|n| !matches!(n.op, TracedOp::Input(_) | TracedOp::Constant(_)))
+            .count()
+    }
+
+    // Drops the nodes the output doesn't depend on. Inputs are kept, runs still take them
+    fn eliminate_dead_code(&mut self) {
+        let mut live = vec![false; self.nodes.len()];
+        live[self.output] = true;
+        for (i, node) in self.nodes.iter().enumerate().rev() {
+            if live[i] || matches!(node.op, TracedOp::Input(_)) {
+                live[i] = true;
+                for &a in &node.args {
+                    live[a] = true;
+                }
+            }
+        }
+
+        let mut new_index = vec![usize::MAX; self.nodes.len()];
+        let mut nodes = vec![];
+        for (i, mut node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if live[i] {
+                node.args.iter_mut().for_each(|a| *a = new_index[*a]);
+                new_index[i] = nodes.len();
+                nodes.push(node);
+            }
+        }
+
+        self.nodes = nodes;
+        self.output = new_index[self.output];
+    }
+
+    // Turns every elementwise op into a fused kernel that also computes its elementwise
+    // arguments read only by it. The absorbed nodes are left dead
+    fn fuse_elementwise(&mut self, max_inputs: usize) {
+        let mut uses = vec![0; self.nodes.len()];
+        uses[self.output] += 1;
+        for node in &self.nodes {
+            for &a in &node.args {
+                uses[a] += 1;
+            }
+        }
+
+        let mut bodies: Vec<Option<FusedBody<usize>>> = vec![None; self.nodes.len()];
+        for i in 0..self.nodes.len() {
+            let node = &self.nodes[i];
+            let build = |inline: bool| {
+                let operand = |a: usize| match &bodies[a] {
+                    Some(body) if inline && uses[a] == 1 => body.clone(),
+                    _ => FusedBody::leaf(a),
+                };
+
+                let mut body = operand(node.args[0]);
+                match &node.op {
+                    TracedOp::ScalarEwize(typ, s) => body.scalar(typ.clone(), *s),
+                    TracedOp::UnopEwize(typ) => body.unop(typ.clone()),
+                    TracedOp::BinopEwize(typ) => {
+                        body.binop(operand(node.args[1]), typ.clone(), |a, b| a == b)
+                    }
+                    _ => unreachable!("Not an elementwise op"),
+                }
+                body
+            };
+
+            let body = match &node.op {
+                TracedOp::ScalarEwize(..) | TracedOp::UnopEwize(_) | TracedOp::BinopEwize(_) => {
+                    let body = build(true);
+                    if body.inputs.len() > max_inputs {
+                        build(false)
+                    } else {
+                        body
+                    }
+                }
+                // Already fused by `FusedExpr`, can still be absorbed by the next ops
+                TracedOp::Fused(program, scalars) => {
+                    bodies[i] = Some(FusedBody {
+                        inputs: node.args.clone(),
+                        instrs: program.instrs.clone(),
+                        scalars: scalars.clone(),
+                    });
+                    continue;
+                }
+                _ => continue,
+            };
+
+            self.nodes[i].op = TracedOp::Fused(body.program(), body.scalars.clone());
+            self.nodes[i].args = body.inputs.clone();
+            bodies[i] = Some(body);
+        }
+    }
+
+    // Index of the last node reading each node
+    fn last_uses(&self) -> Vec<usize> {
+        let mut last_use = vec![0; self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            for &a in &node.args {
+                last_use[a] = i;
+            }
+        }
+        last_use[self.output] = usize::MAX;
+
+        last_use
+    }
+
+    // Runs the nodes in order, freeing each result after its last use so that later nodes can
+    // reuse its buffer
+    fn execute(&self, backend: &dyn Backend, inputs: &[Arc<TensorStorage>]) -> Arc<TensorStorage> {
+        let last_use = self.last_uses();
+        let mut values: Vec<Option<Arc<TensorStorage>>> = vec![None; self.nodes.len()];
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let value = {
+                let args: Vec<&TensorStorage> = node
+                    .args
+                    .iter()
+                    .map(|&a| values[a].as_deref().expect("Arguments are alive"))
+                    .collect();
+
+                match &node.op {
+                    TracedOp::Input(j) => inputs[*j].clone(),
+                    TracedOp::Constant(t) => t.storage().clone(),
+                    TracedOp::ScalarEwize(typ, s) => {
+                        Arc::new(backend.scalar_ewize(typ, args[0], *s))
+                    }
+                    TracedOp::UnopEwize(typ) => Arc::new(backend.unop_ewize(typ, args[0])),
+                    TracedOp::BinopEwize(typ) => {
+                        Arc::new(backend.binop_ewize(typ, args[0], args[1]))
+                    }
+                    TracedOp::Reduce(typ) => Arc::new(backend.reduce(typ, args[0])),
+                    TracedOp::Matmul { m, n, k, precision } => {
+                        Arc::new(backend.matmul(args[0], args[1], *m, *n, *k, *precision))
+                    }
+                    TracedOp::Transpose { m, n } => Arc::new(backend.transpose(args[0], *m, *n)),
+                    TracedOp::Outer { m, n } => Arc::new(backend.outer(args[0], args[1], *m, *n)),
+                    TracedOp::Fused(program, scalars) => {
+                        Arc::new(backend.fused_ewize(program, &args, scalars))
+                    }
+                }
+            };
+            values[i] = Some(value);
+
+            for &a in &node.args {
+                if last_use[a] == i {
+                    values[a] = None;
+                }
+            }
+        }
+
+        values[self.output].take().expect("Output is computed")
+    }
+}
+
+/// What compiling a traced graph did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileStats {
+    /// Ops recorded while tracing.
+    pub traced_ops: usize,
+    /// Ops left after dead code elimination and fusion.
+    pub compiled_ops: usize,
+    /// Kernels dispatched by a run, reductions take several. Zero on the CPU backend.
+    pub dispatches: usize,
+    /// Bytes of the buffers planned for a run. Results that are never alive at the same time
+    /// share buffers. Zero on the CPU backend.
+    pub buffer_bytes: u64,
+}
+
+// Buffers and bind groups built once, runs copy the inputs in and replay the dispatches
+#[derive(Debug)]
+struct GpuPlan {
+    inputs: Vec<Arc<TensorStorage>>,
+    output: Arc<TensorStorage>,
+    capture: Capture,
+}
+
+/// Graph recorded by `trace()`, ready to run on new inputs of the same shapes.
+#[derive(Debug)]
+pub struct CompiledGraph {
+    device: Device,
+    graph: Graph,
+    input_shapes: Vec<Vec<usize>>,
+    stats: CompileStats,
+    plan: Option<GpuPlan>,
+}
+
+impl CompiledGraph {
+    fn compile(device: Device, mut graph: Graph, input_shapes: Vec<Vec<usize>>) -> Self {
+        let traced_ops = graph.op_count();
+        let backend = &device.rt().backend;
+
+        graph.eliminate_dead_code();
+        graph.fuse_elementwise(backend.max_fused_inputs());
+        graph.eliminate_dead_code();
+
+        // Executing the graph once while capturing builds the buffers and bind groups
+        let plan = backend.as_gpu().map(|gpu| {
+            gpu.begin_capture(false);
+            let inputs: Vec<Arc<TensorStorage>> = input_shapes
+                .iter()
+                .map(|s| Arc::new(TensorStorage::Gpu(gpu.request(s.iter().product()))))
+                .collect();
+            let output = graph.execute(gpu, &inputs);
+
+            GpuPlan {
+                inputs,
+                output,
+                capture: gpu.end_capture(),
+            }
+        });
+
+        let stats = CompileStats {
+            traced_ops,
+            compiled_ops: graph.op_count(),
+            dispatches: plan.as_ref().map_or(0, |p| p.capture.dispatches()),
+            buffer_bytes: plan.as_ref().map_or(0, |p| p.capture.buffer_bytes()),
+        };
+
+        Self {
+            device,
+            graph,
+            input_shapes,
+            stats,
+            plan,
+        }
+    }
+
+    pub fn stats(&self) -> CompileStats {
+        self.stats
+    }
+
+    /// Runs the graph on `inputs`, which must have the shapes it was traced with.
+    pub fn run(&self, inputs: &[Tensor]) -> Result<Tensor, TensorOpError> {
+        if inputs.len() != self.input_shapes.len()
+            || inputs
+                .iter()
+                .zip(&self.input_shapes)
+                .any(|(t, shape)| t.shape() != shape)
+        {
+            return Err(TensorOpError::MismatchedShapes);
+        }
+        if inputs.iter().any(|t| t.device() != &self.device) {
+            return Err(TensorOpError::DeviceMismatch);
+        }
+
+        let backend = &self.device.rt().backend;
+        let output = &self.graph.nodes[self.graph.output];
+        let buf = match &self.plan {
+            Some(plan) => {
+                let gpu = backend
+                    .as_gpu()
+                    .expect("Plans are only built for GPU devices");
+                for (t, slot) in inputs.iter().zip(&plan.inputs) {
+                    gpu.copy(t.buf(), slot);
+                }
+                gpu.replay(&plan.capture);
+
+                // The output buffer is overwritten by the next run
+                let out = TensorStorage::Gpu(gpu.request(output.shape.iter().product()));
+                gpu.copy(&plan.output, &out);
+                out
+            }
+            None => {
+                let inputs: Vec<_> = inputs.iter().map(|t| t.storage().clone()).collect();
+                let out = self.graph.execute(backend.as_ref(), &inputs);
+
+                // Still shared when the output is an input or a constant
+                Arc::try_unwrap(out).unwrap_or_else(|out| backend.upload(&backend.download(&out)))
+            }
+        };
+
+        Ok(Tensor {
+            inner: Arc::new(TensorInner {
+                id: get_tensor_id(),
+                device: self.device.clone(),
+                inference: is_inference_tensor(false),
+                buf: Arc::new(buf).into(),
+                pending: Default::default(),
+                shape: output.shape.clone(),
+                requires_grad: false,
+                grad_node: None,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::BackendKind,
+        nn::{Adam, MLP},
+        ops::OpType,
+        runtime::test_adapter,
+    };
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn compiled_mlp_matches_eager_after_updates() {
+        for device in [Device::new(test_adapter(), 0), Device::cpu(0)] {
+            let model = MLP::new_on(&device, &[4, 8, 3], true);
+            let data: Vec<f32> = (0..8).map(|i| i as f32 * 0.25 - 1.0).collect();
+            let x = Tensor::new_on(&device, &[2, 4], &data, false);
+
+            let compiled =
+                trace(std::slice::from_ref(&x), |inputs| model.forward(&inputs[0])).unwrap();
+            assert_close(
+                &compiled.run(std::slice::from_ref(&x)).unwrap().to_vec(),
+                &model.forward(&x).unwrap().to_vec(),
+            );
+
+            // Parameters are read on every run
+            let mut opt = Adam::new(&model, 0.1, 0.9, 0.999, 1e-8);
+            model.forward(&x).unwrap().sum().unwrap().backward();
+            opt.step();
+
+            let y = Tensor::new_on(
+                &device,
+                &[2, 4],
+                &data.iter().map(|v| -v).collect::<Vec<_>>(),
+                false,
+            );
+            let expected = model.forward(&y).unwrap().to_vec();
+            assert_close(&compiled.run(&[y]).unwrap().to_vec(), &expected);
+
+            let stats = compiled.stats();
+            if device.backend() == BackendKind::Wgpu {
+                assert!(stats.dispatches > 0);
+                assert!(stats.buffer_bytes > 0);
+            } else {
+                assert_eq!(stats.dispatches, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn dead_ops_are_dropped_and_elementwise_chains_fused() {
+        for device in [Device::new(test_adapter(), 0), Device::cpu(0)] {
+            let x = Tensor::new_on(&device, &[2, 2], &[1.0, -2.0, 3.0, -4.0], false);
+            let w = Tensor::new_on(&device, &[2, 2], &[1.0, 0.0, 0.0, 1.0], false);
+
+            let compiled = trace(std::slice::from_ref(&x), |inputs| {
+                let x = &inputs[0];
+                let _unused = x.matmul(&w)?.relu()?;
+                x.relu()?.mul_s(2.0)?.add(&x.matmul(&w)?)?.add_s(1.0)
+            })
+            .unwrap();
+
+            let stats = compiled.stats();
+            assert_eq!(stats.traced_ops, 7);
+            // The matmul and one kernel for relu, mul_s, add and add_s
+            assert_eq!(stats.compiled_ops, 2);
+            if device.backend() == BackendKind::Wgpu {
+                assert_eq!(stats.dispatches, 2);
+            }
+            assert_eq!(
+                compiled.run(&[x]).unwrap().to_vec(),
+                vec![4.0, -1.0, 10.0, -3.0]
+            );
+        }
+    }
+
+    #[test]
+    fn untraceable_ops_and_wrong_inputs_are_errors() {
+        let device = Device::new(test_adapter(), 0);
+        let x = Tensor::new_on(&device, &[1, 3], &[1.0, 2.0, 3.0], false);
+        let targets = Tensor::new_on(&device, &[1, 3], &[0.0, 1.0, 0.0], false);
+
+        let res = trace(std::slice::from_ref(&x), |inputs| {
+            inputs[0].cross_entropy_loss(&targets)
+        });
+        assert!(matches!(
+            res,
+            Err(TensorOpError::NotTraceable(OpType::CrossEntropyLoss))
+        ));
+        assert!(!is_tracing());
+
+        let compiled = trace(std::slice::from_ref(&x), |inputs| inputs[0].relu()).unwrap();
+        let y = Tensor::new_on(&device, &[3], &[1.0, 2.0, 3.0], false);
+        assert!(matches!(
+            compiled.run(&[y]),
+            Err(TensorOpError::MismatchedShapes)
+        ));
+        assert_eq!(compiled.run(&[x]).unwrap().to_vec(), vec![1.0, 2.0, 3.0]);
+    }
+}